@@ -0,0 +1,29 @@
+//! Build script that provides build info to the `/version` endpoint using env variables.
+
+use std::{
+    env,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() {
+    // Prefer explicitly passed commit, e.g. in Docker builds where the .git directory is missing.
+    let git_commit = env::var("GIT_COMMIT")
+        .ok()
+        .or_else(git_head_commit)
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GOOUT_GIT_COMMIT={}", git_commit);
+
+    let build_timestamp =
+        SystemTime::now().duration_since(UNIX_EPOCH).expect("now is after Unix epoch").as_secs();
+    println!("cargo:rustc-env=GOOUT_BUILD_TIMESTAMP={}", build_timestamp);
+}
+
+/// Get hash of the git HEAD commit, [None] if git or the repository is not available.
+fn git_head_commit() -> Option<String> {
+    let output = Command::new("git").args(&["rev-parse", "HEAD"]).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
@@ -0,0 +1,679 @@
+//! Configuration of the service, read from env variables and validated once at startup. The rest
+//! of the service gets its settings from [Config] (or [LoggingConfig]) and never reads env
+//! variables itself, so that a bad value stops the service from starting instead of failing
+//! requests later.
+
+use crate::{
+    handlers::city::SEARCH_DEFAULT_LIMIT,
+    logging::LogFormat,
+    response::{ErrorResponse::BadRequest, HandlerResult},
+    services::{
+        locations_repo::{ApproximateScoring, Language},
+        query_templates::QueryKind,
+    },
+};
+use elasticsearch::http::{transport::CloudConnectionPool, Url};
+use env_logger::DEFAULT_FILTER_ENV;
+use log::info;
+use std::{
+    collections::HashMap,
+    env,
+    fmt::Display,
+    num::{NonZeroU64, NonZeroUsize},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Default minimum search query length, suitable for languages written in Latin script.
+const DEFAULT_SEARCH_MIN_LENGTH: usize = 2;
+/// Default maximum `limit` of cities returned by `/city/v1/search`.
+const DEFAULT_SEARCH_MAX_LIMIT: usize = 25;
+/// Default maximum number of regions fetched concurrently when resolving a list of cities.
+const DEFAULT_MAX_CONCURRENT_REGION_FETCHES: usize = 8;
+/// Default maximum number of cities in any list response, the highest per-endpoint limit.
+const DEFAULT_MAX_RESPONSE_CITIES: usize = 1000;
+/// Default number of items in a page of list endpoints.
+const DEFAULT_PAGE_LIMIT: usize = 100;
+/// Default maximum number of items in a page of list endpoints.
+const DEFAULT_MAX_PAGE_LIMIT: usize = 1000;
+/// Default timeout of a whole Elasticsearch request (from connecting to reading the response) in
+/// milliseconds. Generous, as it only guards against hung connections; requests of the service
+/// are bounded by their own budgets, see `GOOUT_REQUEST_TIMEOUT_MS`.
+const DEFAULT_ELASTIC_REQUEST_TIMEOUT_MS: u64 = 30_000;
+/// Default time budget of a request in milliseconds.
+const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 10_000;
+/// Default maximum length of the query string in bytes.
+const DEFAULT_MAX_QUERY_BYTES: usize = 4096;
+/// Default maximum number of query parameters.
+const DEFAULT_MAX_QUERY_PARAMS: usize = 50;
+/// Default `max-age` of cacheable responses in seconds.
+const DEFAULT_CACHE_MAX_AGE: u32 = 3600;
+/// Default number of decimal places of coordinates in API responses. 6 places are about 10 cm,
+/// more would only imply false precision.
+pub(crate) const DEFAULT_COORDINATES_DECIMALS: i32 = 6;
+/// Default number of decimal places of distances in kilometres in API responses, i.e. whole
+/// metres.
+pub(crate) const DEFAULT_DISTANCE_KM_DECIMALS: i32 = 3;
+
+/// Security headers with their default values and env variables to override them with.
+const SECURITY_HEADERS: &[(&str, &str, &str)] = &[
+    ("X-Content-Type-Options", "nosniff", "GOOUT_HEADER_X_CONTENT_TYPE_OPTIONS"),
+    ("X-Frame-Options", "DENY", "GOOUT_HEADER_X_FRAME_OPTIONS"),
+    ("Referrer-Policy", "no-referrer", "GOOUT_HEADER_REFERRER_POLICY"),
+];
+
+/// Kinds of Elasticsearch queries that can be given by a template, with env variables holding
+/// paths to the template files.
+const QUERY_TEMPLATES: &[(QueryKind, &str)] = &[
+    (QueryKind::Search, "GOOUT_QUERY_TEMPLATE_SEARCH"),
+    (QueryKind::Closest, "GOOUT_QUERY_TEMPLATE_CLOSEST"),
+];
+
+/// Configuration of the service, see the README for description of the env variables.
+pub(crate) struct Config {
+    /// Minimum search query length in characters per language.
+    pub(crate) search_min_lengths: HashMap<Language, usize>,
+    /// Minimum relevance score of search results per language, zero for no minimum.
+    pub(crate) search_min_scores: HashMap<Language, f64>,
+    /// Score added to search results whose localized name starts with the search query.
+    pub(crate) search_prefix_boost: f64,
+    /// Boost (multiplier) of search results whose localized name contains a multi-word query as a
+    /// phrase.
+    pub(crate) search_phrase_boost: f64,
+    /// Score added to featured cities in search results.
+    pub(crate) search_featured_boost: f64,
+    /// Maximum `limit` of cities returned by `/city/v1/search`.
+    pub(crate) search_max_limit: usize,
+    /// Maximum number of regions fetched concurrently when resolving a list of cities.
+    pub(crate) max_concurrent_region_fetches: usize,
+    /// Maximum number of cities in any list response.
+    pub(crate) max_response_cities: usize,
+    /// Number of items in a page of list endpoints without their own default if the request does
+    /// not specify `limit`.
+    pub(crate) default_page_limit: usize,
+    /// Maximum `limit` of items in a page of list endpoints.
+    pub(crate) max_page_limit: usize,
+    /// Distance from the closest city within which a point is covered by the service, in km.
+    pub(crate) coverage_radius_km: f64,
+    /// Weights of scoring of cities for approximate coordinates from IP geo-location.
+    pub(crate) approximate_scoring: ApproximateScoring,
+    /// Whether to respond with cities with empty region name when their region cannot be fetched.
+    pub(crate) degrade_on_region_error: bool,
+    /// Shared secret authorizing calls of admin endpoints, which are disabled if not set.
+    pub(crate) admin_secret: Option<String>,
+    /// Whether `/city/v1/closest` may respond with debugging info about candidate cities.
+    pub(crate) closest_debug: bool,
+    /// Whether list endpoints may respond with durations of phases of handling the request.
+    pub(crate) debug_timings: bool,
+    /// Whether to reject requests with unknown query parameters.
+    pub(crate) strict_params: bool,
+    /// Whether `/city/v1/search` rejects `countryIso` of countries without any cities.
+    pub(crate) strict_country_iso: bool,
+    /// Elasticsearch connection and search settings.
+    pub(crate) elastic: ElasticConfig,
+    /// Whether to check mappings of the indices at startup.
+    pub(crate) check_index_mappings: bool,
+    /// Whether to check at startup that fallback cities exist.
+    pub(crate) check_fallback_cities: bool,
+    /// Paths to files of Elasticsearch query templates per query kind.
+    pub(crate) query_templates: Vec<(QueryKind, String)>,
+    /// Time-to-live of entries of the region cache, [None] for entries that never expire.
+    pub(crate) region_cache_ttl: Option<Duration>,
+    /// Number of decimal places of coordinates in API responses.
+    pub(crate) coordinates_decimals: i32,
+    /// Number of decimal places of distances in kilometres in API responses.
+    pub(crate) distance_km_decimals: i32,
+    /// Path to the IP geo-location database, if configured.
+    pub(crate) geoip_database: Option<String>,
+    /// Whether to serve Swagger UI, [None] to serve it only in the development environment.
+    pub(crate) swagger_ui: Option<bool>,
+    /// Path to serve Swagger UI at.
+    pub(crate) swagger_ui_path: String,
+    /// Default time budget of a request.
+    pub(crate) request_timeout: Duration,
+    /// Pairs of path prefix and time budget of requests whose path starts with it.
+    pub(crate) request_timeouts_by_prefix: Vec<(String, Duration)>,
+    /// Whether to send the `X-ES-Took-Ms` response header.
+    pub(crate) es_took_header: bool,
+    /// Maximum length of the query string in bytes.
+    pub(crate) max_query_bytes: usize,
+    /// Maximum number of query parameters.
+    pub(crate) max_query_params: usize,
+    /// Names and values of security headers to set to every response.
+    pub(crate) security_headers: Vec<(&'static str, String)>,
+    /// `max-age` of cacheable responses in seconds.
+    pub(crate) cache_max_age: u32,
+}
+
+/// Where to connect to Elasticsearch.
+pub(crate) enum ElasticLocation {
+    /// Elastic Cloud deployment given by its cloud ID.
+    Cloud(String),
+    /// Single Elasticsearch node.
+    Node(Url),
+}
+
+/// Elasticsearch settings, shared by clients of all worker threads.
+pub(crate) struct ElasticConfig {
+    pub(crate) location: ElasticLocation,
+    /// Username and password for basic authentication, if configured.
+    pub(crate) credentials: Option<(String, String)>,
+    /// Timeout of a whole request.
+    pub(crate) request_timeout: Duration,
+    /// Elasticsearch `preference` of search requests, e.g. to pin them to a set of shard copies.
+    pub(crate) preference: Option<String>,
+    /// Elasticsearch `timeout` of full-text search requests, e.g. `500ms`.
+    pub(crate) search_timeout: Option<String>,
+    /// Elasticsearch `terminate_after` of full-text search requests.
+    pub(crate) search_terminate_after: Option<u64>,
+}
+
+/// Configuration of logging, read separately from [Config] so that logging is set up before the
+/// rest of the configuration is read (and logged).
+#[derive(Debug)]
+pub(crate) struct LoggingConfig {
+    /// Log level (filter) in the `env_logger` syntax, e.g. `info`.
+    pub(crate) filter: String,
+    /// Format of log lines.
+    pub(crate) format: LogFormat,
+}
+
+/// Source of configuration variables: the process environment, or a map in tests.
+struct Vars<'a>(&'a dyn Fn(&str) -> Option<String>);
+
+impl Vars<'_> {
+    /// Value of variable `name`, [None] if it is not set.
+    fn get(&self, name: &str) -> Option<String> {
+        (self.0)(name)
+    }
+
+    /// Value of variable `name`, [None] if it is not set or empty.
+    fn non_empty(&self, name: &str) -> Option<String> {
+        self.get(name).filter(|it| !it.is_empty())
+    }
+
+    /// Parse variable `name` if it is set, [None] otherwise.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the variable is set but cannot be parsed.
+    fn parse_optional<T: FromStr>(&self, name: &str) -> Option<T>
+    where
+        T::Err: Display,
+    {
+        let value = self.get(name)?;
+        Some(value.parse().unwrap_or_else(|e| panic!("{} env variable: {}", name, e)))
+    }
+
+    /// Parse variable `name` if it is set, return `default` otherwise.
+    fn parse<T: FromStr>(&self, name: &str, default: T) -> T
+    where
+        T::Err: Display,
+    {
+        self.parse_optional(name).unwrap_or(default)
+    }
+
+    /// Parse variable `name` as a positive integer if it is set, return `default` otherwise.
+    fn parse_positive(&self, name: &str, default: usize) -> usize {
+        self.parse(name, NonZeroUsize::new(default).expect("non-zero default")).get()
+    }
+}
+
+/// Process environment as [Vars].
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok()
+}
+
+impl Config {
+    /// Read and validate configuration from env variables.
+    ///
+    /// # Panics
+    ///
+    /// Panics if some env variable is required but not set, or is set to an invalid value.
+    pub(crate) fn from_env() -> Self {
+        Self::from_vars(&Vars(&env_var))
+    }
+
+    fn from_vars(vars: &Vars<'_>) -> Self {
+        let search_min_lengths = Language::ALL
+            .iter()
+            .map(|&language| {
+                let env_name = format!("GOOUT_SEARCH_MIN_LENGTH_{:?}", language);
+                (language, vars.parse(&env_name, DEFAULT_SEARCH_MIN_LENGTH))
+            })
+            .collect();
+        info!("Minimum search query lengths: {:?}.", search_min_lengths);
+
+        let search_min_scores = Language::ALL
+            .iter()
+            .map(|&language| {
+                let env_name = format!("GOOUT_SEARCH_MIN_SCORE_{:?}", language);
+                let min_score = vars.parse(&env_name, 0.0);
+                if min_score.is_nan() || min_score < 0.0 {
+                    panic!("{} must not be negative.", env_name);
+                }
+                (language, min_score)
+            })
+            .collect();
+        info!("Minimum search relevance scores: {:?}.", search_min_scores);
+
+        let search_prefix_boost = vars.parse("GOOUT_SEARCH_PREFIX_BOOST", 4.0);
+        info!("Search name prefix boost: {}.", search_prefix_boost);
+
+        let search_phrase_boost = vars.parse("GOOUT_SEARCH_PHRASE_BOOST", 2.0);
+        info!("Search name phrase boost: {}.", search_phrase_boost);
+
+        let search_featured_boost = vars.parse("GOOUT_SEARCH_FEATURED_BOOST", 1.0);
+        info!("Search featured city boost: {}.", search_featured_boost);
+
+        let search_max_limit = vars.parse("GOOUT_SEARCH_MAX_LIMIT", DEFAULT_SEARCH_MAX_LIMIT);
+        if search_max_limit < SEARCH_DEFAULT_LIMIT {
+            panic!("GOOUT_SEARCH_MAX_LIMIT must be at least {}.", SEARCH_DEFAULT_LIMIT);
+        }
+        info!("Maximum search limit: {}.", search_max_limit);
+
+        let max_concurrent_region_fetches = vars.parse_positive(
+            "GOOUT_MAX_CONCURRENT_REGION_FETCHES",
+            DEFAULT_MAX_CONCURRENT_REGION_FETCHES,
+        );
+        info!("Maximum concurrent region fetches: {}.", max_concurrent_region_fetches);
+
+        let max_response_cities =
+            vars.parse_positive("GOOUT_MAX_RESPONSE_CITIES", DEFAULT_MAX_RESPONSE_CITIES);
+        info!("Maximum cities in a response: {}.", max_response_cities);
+
+        let max_page_limit = vars.parse_positive("GOOUT_MAX_PAGE_LIMIT", DEFAULT_MAX_PAGE_LIMIT);
+        let default_page_limit =
+            vars.parse_positive("GOOUT_DEFAULT_PAGE_LIMIT", DEFAULT_PAGE_LIMIT);
+        if default_page_limit > max_page_limit {
+            panic!("GOOUT_DEFAULT_PAGE_LIMIT must not be greater than GOOUT_MAX_PAGE_LIMIT.");
+        }
+        if max_response_cities < max_page_limit {
+            panic!("GOOUT_MAX_RESPONSE_CITIES must not be less than GOOUT_MAX_PAGE_LIMIT.");
+        }
+        info!(
+            "Page limit of list endpoints: default {}, maximum {}.",
+            default_page_limit, max_page_limit
+        );
+
+        let coverage_radius_km = vars.parse("GOOUT_COVERAGE_RADIUS_KM", 50.0);
+        if coverage_radius_km.is_nan() || coverage_radius_km <= 0.0 {
+            panic!("GOOUT_COVERAGE_RADIUS_KM must be positive.");
+        }
+        info!("Coverage radius: {} km.", coverage_radius_km);
+
+        let approximate_scoring = ApproximateScoring {
+            distance_scale_km: vars.parse("GOOUT_APPROXIMATE_DISTANCE_SCALE_KM", 50.0),
+            featured_boost: vars.parse("GOOUT_APPROXIMATE_FEATURED_BOOST", 4.0),
+            population_factor: vars.parse("GOOUT_APPROXIMATE_POPULATION_FACTOR", 1.0),
+        };
+        if !approximate_scoring.distance_scale_km.is_finite()
+            || approximate_scoring.distance_scale_km <= 0.0
+        {
+            panic!("GOOUT_APPROXIMATE_DISTANCE_SCALE_KM must be positive.");
+        }
+        for (name, weight) in &[
+            ("GOOUT_APPROXIMATE_FEATURED_BOOST", approximate_scoring.featured_boost),
+            ("GOOUT_APPROXIMATE_POPULATION_FACTOR", approximate_scoring.population_factor),
+        ] {
+            if !weight.is_finite() || *weight < 0.0 {
+                panic!("{} must be a non-negative number.", name);
+            }
+        }
+        info!("Scoring of cities for approximate coordinates: {:?}.", approximate_scoring);
+
+        let degrade_on_region_error = vars.parse("GOOUT_DEGRADE_ON_REGION_ERROR", false);
+        info!("Degrade gracefully on region errors: {}.", degrade_on_region_error);
+
+        let admin_secret = vars.non_empty("GOOUT_ADMIN_SECRET");
+        info!("Admin endpoints enabled: {}.", admin_secret.is_some());
+
+        let closest_debug = vars.parse("GOOUT_CLOSEST_DEBUG", false);
+        info!("Debugging info of closest city allowed: {}.", closest_debug);
+
+        let debug_timings = vars.parse("GOOUT_DEBUG_TIMINGS", false);
+        info!("Debugging timings of list endpoints allowed: {}.", debug_timings);
+
+        let strict_params = vars.parse("GOOUT_STRICT_PARAMS", false);
+        info!("Reject unknown query parameters: {}.", strict_params);
+
+        let strict_country_iso = vars.parse("GOOUT_STRICT_COUNTRY_ISO", false);
+        info!("Reject unknown countryIso in search: {}.", strict_country_iso);
+
+        let elastic = ElasticConfig::from_vars(vars);
+
+        let check_index_mappings = vars.parse("GOOUT_CHECK_INDEX_MAPPINGS", false);
+        let check_fallback_cities = vars.parse("GOOUT_CHECK_FALLBACK_CITIES", false);
+
+        let query_templates = QUERY_TEMPLATES
+            .iter()
+            .filter_map(|&(kind, env_name)| Some((kind, vars.non_empty(env_name)?)))
+            .collect();
+
+        let region_cache_ttl = match vars.parse("GOOUT_REGION_CACHE_TTL_SECS", 0) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        info!("Region cache time-to-live: {:?}.", region_cache_ttl);
+
+        let coordinates_decimals =
+            vars.parse("GOOUT_COORDINATES_DECIMALS", DEFAULT_COORDINATES_DECIMALS);
+        let distance_km_decimals =
+            vars.parse("GOOUT_DISTANCE_KM_DECIMALS", DEFAULT_DISTANCE_KM_DECIMALS);
+        info!(
+            "Decimal places in responses: {} of coordinates, {} of distances in km.",
+            coordinates_decimals, distance_km_decimals
+        );
+
+        let geoip_database = vars.non_empty("GOOUT_GEOIP_DATABASE");
+
+        let swagger_ui = vars.parse_optional("GOOUT_SWAGGER_UI");
+        let swagger_ui_path = vars.get("GOOUT_SWAGGER_UI_PATH").unwrap_or_else(|| "/docs".into());
+
+        let request_timeout = Duration::from_millis(
+            vars.parse("GOOUT_REQUEST_TIMEOUT_MS", DEFAULT_REQUEST_TIMEOUT_MS),
+        );
+        let request_timeouts_by_prefix = vars
+            .get("GOOUT_REQUEST_TIMEOUT_MS_BY_PREFIX")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|pair| !pair.trim().is_empty())
+            .map(|pair| {
+                let (prefix, millis) = parse_prefix_budget(pair).unwrap_or_else(|| {
+                    panic!("GOOUT_REQUEST_TIMEOUT_MS_BY_PREFIX env variable: invalid `{}`", pair)
+                });
+                (prefix.to_string(), Duration::from_millis(millis))
+            })
+            .collect();
+
+        let es_took_header = vars.parse("GOOUT_ES_TOOK_HEADER", false);
+
+        let max_query_bytes = vars.parse("GOOUT_MAX_QUERY_BYTES", DEFAULT_MAX_QUERY_BYTES);
+        let max_query_params = vars.parse("GOOUT_MAX_QUERY_PARAMS", DEFAULT_MAX_QUERY_PARAMS);
+
+        let security_headers = if vars.parse("GOOUT_SECURITY_HEADERS", true) {
+            SECURITY_HEADERS
+                .iter()
+                .map(|&(name, default, env_name)| {
+                    (name, vars.get(env_name).unwrap_or_else(|| default.to_string()))
+                })
+                .filter(|(_, value)| !value.is_empty())
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let cache_max_age = vars.parse("GOOUT_CACHE_MAX_AGE", DEFAULT_CACHE_MAX_AGE);
+
+        Self {
+            search_min_lengths,
+            search_min_scores,
+            search_prefix_boost,
+            search_phrase_boost,
+            search_featured_boost,
+            search_max_limit,
+            max_concurrent_region_fetches,
+            max_response_cities,
+            default_page_limit,
+            max_page_limit,
+            coverage_radius_km,
+            approximate_scoring,
+            degrade_on_region_error,
+            admin_secret,
+            closest_debug,
+            debug_timings,
+            strict_params,
+            strict_country_iso,
+            elastic,
+            check_index_mappings,
+            check_fallback_cities,
+            query_templates,
+            region_cache_ttl,
+            coordinates_decimals,
+            distance_km_decimals,
+            geoip_database,
+            swagger_ui,
+            swagger_ui_path,
+            request_timeout,
+            request_timeouts_by_prefix,
+            es_took_header,
+            max_query_bytes,
+            max_query_params,
+            security_headers,
+            cache_max_age,
+        }
+    }
+
+    /// Minimum length of search query in characters for given language. Shorter queries are not
+    /// sent to Elasticsearch at all.
+    pub(crate) fn search_min_length(&self, language: Language) -> usize {
+        self.search_min_lengths.get(&language).copied().unwrap_or(DEFAULT_SEARCH_MIN_LENGTH)
+    }
+
+    /// Default minimum relevance score of search results for given language, zero for no minimum.
+    pub(crate) fn search_min_score(&self, language: Language) -> f64 {
+        self.search_min_scores.get(&language).copied().unwrap_or_default()
+    }
+
+    /// Number of items in a page of a list endpoint given requested `limit`: `endpoint_default`
+    /// (capped to the configured maximum) or the configured default if not given, Bad Request if it
+    /// is not between 1 and the configured maximum.
+    pub(crate) fn page_limit(
+        &self,
+        limit: Option<usize>,
+        endpoint_default: Option<usize>,
+    ) -> HandlerResult<usize> {
+        let default =
+            endpoint_default.map_or(self.default_page_limit, |it| it.min(self.max_page_limit));
+        let limit = limit.unwrap_or(default);
+        if limit == 0 || limit > self.max_page_limit {
+            let message = format!("`limit` must be between 1 and {}.", self.max_page_limit);
+            return Err(BadRequest(message));
+        }
+        Ok(limit)
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// Default configuration, independent of env variables.
+    pub(crate) fn for_tests() -> Self {
+        Self::with_test_vars(&[])
+    }
+
+    /// Configuration given by variables `vars`, with a local Elasticsearch node that tests never
+    /// actually connect to.
+    fn with_test_vars(vars: &[(&str, &str)]) -> Self {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        Self::from_vars(&Vars(&|name: &str| match name {
+            "GOOUT_ELASTIC_HOST" => Some("localhost".to_string()),
+            "GOOUT_ELASTIC_PORT" => Some("9200".to_string()),
+            _ => vars.get(name).map(ToString::to_string),
+        }))
+    }
+}
+
+impl ElasticConfig {
+    /// Parse connection settings. Uses Elastic Cloud deployment given by `GOOUT_ELASTIC_CLOUD_ID`
+    /// env variable if it is set, otherwise the node given by `GOOUT_ELASTIC_HOST`,
+    /// `GOOUT_ELASTIC_PORT`. Authenticates using `GOOUT_ELASTIC_USERNAME`, `GOOUT_ELASTIC_PASSWORD`
+    /// if both are set (they are required with a cloud ID). Requests time out after
+    /// `GOOUT_ELASTIC_REQUEST_TIMEOUT_MS` milliseconds.
+    fn from_vars(vars: &Vars<'_>) -> Self {
+        let credentials =
+            match (vars.get("GOOUT_ELASTIC_USERNAME"), vars.get("GOOUT_ELASTIC_PASSWORD")) {
+                (Some(username), Some(password)) => Some((username, password)),
+                _ => None,
+            };
+
+        let location = if let Some(cloud_id) = vars.get("GOOUT_ELASTIC_CLOUD_ID") {
+            assert!(
+                credentials.is_some(),
+                "GOOUT_ELASTIC_USERNAME, GOOUT_ELASTIC_PASSWORD env variables with a cloud ID"
+            );
+            CloudConnectionPool::new(&cloud_id)
+                .unwrap_or_else(|e| panic!("GOOUT_ELASTIC_CLOUD_ID env variable: {}", e));
+            info!("Elasticsearch cloud deployment: {}.", cloud_id);
+            ElasticLocation::Cloud(cloud_id)
+        } else {
+            let es_url = format!(
+                "http://{}:{}/",
+                vars.get("GOOUT_ELASTIC_HOST").expect("GOOUT_ELASTIC_HOST env variable"),
+                vars.get("GOOUT_ELASTIC_PORT").expect("GOOUT_ELASTIC_PORT env variable")
+            );
+            let url = Url::parse(&es_url)
+                .unwrap_or_else(|e| panic!("GOOUT_ELASTIC_HOST, GOOUT_ELASTIC_PORT: {}", e));
+            info!("Elasticsearch node: {}.", url);
+            ElasticLocation::Node(url)
+        };
+        info!("Elasticsearch basic authentication: {}.", credentials.is_some());
+
+        let request_timeout = Duration::from_millis(
+            vars.parse(
+                "GOOUT_ELASTIC_REQUEST_TIMEOUT_MS",
+                NonZeroU64::new(DEFAULT_ELASTIC_REQUEST_TIMEOUT_MS).expect("non-zero default"),
+            )
+            .get(),
+        );
+        info!("Elasticsearch request timeout: {:?}.", request_timeout);
+
+        let preference = vars.get("GOOUT_ELASTIC_PREFERENCE");
+        info!("Elasticsearch search preference: {:?}.", preference);
+
+        let search_timeout = vars.non_empty("GOOUT_ELASTIC_SEARCH_TIMEOUT");
+        let search_terminate_after = match vars.parse("GOOUT_ELASTIC_SEARCH_TERMINATE_AFTER", 0) {
+            0 => None,
+            terminate_after => Some(terminate_after),
+        };
+        info!(
+            "Elasticsearch full-text search timeout: {:?}, terminate after: {:?}.",
+            search_timeout, search_terminate_after
+        );
+
+        Self {
+            location,
+            credentials,
+            request_timeout,
+            preference,
+            search_timeout,
+            search_terminate_after,
+        }
+    }
+}
+
+impl LoggingConfig {
+    /// Read logging configuration. Log level (filter) is taken from the standard `RUST_LOG` env
+    /// variable, or from `GOOUT_LOG_LEVEL` if it is not set, defaulting to `info`. Format is given
+    /// by `GOOUT_LOG_FORMAT` env variable, human-readable `text` by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `GOOUT_LOG_FORMAT` env variable is set to an unknown format.
+    pub(crate) fn from_env() -> Self {
+        let vars = Vars(&env_var);
+        let filter = vars
+            .get(DEFAULT_FILTER_ENV)
+            .or_else(|| vars.get("GOOUT_LOG_LEVEL"))
+            .unwrap_or_else(|| "info".to_string());
+        let format = vars.parse("GOOUT_LOG_FORMAT", LogFormat::Text);
+
+        Self { filter, format }
+    }
+}
+
+/// Parse `<prefix>=<milliseconds>` pair.
+fn parse_prefix_budget(pair: &str) -> Option<(&str, u64)> {
+    let mut parts = pair.trim().splitn(2, '=');
+    let prefix = parts.next()?;
+    let millis = parts.next()?.parse().ok()?;
+    Some((prefix, millis))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(vars: &[(&str, &str)]) -> Config {
+        Config::with_test_vars(vars)
+    }
+
+    #[test]
+    fn page_limit_defaults_per_endpoint_and_caps_to_maximum() {
+        let config =
+            config(&[("GOOUT_DEFAULT_PAGE_LIMIT", "100"), ("GOOUT_MAX_PAGE_LIMIT", "500")]);
+
+        assert_eq!(config.page_limit(None, None).unwrap(), 100);
+        assert_eq!(config.page_limit(None, Some(10)).unwrap(), 10);
+        assert_eq!(config.page_limit(None, Some(1000)).unwrap(), 500);
+        assert_eq!(config.page_limit(Some(500), Some(10)).unwrap(), 500);
+        assert!(config.page_limit(Some(501), None).is_err());
+        assert!(config.page_limit(Some(0), Some(10)).is_err());
+    }
+
+    #[test]
+    fn variables_are_parsed() {
+        let config = config(&[
+            ("GOOUT_SEARCH_MIN_LENGTH_CS", "3"),
+            ("GOOUT_REQUEST_TIMEOUT_MS_BY_PREFIX", "/city/v1/search=500, /geo/=2000"),
+            ("GOOUT_HEADER_X_FRAME_OPTIONS", ""),
+            ("GOOUT_REGION_CACHE_TTL_SECS", "60"),
+            ("GOOUT_SWAGGER_UI", "true"),
+        ]);
+
+        assert_eq!(config.search_min_length(Language::CS), 3);
+        assert_eq!(config.search_min_length(Language::EN), DEFAULT_SEARCH_MIN_LENGTH);
+        assert_eq!(
+            config.request_timeouts_by_prefix,
+            vec![
+                ("/city/v1/search".to_string(), Duration::from_millis(500)),
+                ("/geo/".to_string(), Duration::from_millis(2000)),
+            ]
+        );
+        let header_names: Vec<_> = config.security_headers.iter().map(|(name, _)| *name).collect();
+        assert_eq!(header_names, vec!["X-Content-Type-Options", "Referrer-Policy"]);
+        assert_eq!(config.region_cache_ttl, Some(Duration::from_secs(60)));
+        assert_eq!(config.swagger_ui, Some(true));
+        assert_eq!(config.elastic.search_terminate_after, None);
+    }
+
+    #[test]
+    fn security_headers_can_be_disabled() {
+        assert!(config(&[("GOOUT_SECURITY_HEADERS", "false")]).security_headers.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "GOOUT_SECURITY_HEADERS env variable")]
+    fn invalid_security_headers_switch_is_rejected() {
+        config(&[("GOOUT_SECURITY_HEADERS", "0")]);
+    }
+
+    #[test]
+    #[should_panic(expected = "GOOUT_MAX_RESPONSE_CITIES must not be less")]
+    fn max_response_cities_below_max_page_limit_is_rejected() {
+        config(&[("GOOUT_MAX_RESPONSE_CITIES", "500")]);
+    }
+
+    #[test]
+    #[should_panic(expected = "GOOUT_MAX_RESPONSE_CITIES env variable")]
+    fn zero_max_response_cities_is_rejected() {
+        config(&[("GOOUT_MAX_RESPONSE_CITIES", "0")]);
+    }
+
+    #[test]
+    #[should_panic(expected = "GOOUT_APPROXIMATE_DISTANCE_SCALE_KM must be positive")]
+    fn zero_approximate_distance_scale_is_rejected() {
+        config(&[("GOOUT_APPROXIMATE_DISTANCE_SCALE_KM", "0")]);
+    }
+
+    #[test]
+    #[should_panic(expected = "GOOUT_APPROXIMATE_FEATURED_BOOST must be a non-negative number")]
+    fn nan_approximate_boost_is_rejected() {
+        config(&[("GOOUT_APPROXIMATE_FEATURED_BOOST", "NaN")]);
+    }
+
+    #[test]
+    #[should_panic(expected = "GOOUT_REQUEST_TIMEOUT_MS_BY_PREFIX env variable")]
+    fn malformed_timeout_by_prefix_is_rejected() {
+        config(&[("GOOUT_REQUEST_TIMEOUT_MS_BY_PREFIX", "/city/=fast")]);
+    }
+}
@@ -0,0 +1,93 @@
+//! Fairing to set `Cache-Control` header of responses according to per-endpoint policy.
+
+use crate::config::Config;
+use log::info;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Request, Response,
+};
+
+/// Prefixes of paths of API endpoints whose responses may be cached.
+const CACHEABLE_PATH_PREFIXES: &[&str] = &["/city/", "/region/"];
+/// Paths of API endpoints whose responses must not be cached, e.g. because they depend on
+/// geo-location of the client or serve a sync feed that must not be stale.
+const NO_STORE_PATHS: &[&str] = &["/city/v1/closest", "/city/v1/changes"];
+
+/// Fairing that sets `Cache-Control` to `public, max-age=<max_age>` for cacheable API endpoints,
+/// to `no-store` for location-dependent endpoints and for all error responses. Successful
+/// responses that already have `Cache-Control` set by their handler are left intact.
+#[derive(Debug)]
+pub(crate) struct CacheControl {
+    max_age: u32,
+}
+
+impl CacheControl {
+    /// Construct the fairing with `max-age` of [Config::cache_max_age].
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let max_age = config.cache_max_age;
+        info!("Cache-Control max-age of cacheable responses: {} s.", max_age);
+
+        Self { max_age }
+    }
+}
+
+impl Fairing for CacheControl {
+    fn info(&self) -> Info {
+        Info { name: "Cache-Control", kind: Kind::Response }
+    }
+
+    fn on_response(&self, request: &Request<'_>, response: &mut Response<'_>) {
+        let path = request.uri().path();
+
+        let value = if response.status().code >= 400 {
+            "no-store".to_string()
+        } else if response.headers().contains("Cache-Control") {
+            return;
+        } else if NO_STORE_PATHS.contains(&path) {
+            "no-store".to_string()
+        } else if CACHEABLE_PATH_PREFIXES.iter().any(|prefix| path.starts_with(prefix)) {
+            format!("public, max-age={}", self.max_age)
+        } else {
+            return;
+        };
+        response.set_raw_header("Cache-Control", value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::{get, local::Client, routes};
+
+    #[get("/city/v1/get")]
+    fn get() -> &'static str {
+        "city"
+    }
+
+    #[get("/city/v1/closest")]
+    fn closest() -> &'static str {
+        "closest"
+    }
+
+    #[get("/city/v1/changes")]
+    fn changes() -> &'static str {
+        "changes"
+    }
+
+    #[test]
+    fn sets_cache_control_per_endpoint() {
+        let rocket = rocket::ignite()
+            .attach(CacheControl { max_age: 60 })
+            .mount("/", routes![get, closest, changes]);
+        let client = Client::new(rocket).expect("valid rocket instance");
+
+        let cache_control = |uri| {
+            let response = client.get(uri).dispatch();
+            response.headers().get_one("Cache-Control").map(ToString::to_string)
+        };
+        assert_eq!(cache_control("/city/v1/get").as_deref(), Some("public, max-age=60"));
+        assert_eq!(cache_control("/city/v1/closest").as_deref(), Some("no-store"));
+        assert_eq!(cache_control("/city/v1/changes").as_deref(), Some("no-store"));
+        assert_eq!(cache_control("/city/v1/missing").as_deref(), Some("no-store"));
+    }
+}
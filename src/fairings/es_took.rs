@@ -0,0 +1,59 @@
+//! Fairing to tell clients how much time Elasticsearch spent on their request.
+
+use crate::config::Config;
+use log::info;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Data, Request, Response,
+};
+use std::cell::Cell;
+
+/// Response header with total `took` time of Elasticsearch queries of the request in ms.
+const ES_TOOK_HEADER: &str = "X-ES-Took-Ms";
+
+thread_local! {
+    // Thread-local because Rocket processes each request synchronously in one worker thread.
+    static CURRENT_TOOK_MS: Cell<Option<u64>> = Cell::new(None);
+}
+
+/// Add `took_ms` reported by Elasticsearch for a query to the total of the request being
+/// processed by the current thread.
+pub(crate) fn record(took_ms: u64) {
+    CURRENT_TOOK_MS.with(|it| it.set(Some(it.get().unwrap_or(0) + took_ms)));
+}
+
+/// Fairing that sums `took` times of Elasticsearch queries made while handling each request (as
+/// recorded using [record()]) and, if enabled, sends the sum in the `X-ES-Took-Ms` response
+/// header, so that time spent in Elasticsearch can be told apart from overhead of the service.
+/// The header is absent for requests without Elasticsearch queries.
+#[derive(Debug)]
+pub(crate) struct EsTook {
+    enabled: bool,
+}
+
+impl EsTook {
+    /// Construct the fairing, enabled by [Config::es_took_header].
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let enabled = config.es_took_header;
+        info!("Send {} response header: {}.", ES_TOOK_HEADER, enabled);
+
+        Self { enabled }
+    }
+}
+
+impl Fairing for EsTook {
+    fn info(&self) -> Info {
+        Info { name: "Elasticsearch took time", kind: Kind::Request | Kind::Response }
+    }
+
+    fn on_request(&self, _request: &mut Request<'_>, _data: &Data) {
+        CURRENT_TOOK_MS.with(|it| it.set(None));
+    }
+
+    fn on_response(&self, _request: &Request<'_>, response: &mut Response<'_>) {
+        let took_ms = CURRENT_TOOK_MS.with(|it| it.replace(None));
+        if let (true, Some(took_ms)) = (self.enabled, took_ms) {
+            response.set_raw_header(ES_TOOK_HEADER, took_ms.to_string());
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Fairing to respond with HTTP 406 Not Acceptable to clients that don't accept JSON responses of
+//! endpoints that cannot encode them otherwise.
+
+use crate::response::{
+    accepts_json, not_acceptable,
+    ErrorResponse::{self, NotFound},
+};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    get,
+    http::{uri::Origin, Method},
+    request::{FromRequest, Outcome},
+    Data, Request, Response,
+};
+
+/// Paths of endpoints that respond only with JSON. Endpoints that negotiate their format (see
+/// [crate::response::Negotiated]) respond with JSON only if it is acceptable, so they are not
+/// listed here.
+const JSON_ONLY_PATHS: &[&str] = &[
+    "/admin/cache/clear",
+    "/city/v1/featuredByCountry",
+    "/city/v1/featuredCount",
+    "/city/v1/region",
+    "/config",
+    "/geo/v1/resolve",
+    "/geo/v1/covered",
+    "/metrics",
+    "/openapi.json",
+    "/region/v1/search",
+    "/version",
+];
+/// Path of the [not_acceptable_route], must match its attribute.
+const NOT_ACCEPTABLE_PATH: &str = "/_jsonOnly/notAcceptable";
+
+/// Fairing that responds with HTTP 406 Not Acceptable to requests of JSON-only endpoints whose
+/// `Accept` header doesn't allow JSON, e.g. if it is just `application/x-protobuf`. The check is
+/// done before routing, so that the handler never runs: Rocket fairings cannot short-circuit
+/// requests, so such a request is rewritten to `GET` of the [not_acceptable_route], which must be
+/// mounted. Responses of JSON-only endpoints get `Vary: Accept`, as they depend on the header.
+#[derive(Debug)]
+pub(crate) struct JsonOnly;
+
+impl Fairing for JsonOnly {
+    fn info(&self) -> Info {
+        Info { name: "JSON Only", kind: Kind::Request | Kind::Response }
+    }
+
+    fn on_request(&self, request: &mut Request<'_>, _data: &Data) {
+        if !JSON_ONLY_PATHS.contains(&request.uri().path()) || accepts_json(request) {
+            return;
+        }
+
+        let error = not_acceptable(request);
+        request.set_method(Method::Get);
+        request.set_uri(Origin::new(NOT_ACCEPTABLE_PATH, None::<String>));
+        request.local_cache(|| Rejection(Some(error)));
+    }
+
+    fn on_response(&self, request: &Request<'_>, response: &mut Response<'_>) {
+        let path = request.uri().path();
+        if !JSON_ONLY_PATHS.contains(&path) && path != NOT_ACCEPTABLE_PATH {
+            return;
+        }
+
+        let mut values: Vec<String> = response
+            .headers()
+            .get("Vary")
+            .flat_map(|value| value.split(','))
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+            .collect();
+        if !values.iter().any(|value| value.eq_ignore_ascii_case("Accept")) {
+            values.push("Accept".to_string());
+        }
+        response.set_raw_header("Vary", values.join(", "));
+    }
+}
+
+/// Error of a request rejected by [JsonOnly], if it was rejected.
+#[derive(Clone)]
+pub(crate) struct Rejection(Option<ErrorResponse>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Rejection {
+    type Error = ();
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(request.local_cache(|| Rejection(None)).clone())
+    }
+}
+
+/// Route of requests rejected by [JsonOnly], responds with HTTP 406 Not Acceptable. Not Found if
+/// requested directly.
+#[get("/_jsonOnly/notAcceptable")]
+pub(crate) fn not_acceptable_route(rejection: Rejection) -> ErrorResponse {
+    rejection.0.unwrap_or_else(|| NotFound(format!("{} not found.", NOT_ACCEPTABLE_PATH)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::{
+        http::{Header, Status},
+        local::{Client, LocalResponse},
+        post, routes, State,
+    };
+    use rocket_contrib::json::Json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counter of calls of the [version] handler.
+    struct Calls(AtomicUsize);
+
+    #[get("/version")]
+    fn version(calls: State<'_, Calls>) -> Json<&'static str> {
+        calls.0.fetch_add(1, Ordering::SeqCst);
+        Json("ok")
+    }
+
+    #[post("/admin/cache/clear")]
+    fn cache_clear(calls: State<'_, Calls>) -> Json<&'static str> {
+        calls.0.fetch_add(1, Ordering::SeqCst);
+        Json("cleared")
+    }
+
+    fn client() -> Client {
+        let rocket = rocket::ignite()
+            .manage(Calls(AtomicUsize::new(0)))
+            .attach(JsonOnly)
+            .mount("/", routes![version, cache_clear, not_acceptable_route]);
+        Client::new(rocket).expect("valid rocket instance")
+    }
+
+    fn calls(client: &Client) -> usize {
+        client.rocket().state::<Calls>().expect("managed").0.load(Ordering::SeqCst)
+    }
+
+    fn get<'c>(client: &'c Client, accept: Option<&str>) -> LocalResponse<'c> {
+        let mut request = client.get("/version");
+        if let Some(accept) = accept {
+            request.add_header(Header::new("Accept", accept.to_string()));
+        }
+        request.dispatch()
+    }
+
+    #[test]
+    fn json_is_served_when_acceptable() {
+        let client = client();
+        let accepts = [None, Some("application/json"), Some("*/*"), Some("application/*")];
+        for &accept in &accepts {
+            let response = get(&client, accept);
+            assert_eq!(response.status(), Status::Ok, "{:?}", accept);
+            assert_eq!(response.headers().get_one("Vary"), Some("Accept"), "{:?}", accept);
+        }
+        let mixed = Some("application/x-protobuf, application/json;q=0.5");
+        assert_eq!(get(&client, mixed).status(), Status::Ok);
+        assert_eq!(calls(&client), accepts.len() + 1);
+    }
+
+    #[test]
+    fn json_only_requests_are_not_acceptable_otherwise_before_handler() {
+        let client = client();
+        for &accept in &["application/x-protobuf", "text/html", "application/json;q=0"] {
+            let response = get(&client, Some(accept));
+            assert_eq!(response.status(), Status::NotAcceptable, "{}", accept);
+            assert_eq!(response.headers().get_one("Vary"), Some("Accept"), "{}", accept);
+        }
+        let response = client
+            .post("/admin/cache/clear")
+            .header(Header::new("Accept", "application/x-protobuf"))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotAcceptable);
+        assert_eq!(calls(&client), 0);
+    }
+
+    #[test]
+    fn not_acceptable_route_is_not_found_when_requested_directly() {
+        let client = client();
+        assert_eq!(client.get(NOT_ACCEPTABLE_PATH).dispatch().status(), Status::NotFound);
+    }
+}
@@ -0,0 +1,101 @@
+//! Fairing to accept alternative names of query parameters used by some clients.
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::uri::Origin,
+    request::FormItems,
+    Data, Request,
+};
+
+/// Pairs of alias and canonical name of query parameters. The canonical parameter takes precedence
+/// if the request has both.
+const PARAM_ALIASES: &[(&str, &str)] = &[("lang", "language")];
+
+/// Fairing that renames aliased query parameters of requests to their canonical names (or drops
+/// them if the canonical parameter is present), so that query structs and strict parameter checks
+/// only deal with the canonical names. Done before routing, thus for all endpoints at once rather
+/// than in `FromForm` implementation of each query struct.
+#[derive(Debug)]
+pub(crate) struct ParamAliases;
+
+impl Fairing for ParamAliases {
+    fn info(&self) -> Info {
+        Info { name: "Query parameter aliases", kind: Kind::Request }
+    }
+
+    fn on_request(&self, request: &mut Request<'_>, _data: &Data) {
+        let query = match request.uri().query().and_then(canonical_query) {
+            Some(query) => query,
+            None => return,
+        };
+        let uri = Origin::new(request.uri().path().to_string(), Some(query));
+        request.set_uri(uri);
+    }
+}
+
+/// Rewrite URL `query` to use canonical names of aliased parameters, [None] if it has no aliases.
+fn canonical_query(query: &str) -> Option<String> {
+    // Pairs of decoded key and raw (still encoded) item, so that other items are kept as-is.
+    let items: Vec<(String, &str)> = FormItems::from(query)
+        .map(|item| (item.key.url_decode_lossy(), item.raw.as_str()))
+        .collect();
+    if !items.iter().any(|(key, _)| canonical_name(key).is_some()) {
+        return None;
+    }
+
+    let params: Vec<String> = items
+        .iter()
+        .filter_map(|(key, raw)| match canonical_name(key) {
+            Some(name) if items.iter().any(|(key, _)| key == name) => None,
+            Some(name) => {
+                Some(format!("{}={}", name, raw.splitn(2, '=').nth(1).unwrap_or_default()))
+            }
+            None => Some(raw.to_string()),
+        })
+        .collect();
+    Some(params.join("&"))
+}
+
+/// Canonical name of query parameter `key` if it is an alias.
+fn canonical_name(key: &str) -> Option<&'static str> {
+    PARAM_ALIASES.iter().find(|(alias, _)| *alias == key).map(|&(_, name)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aliases_are_renamed() {
+        assert_eq!(canonical_query("lang=cs"), Some("language=cs".to_string()));
+        assert_eq!(canonical_query("id=1&lang=sk&x=y"), Some("id=1&language=sk&x=y".to_string()));
+    }
+
+    #[test]
+    fn canonical_param_takes_precedence() {
+        assert_eq!(canonical_query("lang=de&language=cs"), Some("language=cs".to_string()));
+        assert_eq!(
+            canonical_query("language=cs&id=1&lang=de"),
+            Some("language=cs&id=1".to_string())
+        );
+    }
+
+    #[test]
+    fn raw_encoding_is_kept() {
+        assert_eq!(
+            canonical_query("lang=cs&query=New+York&x=a%2Bb"),
+            Some("language=cs&query=New+York&x=a%2Bb".to_string())
+        );
+        assert_eq!(
+            canonical_query("query=%C5%BD%C4%8F%C3%A1r&lang=cs%2B"),
+            Some("query=%C5%BD%C4%8F%C3%A1r&language=cs%2B".to_string())
+        );
+    }
+
+    #[test]
+    fn queries_without_aliases_are_kept() {
+        assert_eq!(canonical_query("language=cs&id=1"), None);
+        assert_eq!(canonical_query("language=cs&langx=1&xlang=2"), None);
+        assert_eq!(canonical_query(""), None);
+    }
+}
@@ -0,0 +1,151 @@
+//! Fairing to reject requests with oversized or suspicious query strings.
+
+use crate::{
+    config::Config,
+    response::ErrorResponse::{self, NotFound},
+};
+use log::{info, warn};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    get,
+    http::{uri::Origin, Method},
+    request::{FromRequest, Outcome},
+    Data, Request,
+};
+
+/// Paths exempt from the limits, probed by infrastructure that should never be rejected.
+const EXEMPT_PATHS: &[&str] = &["/health", "/livez", "/readyz"];
+/// Path of the [rejected] route, must match its attribute.
+const REJECTED_PATH: &str = "/_queryLimits/rejected";
+
+/// Fairing that rejects requests whose query string is longer than a limit in bytes (with HTTP 414
+/// URI Too Long) or has more parameters than a limit (with HTTP 400 Bad Request), so that form
+/// parsers of handlers never see pathological input. Rocket fairings cannot short-circuit
+/// requests, so a rejected request is rewritten to `GET` of the [rejected] route, which must be
+/// mounted, and no handler of the requested path runs.
+#[derive(Debug)]
+pub(crate) struct QueryLimits {
+    max_bytes: usize,
+    max_params: usize,
+}
+
+impl QueryLimits {
+    /// Construct the fairing with query string limits of `config`.
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let (max_bytes, max_params) = (config.max_query_bytes, config.max_query_params);
+        info!("Query string limits: {} bytes, {} parameters.", max_bytes, max_params);
+
+        Self { max_bytes, max_params }
+    }
+
+    /// Error response for `query` if it exceeds the limits.
+    fn check(&self, query: &str) -> Option<ErrorResponse> {
+        if query.len() > self.max_bytes {
+            return Some(ErrorResponse::UriTooLong(format!(
+                "Query string must be at most {} bytes long.",
+                self.max_bytes
+            )));
+        }
+        let params = query.split('&').filter(|it| !it.is_empty()).count();
+        if params > self.max_params {
+            return Some(ErrorResponse::BadRequest(format!(
+                "At most {} query parameters are accepted.",
+                self.max_params
+            )));
+        }
+        None
+    }
+}
+
+impl Fairing for QueryLimits {
+    fn info(&self) -> Info {
+        Info { name: "Query string limits", kind: Kind::Request }
+    }
+
+    fn on_request(&self, request: &mut Request<'_>, _data: &Data) {
+        let path = request.uri().path();
+        if EXEMPT_PATHS.contains(&path) {
+            return;
+        }
+        let error = match request.uri().query().and_then(|query| self.check(query)) {
+            Some(error) => error,
+            None => return,
+        };
+
+        warn!("Rejecting {} request to {}: {}", request.method(), path, error);
+        request.set_method(Method::Get);
+        request.set_uri(Origin::new(REJECTED_PATH, None::<String>));
+        request.local_cache(|| Rejection(Some(error)));
+    }
+}
+
+/// Error of a request rejected by [QueryLimits], if it was rejected.
+#[derive(Clone)]
+pub(crate) struct Rejection(Option<ErrorResponse>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Rejection {
+    type Error = ();
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(request.local_cache(|| Rejection(None)).clone())
+    }
+}
+
+/// Route of requests rejected by [QueryLimits], responds with their error. Not Found if requested
+/// directly.
+#[get("/_queryLimits/rejected")]
+pub(crate) fn rejected(rejection: Rejection) -> ErrorResponse {
+    rejection.0.unwrap_or_else(|| NotFound(format!("{} not found.", REJECTED_PATH)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::{http::Status, local::Client, routes, State};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counter of calls of the [echo] handler.
+    struct Calls(AtomicUsize);
+
+    #[get("/echo")]
+    fn echo(calls: State<'_, Calls>) -> &'static str {
+        calls.0.fetch_add(1, Ordering::SeqCst);
+        "ok"
+    }
+
+    fn client() -> Client {
+        let rocket = rocket::ignite()
+            .manage(Calls(AtomicUsize::new(0)))
+            .attach(QueryLimits { max_bytes: 20, max_params: 2 })
+            .mount("/", routes![echo, rejected]);
+        Client::new(rocket).expect("valid rocket instance")
+    }
+
+    fn calls(client: &Client) -> usize {
+        client.rocket().state::<Calls>().expect("managed").0.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn queries_within_limits_reach_handler() {
+        let client = client();
+        let mut response = client.get("/echo?a=1&b=2").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.body_string().as_deref(), Some("ok"));
+        assert_eq!(calls(&client), 1);
+    }
+
+    #[test]
+    fn oversized_queries_are_rejected_before_handler() {
+        let client = client();
+        assert_eq!(client.get("/echo?a=1&b=2&c=3").dispatch().status(), Status::BadRequest);
+        let long = format!("/echo?a={}", "x".repeat(20));
+        assert_eq!(client.get(long).dispatch().status(), Status::UriTooLong);
+        assert_eq!(client.post("/echo?a=1&b=2&c=3").dispatch().status(), Status::BadRequest);
+        assert_eq!(calls(&client), 0);
+    }
+
+    #[test]
+    fn rejected_route_is_not_found_when_requested_directly() {
+        let client = client();
+        assert_eq!(client.get(REJECTED_PATH).dispatch().status(), Status::NotFound);
+    }
+}
@@ -0,0 +1,56 @@
+//! Fairing to assign an id to each request, so that log messages can be correlated.
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Data, Request, Response,
+};
+use std::cell::RefCell;
+
+/// Request header to take request id from (e.g. when set by a load balancer), and response header
+/// to echo it in.
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+/// Maximum length of a request id accepted from the request header.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+thread_local! {
+    // Thread-local because Rocket processes each request synchronously in one worker thread.
+    static CURRENT_REQUEST_ID: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Id of the request being processed by the current thread, if any.
+pub(crate) fn current() -> Option<String> {
+    CURRENT_REQUEST_ID.with(|id| id.borrow().clone())
+}
+
+/// Fairing that takes request id from the `X-Request-Id` request header or generates a random one,
+/// makes it available using [current()] during request processing and sets it to the
+/// `X-Request-Id` response header.
+#[derive(Debug)]
+pub(crate) struct RequestId;
+
+impl Fairing for RequestId {
+    fn info(&self) -> Info {
+        Info { name: "Request id", kind: Kind::Request | Kind::Response }
+    }
+
+    fn on_request(&self, request: &mut Request<'_>, _data: &Data) {
+        let request_id = request
+            .headers()
+            .get_one(REQUEST_ID_HEADER)
+            .filter(|id| is_valid_request_id(id))
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+        CURRENT_REQUEST_ID.with(|id| id.replace(Some(request_id)));
+    }
+
+    fn on_response(&self, _request: &Request<'_>, response: &mut Response<'_>) {
+        if let Some(request_id) = CURRENT_REQUEST_ID.with(|id| id.replace(None)) {
+            response.set_raw_header(REQUEST_ID_HEADER, request_id);
+        }
+    }
+}
+
+/// Accept only reasonably short request ids consisting of visible ASCII characters.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH && id.bytes().all(|b| b.is_ascii_graphic())
+}
@@ -0,0 +1,41 @@
+//! Fairing to add basic security headers to all responses, including error ones.
+
+use crate::config::Config;
+use log::info;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Header,
+    Request, Response,
+};
+
+/// Fairing that sets a configurable set of security headers to every response.
+#[derive(Debug)]
+pub(crate) struct SecurityHeaders {
+    headers: Vec<Header<'static>>,
+}
+
+impl SecurityHeaders {
+    /// Construct the fairing with [Config::security_headers].
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let headers: Vec<_> = config
+            .security_headers
+            .iter()
+            .map(|(name, value)| Header::new(*name, value.clone()))
+            .collect();
+        info!("Security headers: {:?}.", headers);
+
+        Self { headers }
+    }
+}
+
+impl Fairing for SecurityHeaders {
+    fn info(&self) -> Info {
+        Info { name: "Security headers", kind: Kind::Response }
+    }
+
+    fn on_response(&self, _request: &Request<'_>, response: &mut Response<'_>) {
+        for header in &self.headers {
+            response.set_header(header.clone());
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Fairing to enforce per-request deadlines, configurable per path prefix.
+
+use crate::{config::Config, response::ErrorResponse};
+use log::{info, warn};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Data, Request, Response,
+};
+use std::{
+    cell::RefCell,
+    time::{Duration, Instant},
+};
+
+/// Deadline of the request being processed.
+#[derive(Clone, Debug)]
+pub(crate) struct RequestDeadline {
+    pub(crate) deadline: Instant,
+    budget: Duration,
+    path: String,
+}
+
+impl RequestDeadline {
+    /// Log that the request has exceeded its deadline and construct HTTP 503 response for it.
+    pub(crate) fn timed_out(&self) -> ErrorResponse {
+        warn!("Request timed out: {} exceeded its budget of {:?}.", self.path, self.budget);
+        ErrorResponse::ServiceUnavailable(format!(
+            "Request did not complete within {} ms.",
+            self.budget.as_millis()
+        ))
+    }
+}
+
+thread_local! {
+    // Thread-local because Rocket processes each request synchronously in one worker thread.
+    static CURRENT_DEADLINE: RefCell<Option<RequestDeadline>> = RefCell::new(None);
+}
+
+/// Deadline of the request being processed by the current thread, if any.
+pub(crate) fn current() -> Option<RequestDeadline> {
+    CURRENT_DEADLINE.with(|deadline| deadline.borrow().clone())
+}
+
+/// Fairing that assigns each request a deadline given by time budget of the longest matching path
+/// prefix or the default one, and makes it available using [current()]. Handlers enforce it when
+/// running their async code using `App::block_on()`.
+#[derive(Debug)]
+pub(crate) struct Timeout {
+    default_budget: Duration,
+    /// Pairs of path prefix and its time budget.
+    prefix_budgets: Vec<(String, Duration)>,
+}
+
+impl Timeout {
+    /// Construct the fairing with the default and per-prefix budgets of `config`.
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let default_budget = config.request_timeout;
+        let prefix_budgets = config.request_timeouts_by_prefix.clone();
+        info!("Request timeouts: default {:?}, per prefix {:?}.", default_budget, prefix_budgets);
+
+        Self { default_budget, prefix_budgets }
+    }
+
+    /// Time budget of request with given `path`.
+    fn budget(&self, path: &str) -> Duration {
+        self.prefix_budgets
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default_budget, |&(_, budget)| budget)
+    }
+}
+
+impl Fairing for Timeout {
+    fn info(&self) -> Info {
+        Info { name: "Timeout", kind: Kind::Request | Kind::Response }
+    }
+
+    fn on_request(&self, request: &mut Request<'_>, _data: &Data) {
+        let path = request.uri().path();
+        let budget = self.budget(path);
+        let deadline =
+            RequestDeadline { deadline: Instant::now() + budget, budget, path: path.to_string() };
+        CURRENT_DEADLINE.with(|it| it.replace(Some(deadline)));
+    }
+
+    fn on_response(&self, _request: &Request<'_>, _response: &mut Response<'_>) {
+        CURRENT_DEADLINE.with(|it| it.replace(None));
+    }
+}
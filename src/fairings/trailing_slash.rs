@@ -0,0 +1,76 @@
+//! Fairing to make paths with and without a trailing slash equivalent.
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::uri::Origin,
+    Data, Request,
+};
+
+/// Fairing that strips trailing slashes from request paths (except the root `/`), so that e.g.
+/// `/city/v1/get/` is routed to the same handler as `/city/v1/get` rather than being Not Found.
+/// The query is kept as-is. Done by rewriting the request rather than redirecting, which saves
+/// clients a round-trip. Should be attached before other fairings that look at the path.
+#[derive(Debug)]
+pub(crate) struct TrailingSlash;
+
+impl Fairing for TrailingSlash {
+    fn info(&self) -> Info {
+        Info { name: "Trailing slash", kind: Kind::Request }
+    }
+
+    fn on_request(&self, request: &mut Request<'_>, _data: &Data) {
+        let path = request.uri().path();
+        if path.len() <= 1 || !path.ends_with('/') {
+            return;
+        }
+
+        let path = match path.trim_end_matches('/') {
+            "" => "/",
+            path => path,
+        };
+        let query = request.uri().query().map(ToString::to_string);
+        let uri = Origin::new(path.to_string(), query);
+        request.set_uri(uri);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{handlers, App};
+    use rocket::{get, http::Status, local::Client, routes};
+
+    #[get("/")]
+    fn root() -> &'static str {
+        "root"
+    }
+
+    fn client() -> Client {
+        let rocket = rocket::ignite()
+            .manage(App::for_tests())
+            .attach(TrailingSlash)
+            .mount("/", routes![root, handlers::health::livez, handlers::city::get]);
+        Client::new(rocket).expect("valid rocket instance")
+    }
+
+    #[test]
+    fn paths_with_trailing_slash_reach_same_handlers() {
+        let client = client();
+        for &path in &["/livez", "/livez/", "/livez//"] {
+            let mut response = client.get(path).dispatch();
+            assert_eq!(response.status(), Status::Ok, "{}", path);
+            assert_eq!(response.body_string().as_deref(), Some("OK"), "{}", path);
+        }
+        // Missing `id` is reported by the handler, so it is reached without touching Elasticsearch.
+        for &uri in &["/city/v1/get?language=cs", "/city/v1/get/?language=cs"] {
+            assert_eq!(client.get(uri).dispatch().status(), Status::BadRequest, "{}", uri);
+        }
+    }
+
+    #[test]
+    fn root_is_kept() {
+        let mut response = client().get("/").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.body_string().as_deref(), Some("root"));
+    }
+}
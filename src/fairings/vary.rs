@@ -0,0 +1,48 @@
+//! Fairing to set `Vary` header of responses that depend on request headers.
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Request, Response,
+};
+
+/// Request headers that responses of API endpoints given by their paths depend on. Endpoints that
+/// start to negotiate content based on request headers (`Accept`, `Accept-Language`, ...) should
+/// be listed here so that shared caches (CDNs) keep the variants apart.
+const VARY_BY_PATH: &[(&str, &[&str])] =
+    &[("/city/v1/closest", &["Fastly-Geo-Lat", "Fastly-Geo-Lon"])];
+
+/// Fairing that adds request headers the response depends on to its `Vary` header, preserving
+/// any values already set by the handler.
+#[derive(Debug)]
+pub(crate) struct Vary;
+
+impl Fairing for Vary {
+    fn info(&self) -> Info {
+        Info { name: "Vary", kind: Kind::Response }
+    }
+
+    fn on_response(&self, request: &Request<'_>, response: &mut Response<'_>) {
+        let path = request.uri().path();
+        let path_headers = VARY_BY_PATH
+            .iter()
+            .filter(|(vary_path, _)| *vary_path == path)
+            .flat_map(|(_, headers)| headers.iter().copied());
+
+        let mut values: Vec<String> = response
+            .headers()
+            .get("Vary")
+            .flat_map(|value| value.split(','))
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+            .collect();
+        for header in path_headers {
+            if !values.iter().any(|value| value.eq_ignore_ascii_case(header)) {
+                values.push(header.to_string());
+            }
+        }
+
+        if !values.is_empty() {
+            response.set_raw_header("Vary", values.join(", "));
+        }
+    }
+}
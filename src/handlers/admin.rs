@@ -0,0 +1,100 @@
+//! Handlers for `/admin/*` endpoints, meant for operators rather than clients.
+
+use crate::{
+    response::{ErrorResponse::Forbidden, HandlerResult, JsonResult},
+    services::locations_repo,
+    AppState,
+};
+use log::info;
+use rocket::{
+    outcome::Outcome::Success,
+    post,
+    request::{FromRequest, Outcome},
+    Request,
+};
+use rocket_contrib::json::Json;
+use rocket_okapi::{openapi, JsonSchema};
+use serde::Serialize;
+
+/// Request header with the shared secret that authorizes calls of admin endpoints.
+const ADMIN_SECRET_HEADER: &str = "X-Admin-Secret";
+
+/// Response of the `/admin/cache/clear` endpoint: numbers of cleared entries per cache.
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct CacheClearResponse {
+    /// Number of cleared cached regions, e.g. `42`.
+    regions: usize,
+    /// Number of cleared cached lists of countries that have some cities, `0` or `1`.
+    countries: usize,
+}
+
+/// The `/admin/cache/clear` endpoint. HTTP request: `X-Admin-Secret` header, response:
+/// [`CacheClearResponse`].
+///
+/// Clears in-memory caches of the service, e.g. after an update of the Elasticsearch indices.
+/// Responds with HTTP 403 Forbidden unless the header matches the secret configured using the
+/// `GOOUT_ADMIN_SECRET` env variable. Always forbidden if the env variable is not set.
+#[openapi]
+#[post("/admin/cache/clear")]
+pub(crate) fn cache_clear(
+    secret: AdminSecret,
+    app: AppState<'_>,
+) -> JsonResult<CacheClearResponse> {
+    check_admin_secret(&secret, &app)?;
+
+    let response = CacheClearResponse {
+        regions: locations_repo::clear_region_cache(),
+        countries: locations_repo::clear_country_isos_cache(),
+    };
+    info!("Cleared caches: {} regions, {} country lists.", response.regions, response.countries);
+    Ok(Json(response))
+}
+
+/// Error with HTTP 403 Forbidden if `secret` from the request doesn't match the configured one.
+fn check_admin_secret(secret: &AdminSecret, app: &AppState<'_>) -> HandlerResult<()> {
+    let authorized = match (&secret.0, &app.config.admin_secret) {
+        (Some(given), Some(expected)) => constant_time_eq(given.as_bytes(), expected.as_bytes()),
+        _ => false,
+    };
+    if !authorized {
+        return Err(Forbidden(format!("Valid `{}` header required.", ADMIN_SECRET_HEADER)));
+    }
+    Ok(())
+}
+
+/// Compare `given` with `expected` in time independent of the position of the first difference,
+/// so that the secret cannot be guessed byte by byte from response times. Only its length leaks.
+fn constant_time_eq(given: &[u8], expected: &[u8]) -> bool {
+    if given.len() != expected.len() {
+        return false;
+    }
+    given.iter().zip(expected).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Shared secret passed by the client in the `X-Admin-Secret` request header, if any.
+#[derive(Debug)]
+pub(crate) struct AdminSecret(Option<String>);
+
+/// Implement Rocket request guard to get the secret. Always succeeds so that handlers can respond
+/// with a proper error.
+impl<'a, 'r> FromRequest<'a, 'r> for AdminSecret {
+    type Error = ();
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        Success(Self(request.headers().get_one(ADMIN_SECRET_HEADER).map(str::to_string)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_time_eq_compares_whole_secrets() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"Secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+}
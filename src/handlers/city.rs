@@ -1,31 +1,75 @@
 //! Handlers for `/city/*` endpoints.
 
 use crate::{
-    response::{ErrorResponse::BadRequest, HandlerResult, JsonResult},
-    services::locations_repo::{Coordinates, ElasticCity, Language, LocationsElasticRepository},
-    stateful::elasticsearch::WithElastic,
-    AppState,
+    handlers::region::RegionResponse,
+    protobuf::{self, EncodeProtobuf, IntoProtobuf},
+    response::{
+        ErrorResponse::{self, BadRequest, NotFound, PayloadTooLarge},
+        HandlerResult, JsonResult, Negotiated, NegotiatedResult, OrNoContent, WithLastModified,
+        WithServerTiming, WithWarning,
+    },
+    services::{
+        collation::Collation,
+        countries::{country_name, is_valid_iso_code},
+        locations_repo::{
+            round_distance_km, serialize_distance_km, serialize_opt_coordinates,
+            serialize_opt_distance_km, BoundingBoxOrder, CityId, Coordinates, CursorKind,
+            ElasticCity, Language, LocationsElasticRepository, LocationsRepository, MatchedOn,
+            RegionId, SearchAfter, SearchOptions, SearchResults,
+        },
+    },
+    App, AppState,
 };
-use futures::{stream::FuturesOrdered, TryStreamExt};
+use futures::{stream, StreamExt, TryStreamExt};
+use log::warn;
 use rocket::{
     get,
-    http::HeaderMap,
-    outcome::IntoOutcome,
-    request::{FormParseError, FromRequest, LenientForm, Outcome},
-    FromForm, Request,
+    http::{HeaderMap, RawStr},
+    outcome::{IntoOutcome, Outcome::Success},
+    post,
+    request::{
+        FormItem, FormItems, FormParseError, FromForm, FromFormValue, FromRequest, LenientForm,
+        Outcome,
+    },
+    FromForm, FromFormValue, Request,
 };
 use rocket_contrib::json::Json;
 use rocket_okapi::{openapi, JsonSchema};
+use schemars::{gen::SchemaGenerator, schema::Schema, schema_for};
 use serde::Serialize;
-use std::cmp::Reverse;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    time::{Duration, Instant},
+};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
 use validator::Validate;
 
 /// Query for the `/city/v1/get` endpoint.
+#[allow(non_snake_case)]
 #[derive(JsonSchema, FromForm)]
 pub(crate) struct CityQuery {
     /// Id of the city to get, positive integer.
-    id: u64,
+    id: CityId,
     language: Language,
+    /// Whether to include `timezone` field in the response.
+    includeTimezone: bool,
+    /// Whether to include `countryName` field in the response.
+    includeCountryName: bool,
+    /// Whether to include `population` field in the response.
+    includePopulation: bool,
+    /// Whether to include `regions` field in the response.
+    includeRegionHierarchy: bool,
+    /// Whether to include `displayName` field in the response.
+    includeDisplayName: bool,
+    /// Whether to include `officialName` and `commonName` fields in the response.
+    includeNameVariants: bool,
+    /// Whether to include `regionId` field in the response.
+    includeIds: bool,
+    /// Whether to leave out `regionName` from the response and skip fetching the region, for
+    /// faster responses to clients that do not need it. The region is still fetched (as a part of
+    /// `regions`) if `includeRegionHierarchy` is requested too.
+    skipRegion: bool,
 }
 
 /// `City` API entity. All city endpoints respond with this payload (or a composition of it).
@@ -33,250 +77,2601 @@ pub(crate) struct CityQuery {
 #[derive(JsonSchema, Serialize)]
 pub(crate) struct CityResponse {
     /// Id of the city, e.g. `123`.
-    id: u64,
+    id: CityId,
     /// Whether this city is marked as *featured*, e.g. `false`.
     isFeatured: bool,
     /// ISO 3166-1 alpha-2 country code, or a custom 4-letter code, e.g. `"CZ"`.
     countryIso: String,
     /// E.g. `"Plzeň"`.
     name: String,
-    /// E.g. `"Plzeňský kraj"`.
-    regionName: String,
+    /// E.g. `"Plzeňský kraj"`. Empty if the region cannot be fetched and the server is configured
+    /// to degrade gracefully. Absent if requested using `skipRegion`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    regionName: Option<String>,
+    /// Id of the region of the city (the one of `regionName`), e.g. `123`, usable with region
+    /// endpoints. Present only if requested using `includeIds`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    regionId: Option<RegionId>,
+    /// Name of the city with its region appended to tell apart cities of the same name, formatted
+    /// according to conventions of the requested language, e.g. `"Plzeň, Plzeňský kraj"`. Just
+    /// the city name if the region name is empty, the same or skipped. Present only if requested using
+    /// `includeDisplayName`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    displayName: Option<String>,
+    /// Official name of the city, e.g. `"Statutární město Plzeň"`. Same as `commonName` if the
+    /// index has just one name of the city. Present only if requested using
+    /// `includeNameVariants`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    officialName: Option<String>,
+    /// Commonly used name of the city, always the same as `name`, e.g. `"Plzeň"`. Present only if
+    /// requested using `includeNameVariants`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    commonName: Option<String>,
+    /// Hierarchy of administrative regions the city lies in, from its own region (the one of
+    /// `regionName`) to the top-level one. Present only if requested using
+    /// `includeRegionHierarchy`. Has a single item if the region has no parent region, empty if
+    /// the regions cannot be fetched and the server is configured to degrade gracefully.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    regions: Option<Vec<RegionResponse>>,
+    /// IANA timezone of the city, e.g. `"Europe/Prague"`. Present only if requested using
+    /// `includeTimezone` and if the city has timezone data in the index.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timezone: Option<String>,
+    /// Name of the country localized to requested language, e.g. `"Česko"`. Present only if
+    /// requested using `includeCountryName`. Falls back to `countryIso` for unknown countries.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    countryName: Option<String>,
+    /// Number of inhabitants of the city, e.g. `170000`. Present only if requested using
+    /// `includePopulation` and if the city has population data in the index.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    population: Option<u64>,
+    /// Distance of the city centroid from a point given in the request in kilometres rounded to
+    /// whole metres, e.g. `12.345`. Present only for endpoints that sort cities by distance, and for
+    /// `associatedFeatured` (distance from the requested city) if the requested city is not
+    /// featured itself.
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_opt_distance_km")]
+    distanceKm: Option<f64>,
+    /// Which part of the city matched the search query: `"name"` for the name in requested
+    /// language, `"alias"` for a name in other language. Present only for search requested with
+    /// `includeMatchInfo`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    matchedOn: Option<MatchedOn>,
+    /// Coordinates the server actually used to find the city, either from the request or from IP
+    /// geo-location. Present only for `closest` requested with `includeResolvedCoordinates` and
+    /// only if some coordinates were available. Meant for debugging.
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_opt_coordinates")]
+    resolvedCoordinates: Option<Coordinates>,
+    /// Best cities the server considered for `closest`, best first. Present only for `closest`
+    /// requested with `debug` on a server that allows debugging, and only if some coordinates were
+    /// available. Meant for diagnosing geo-location issues.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    candidates: Option<Vec<CandidateResponse>>,
+    /// Whether this is the closest city itself (`true`) rather than an alternative to it. Present
+    /// only for `closest` requested with `alternatives`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    isPrimary: Option<bool>,
+}
+
+/// A city considered by `/city/v1/closest`, for debugging.
+#[allow(non_snake_case)]
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct CandidateResponse {
+    /// Id of the city, e.g. `123`.
+    id: CityId,
+    /// Name of the city in requested language, e.g. `"Plzeň"`.
+    name: String,
+    /// Distance of the city centroid from the resolved coordinates in kilometres rounded to whole
+    /// metres, e.g. `12.3`.
+    #[serde(serialize_with = "serialize_distance_km")]
+    distanceKm: f64,
+    /// Elasticsearch score of the city for approximate coordinates from IP geo-location, e.g.
+    /// `2.5`. Absent for coordinates from the request, where cities are sorted by distance.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score: Option<f64>,
+}
+
+impl IntoProtobuf for CityResponse {
+    type Message = protobuf::City;
+
+    fn into_protobuf(self) -> protobuf::City {
+        protobuf::City {
+            id: self.id.0,
+            is_featured: self.isFeatured,
+            country_iso: self.countryIso,
+            name: self.name,
+            region_name: self.regionName.unwrap_or_default(),
+            display_name: self.displayName,
+            region_id: self.regionId.map(|it| it.0),
+            official_name: self.officialName,
+            common_name: self.commonName,
+            regions: self
+                .regions
+                .unwrap_or_default()
+                .into_iter()
+                .map(IntoProtobuf::into_protobuf)
+                .collect(),
+            timezone: self.timezone,
+            country_name: self.countryName,
+            population: self.population,
+            distance_km: self.distanceKm.map(round_distance_km),
+            matched_on: self.matchedOn.map(|it| protobuf::MatchedOn::from(it) as i32),
+            resolved_coordinates: self.resolvedCoordinates.map(|it| it.rounded().into()),
+            candidates: self
+                .candidates
+                .unwrap_or_default()
+                .into_iter()
+                .map(IntoProtobuf::into_protobuf)
+                .collect(),
+            is_primary: self.isPrimary,
+        }
+    }
+}
+
+impl IntoProtobuf for CandidateResponse {
+    type Message = protobuf::Candidate;
+
+    fn into_protobuf(self) -> protobuf::Candidate {
+        protobuf::Candidate {
+            id: self.id.0,
+            name: self.name,
+            distance_km: round_distance_km(self.distanceKm),
+            score: self.score,
+        }
+    }
+}
+
+/// Optional parts of [CityResponse] requested by the client using `include*` query flags, and
+/// server-wide options of its construction.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct CityResponseOptions {
+    pub(crate) include_timezone: bool,
+    pub(crate) include_country_name: bool,
+    pub(crate) include_population: bool,
+    pub(crate) include_region_hierarchy: bool,
+    pub(crate) include_display_name: bool,
+    pub(crate) include_name_variants: bool,
+    pub(crate) include_ids: bool,
+    /// Leave [CityResponse::regionName] out without fetching the region (unless
+    /// `include_region_hierarchy` is set, the hierarchy includes it).
+    pub(crate) skip_region: bool,
+    /// Fill [CityResponse::distanceKm] with distance from these coordinates.
+    pub(crate) distance_from: Option<Coordinates>,
+    /// Respond with empty [CityResponse::regionName] instead of failing if the region cannot be
+    /// fetched.
+    pub(crate) degrade_on_region_error: bool,
 }
 
+/// Implement `options()` for query structs with the `include*` and `skipRegion` flags of
+/// [CityResponseOptions]. Rocket forms cannot be nested, so each query struct has the flags as its
+/// own fields, and this constructs the options from them in one place.
+macro_rules! impl_city_response_options {
+    ($($query:ty),+ $(,)?) => {$(
+        impl $query {
+            /// Options of city responses to this query, with distances from `distance_from`.
+            fn options(
+                &self,
+                app: &$crate::App,
+                distance_from: Option<$crate::services::locations_repo::Coordinates>,
+            ) -> $crate::handlers::city::CityResponseOptions {
+                $crate::handlers::city::CityResponseOptions {
+                    include_timezone: self.includeTimezone,
+                    include_country_name: self.includeCountryName,
+                    include_population: self.includePopulation,
+                    include_region_hierarchy: self.includeRegionHierarchy,
+                    include_display_name: self.includeDisplayName,
+                    include_name_variants: self.includeNameVariants,
+                    include_ids: self.includeIds,
+                    skip_region: self.skipRegion,
+                    distance_from,
+                    degrade_on_region_error: app.config.degrade_on_region_error,
+                }
+            }
+        }
+    )+};
+}
+
+impl_city_response_options!(
+    CityQuery,
+    CitiesQuery,
+    CityByNameQuery,
+    FeaturedQuery,
+    FeaturedByCountryQuery,
+    ListQuery,
+    ChangesQuery,
+    BoundingBoxQuery,
+    WithinPolygonQuery,
+    SearchQuery,
+    ClosestQuery,
+    ClosestBatchQuery,
+    AssociatedFeaturedQuery,
+    NeighborsQuery,
+    ClosestPerCountryQuery,
+);
+
 /// Type alias to parse query parameters using a struct, catching errors, ignoring extra params.
-type Parse<'f, T> = Result<LenientForm<T>, FormParseError<'f>>;
+pub(crate) type Parse<'f, T> = Result<LenientForm<T>, <T as FromForm<'f>>::Error>;
+
+/// Request header to turn strict parsing of query parameters on for a single request.
+const STRICT_PARAMS_HEADER: &str = "X-Strict-Params";
+
+/// Rocket request guard with raw query of the request if strict parsing of query parameters is on
+/// for it, either server-wide or using the `X-Strict-Params: true` request header. Complements
+/// [Parse], which ignores extra params.
+#[derive(Debug)]
+pub(crate) struct StrictParams(Option<String>);
+
+impl StrictParams {
+    /// Error with HTTP 400 Bad Request listing query parameters that are not fields of query
+    /// struct `T`, if strict parsing is on.
+    pub(crate) fn check<T: JsonSchema>(&self) -> HandlerResult<()> {
+        let raw_query = match &self.0 {
+            Some(raw_query) => raw_query,
+            None => return Ok(()),
+        };
+        // Field names of query structs are conveniently available from their JSON schema.
+        let known_params = schema_for!(T).schema.object.map(|it| it.properties).unwrap_or_default();
+        let mut unknown_params: Vec<String> = FormItems::from(raw_query.as_str())
+            .map(|item| item.key.url_decode_lossy())
+            .filter(|param| !known_params.contains_key(param))
+            .collect();
+        if unknown_params.is_empty() {
+            return Ok(());
+        }
+        unknown_params.sort();
+        unknown_params.dedup();
+        Err(BadRequest(format!("Unknown query parameters: {}.", unknown_params.join(", "))))
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for StrictParams {
+    type Error = ();
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let app = request.guard::<AppState<'_>>()?;
+        let strict = app.config.strict_params
+            || request.headers().get_one(STRICT_PARAMS_HEADER).map_or(false, |it| it == "true");
+        let raw_query = request.uri().query().unwrap_or_default();
+        Success(Self(if strict { Some(raw_query.to_string()) } else { None }))
+    }
+}
 
 /// The `/city/v1/get` endpoint. HTTP request: [`CityQuery`], response: [`CityResponse`].
 ///
 /// Get city of given ID localized to given language.
+/// Supports conditional requests using `If-Modified-Since` if the city has its update time set.
 #[openapi]
 #[get("/city/v1/get?<query..>")]
-pub(crate) fn get(query: Parse<'_, CityQuery>, app: AppState<'_>) -> JsonResult<CityResponse> {
+pub(crate) fn get(
+    query: Parse<'_, CityQuery>,
+    strict: StrictParams,
+    app: AppState<'_>,
+) -> HandlerResult<WithLastModified<Negotiated<CityResponse>>> {
     let query = query?;
+    strict.check::<CityQuery>()?;
     let locations_es_repo = LocationsElasticRepository(&app);
 
     app.block_on(async {
         let es_city = locations_es_repo.get_city(query.id).await?;
+        let last_modified = es_city.updatedAt.map(|millis| (millis / 1000) as i64);
+
+        let options = query.options(&app, None);
+        let body =
+            Negotiated(es_city.into_resp(&locations_es_repo, query.language, options).await?);
+        Ok(WithLastModified { body, last_modified })
+    })
+}
+
+/// Maximum number of ids in a single `/city/v1/getMany` request.
+const GET_MANY_MAX_IDS: usize = 100;
+
+/// List of city ids in a query parameter: comma-separated (e.g. `ids=1,2`), and if the query
+/// struct merges them, repeated (e.g. `ids=1&ids=2`). Whitespace around ids is ignored and
+/// duplicates are dropped, keeping order of first occurrence.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct CityIds(Vec<CityId>);
+
+impl CityIds {
+    /// Append ids of `other` that are not in this list yet.
+    fn merge(&mut self, other: CityIds) {
+        for id in other.0 {
+            if !self.0.contains(&id) {
+                self.0.push(id);
+            }
+        }
+    }
+}
+
+/// Errors with Bad Request naming the invalid id, see [parse_city_ids()].
+impl<'v> FromFormValue<'v> for CityIds {
+    type Error = ErrorResponse;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Self, Self::Error> {
+        let text = form_value
+            .url_decode()
+            .map_err(|_| BadRequest(format!("Invalid encoding of `ids`: `{}`.", form_value)))?;
+        parse_city_ids(&text).map(Self)
+    }
+}
+
+/// Documented as the comma-separated string it is given as.
+impl JsonSchema for CityIds {
+    fn schema_name() -> String {
+        String::schema_name()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        String::json_schema(gen)
+    }
+}
+
+/// Query for the `/city/v1/getMany` endpoint.
+#[allow(non_snake_case)]
+#[derive(JsonSchema)]
+pub(crate) struct CitiesQuery {
+    /// Comma-separated ids of the cities to get, e.g. `101748113,101752777`. At most 100 ids,
+    /// whitespace around them is ignored. May be repeated (e.g. `ids=101748113&ids=101752777`),
+    /// ids of all occurrences are then merged.
+    ids: CityIds,
+    language: Language,
+    /// Whether to include `timezone` field in the response.
+    includeTimezone: bool,
+    /// Whether to include `countryName` field in the response.
+    includeCountryName: bool,
+    /// Whether to include `population` field in the response.
+    includePopulation: bool,
+    /// Whether to include `regions` field in the response.
+    includeRegionHierarchy: bool,
+    /// Whether to include `displayName` field in the response.
+    includeDisplayName: bool,
+    /// Whether to include `officialName` and `commonName` fields in the response.
+    includeNameVariants: bool,
+    /// Whether to include `regionId` field in the response.
+    includeIds: bool,
+    /// Whether to leave out `regionName` from the response and skip fetching the region, for
+    /// faster responses to clients that do not need it. The region is still fetched (as a part of
+    /// `regions`) if `includeRegionHierarchy` is requested too.
+    skipRegion: bool,
+}
+
+/// Implemented by hand so that ids of all occurrences of a repeated `ids` parameter are merged, the
+/// derived implementation would keep only the last one. Errors are [ErrorResponse] so that invalid
+/// ids are named in the message.
+impl<'f> FromForm<'f> for CitiesQuery {
+    type Error = ErrorResponse;
+
+    fn from_form(items: &mut FormItems<'f>, strict: bool) -> Result<Self, Self::Error> {
+        let (mut ids, mut language) = (None, None);
+        let mut query = Self {
+            ids: CityIds::default(),
+            language: Language::EN, // Replaced below, `language` is required.
+            includeTimezone: false,
+            includeCountryName: false,
+            includePopulation: false,
+            includeRegionHierarchy: false,
+            includeDisplayName: false,
+            includeNameVariants: false,
+            includeIds: false,
+            skipRegion: false,
+        };
+        for item in items {
+            match item.key.as_str() {
+                "ids" => ids
+                    .get_or_insert_with(CityIds::default)
+                    .merge(CityIds::from_form_value(item.value)?),
+                "language" => language = Some(form_value(&item)?),
+                "includeTimezone" => query.includeTimezone = form_value(&item)?,
+                "includeCountryName" => query.includeCountryName = form_value(&item)?,
+                "includePopulation" => query.includePopulation = form_value(&item)?,
+                "includeRegionHierarchy" => query.includeRegionHierarchy = form_value(&item)?,
+                "includeDisplayName" => query.includeDisplayName = form_value(&item)?,
+                "includeNameVariants" => query.includeNameVariants = form_value(&item)?,
+                "includeIds" => query.includeIds = form_value(&item)?,
+                "skipRegion" => query.skipRegion = form_value(&item)?,
+                _ if strict => return Err(FormParseError::Unknown(item.key, item.value).into()),
+                _ => {}
+            }
+        }
+        query.ids = ids.ok_or_else(|| FormParseError::Missing("ids".into()))?;
+        query.language = language.ok_or_else(|| FormParseError::Missing("language".into()))?;
+        Ok(query)
+    }
+}
+
+/// Value of form `item` parsed as `T`, for hand-written [FromForm] implementations.
+fn form_value<'f, T: FromFormValue<'f>>(item: &FormItem<'f>) -> Result<T, ErrorResponse> {
+    T::from_form_value(item.value)
+        .map_err(|_| FormParseError::BadValue(item.key, item.value).into())
+}
+
+/// The `/city/v1/getMany` endpoint. HTTP request: [`CitiesQuery`], response: [`MultiCityResponse`].
+///
+/// Get cities of given IDs localized to given language, in order of first occurrence of their ids.
+/// Duplicate ids are ignored. Responds with Bad Request naming the first id that is not a positive
+/// integer and with Not Found listing ids of cities that don't exist.
+#[openapi]
+#[get("/city/v1/getMany?<query..>")]
+pub(crate) fn get_many(
+    query: Parse<'_, CitiesQuery>,
+    strict: StrictParams,
+    app: AppState<'_>,
+) -> NegotiatedResult<MultiCityResponse> {
+    let query = query?;
+    strict.check::<CitiesQuery>()?;
+    let ids = &query.ids.0;
+    if ids.len() > GET_MANY_MAX_IDS {
+        return Err(PayloadTooLarge(format!("At most {} ids are accepted.", GET_MANY_MAX_IDS)));
+    }
+    let locations_es_repo = LocationsElasticRepository(&app);
+
+    app.block_on(async {
+        let mut es_cities: HashMap<CityId, ElasticCity> =
+            locations_es_repo.get_cities(ids).await?.into_iter().map(|c| (c.id, c)).collect();
+        let missing: Vec<String> =
+            ids.iter().filter(|&id| !es_cities.contains_key(id)).map(|id| id.to_string()).collect();
+        if !missing.is_empty() {
+            return Err(NotFound(format!("Cities not found: {}.", missing.join(", "))));
+        }
+        let es_cities = ids.iter().filter_map(|id| es_cities.remove(id)).collect();
+
+        let options = query.options(&app, None);
+        es_cities_into_resp(
+            &locations_es_repo,
+            es_cities,
+            query.language,
+            options,
+            app.config.max_concurrent_region_fetches,
+            app.config.max_response_cities,
+        )
+        .await
+    })
+}
+
+/// Parse comma-separated list of city ids, trimming whitespace around them and dropping duplicates
+/// while keeping order of first occurrence. Error with Bad Request naming the first invalid token,
+/// including empty ones (e.g. from `1,,2`).
+fn parse_city_ids(ids: &str) -> HandlerResult<Vec<CityId>> {
+    let (mut city_ids, mut seen) = (Vec::new(), HashSet::new());
+    for token in ids.split(',').map(str::trim) {
+        let id = match token.parse() {
+            Ok(id) if id > 0 => CityId(id),
+            _ => return Err(BadRequest(format!("Invalid city id `{}` in `ids`.", token))),
+        };
+        if seen.insert(id) {
+            city_ids.push(id);
+        }
+    }
+    Ok(city_ids)
+}
+
+/// Query for the `/city/v1/getByName` endpoint.
+#[allow(non_snake_case)]
+#[derive(JsonSchema, FromForm)]
+pub(crate) struct CityByNameQuery {
+    /// Exact name of the city in given `language`, including diacritics, e.g. `Plzeň`.
+    name: String,
+    /// ISO 3166-1 alpha-2 country code of the city.
+    countryIso: String,
+    language: Language,
+    /// Whether to include `timezone` field in the response.
+    includeTimezone: bool,
+    /// Whether to include `countryName` field in the response.
+    includeCountryName: bool,
+    /// Whether to include `population` field in the response.
+    includePopulation: bool,
+    /// Whether to include `regions` field in the response.
+    includeRegionHierarchy: bool,
+    /// Whether to include `displayName` field in the response.
+    includeDisplayName: bool,
+    /// Whether to include `officialName` and `commonName` fields in the response.
+    includeNameVariants: bool,
+    /// Whether to include `regionId` field in the response.
+    includeIds: bool,
+    /// Whether to leave out `regionName` from the response and skip fetching the region, for
+    /// faster responses to clients that do not need it. The region is still fetched (as a part of
+    /// `regions`) if `includeRegionHierarchy` is requested too.
+    skipRegion: bool,
+}
+
+/// The `/city/v1/getByName` endpoint. HTTP request: [`CityByNameQuery`], response: [`CityResponse`].
+///
+/// Get city by its exact localized name within a country. Unlike `/city/v1/search`, this does no
+/// relevance ranking. Responds with Not Found if there is no such city and with Bad Request if
+/// the name is ambiguous (more cities match).
+#[openapi]
+#[get("/city/v1/getByName?<query..>")]
+pub(crate) fn get_by_name(
+    query: Parse<'_, CityByNameQuery>,
+    strict: StrictParams,
+    app: AppState<'_>,
+) -> NegotiatedResult<CityResponse> {
+    let query = query?;
+    strict.check::<CityByNameQuery>()?;
+    let locations_es_repo = LocationsElasticRepository(&app);
+
+    app.block_on(async {
+        let name = normalize_text(&query.name);
+        let mut es_cities =
+            locations_es_repo.get_cities_by_name(&name, query.language, &query.countryIso).await?;
+        let description = format!("City named `{}` in {}", query.name, query.countryIso);
+        let es_city = match es_cities.len() {
+            0 => return Err(NotFound(format!("{} not found.", description))),
+            1 => es_cities.remove(0),
+            _ => return Err(BadRequest(format!("{} is ambiguous.", description))),
+        };
 
-        Ok(Json(es_city.into_resp(&app, query.language).await?))
+        let options = query.options(&app, None);
+        Ok(Negotiated(es_city.into_resp(&locations_es_repo, query.language, options).await?))
     })
 }
 
 /// Query for the `/city/v1/featured` endpoint.
+#[allow(non_snake_case)]
 #[derive(JsonSchema, FromForm)]
 pub(crate) struct FeaturedQuery {
     language: Language,
+    /// Whether to include `timezone` field in the response.
+    includeTimezone: bool,
+    /// Whether to include `countryName` field in the response.
+    includeCountryName: bool,
+    /// Whether to include `population` field in the response.
+    includePopulation: bool,
+    /// Whether to include `regions` field in the response.
+    includeRegionHierarchy: bool,
+    /// Whether to include `displayName` field in the response.
+    includeDisplayName: bool,
+    /// Whether to include `officialName` and `commonName` fields in the response.
+    includeNameVariants: bool,
+    /// Whether to include `regionId` field in the response.
+    includeIds: bool,
+    /// Whether to leave out `regionName` from the response and skip fetching the region, for
+    /// faster responses to clients that do not need it. The region is still fetched (as a part of
+    /// `regions`) if `includeRegionHierarchy` is requested too.
+    skipRegion: bool,
+    /// Maximum number of cities to return, all cities by default. If `offset` is given, defaults
+    /// to 100. At least 1 and at most 1000 unless configured otherwise.
+    limit: Option<usize>,
+    /// Number of cities to skip from the start of the list, 0 by default.
+    offset: Option<usize>,
+    /// Whether to sort cities by population (descending) rather than preferring cities of the
+    /// country associated with `language`.
+    sortByPopulation: bool,
+    /// Debugging output to include in the response: `timings` for the `timings` field (only in
+    /// JSON responses) and the `Server-Timing` header, which also has the duration of serializing
+    /// the response. Ignored unless the server allows debugging timings.
+    debug: Option<DebugMode>,
+}
+
+/// Query for the `/city/v1/featuredByCountry` endpoint.
+#[allow(non_snake_case)]
+#[derive(JsonSchema, FromForm)]
+pub(crate) struct FeaturedByCountryQuery {
+    language: Language,
+    /// Whether to include `timezone` field in the response.
+    includeTimezone: bool,
+    /// Whether to include `countryName` field in the response.
+    includeCountryName: bool,
+    /// Whether to include `population` field in the response.
+    includePopulation: bool,
+    /// Whether to include `regions` field in the response.
+    includeRegionHierarchy: bool,
+    /// Whether to include `displayName` field in the response.
+    includeDisplayName: bool,
+    /// Whether to include `officialName` and `commonName` fields in the response.
+    includeNameVariants: bool,
+    /// Whether to include `regionId` field in the response.
+    includeIds: bool,
+    /// Whether to leave out `regionName` from the response and skip fetching the region, for
+    /// faster responses to clients that do not need it. The region is still fetched (as a part of
+    /// `regions`) if `includeRegionHierarchy` is requested too.
+    skipRegion: bool,
+}
+
+/// Query for the `/city/v1/featuredCount` endpoint.
+#[allow(non_snake_case)]
+#[derive(JsonSchema, FromForm)]
+pub(crate) struct FeaturedCountQuery {
+    /// ISO 3166-1 alpha-2 country code. Can be used to count only featured cities of a country.
+    countryIso: Option<String>,
+}
+
+/// Number of entities, e.g. featured cities.
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct CountResponse {
+    /// E.g. `42`.
+    count: u64,
 }
 
 /// A list of `City` API entities.
+#[allow(non_snake_case)]
 #[derive(JsonSchema, Serialize)]
 pub(crate) struct MultiCityResponse {
     cities: Vec<CityResponse>,
+    /// Total number of cities available when the list is paginated, e.g. `42`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<usize>,
+    /// Opaque cursor to get the next page with, present when the list is paginated using cursors
+    /// and this is not the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nextCursor: Option<String>,
+    /// `true` if the server cut the search short to bound its cost, so that the list may miss
+    /// some matching cities. Absent otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    partial: Option<bool>,
+    /// Durations of phases of handling the request. Present only if requested using
+    /// `debug=timings` on a server that allows it, and only in JSON responses. Meant for
+    /// performance investigations.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timings: Option<TimingsResponse>,
+    /// Suggested corrections of the search query, e.g. `["praha"]` for `prha`. Present only if
+    /// requested using `suggest` and no city matches.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suggestions: Option<Vec<String>>,
+}
+
+/// Durations of phases of handling a request, for debugging. Duration of serializing the response
+/// can only be sent in the `Server-Timing` header, which has all the phases.
+#[allow(non_snake_case)]
+#[derive(Clone, Copy, Debug, JsonSchema, Serialize)]
+pub(crate) struct TimingsResponse {
+    /// Time spent querying cities from Elasticsearch in milliseconds, e.g. `12.345`.
+    elasticsearchMs: f64,
+    /// Time spent building the response from the cities in milliseconds, mostly resolving their
+    /// regions (possibly from cache).
+    buildMs: f64,
+}
+
+impl TimingsResponse {
+    /// Construct from measured durations of the phases.
+    fn new(elasticsearch: Duration, build: Duration) -> Self {
+        let millis = |duration: Duration| duration.as_micros() as f64 / 1000.0;
+        Self { elasticsearchMs: millis(elasticsearch), buildMs: millis(build) }
+    }
+
+    /// Phases for the `Server-Timing` header.
+    fn server_timing(self) -> Vec<(&'static str, f64)> {
+        vec![("elasticsearch", self.elasticsearchMs), ("build", self.buildMs)]
+    }
+}
+
+/// Kinds of debugging output of list endpoints.
+#[serde(rename_all = "camelCase")] // Not used by Rocket itself, but *is* used by rocket_okapi.
+#[derive(Clone, Copy, Debug, Eq, FromFormValue, JsonSchema, PartialEq)]
+pub(crate) enum DebugMode {
+    /// Durations of phases of handling the request.
+    Timings,
+}
+
+impl IntoProtobuf for MultiCityResponse {
+    type Message = protobuf::MultiCity;
+
+    fn into_protobuf(self) -> protobuf::MultiCity {
+        protobuf::MultiCity {
+            cities: self.cities.into_iter().map(CityResponse::into_protobuf).collect(),
+            total: self.total.map(|it| it as u64),
+            next_cursor: self.nextCursor,
+            partial: self.partial,
+            suggestions: self.suggestions.unwrap_or_default(),
+        }
+    }
 }
 
 /// The `/city/v1/featured` endpoint. HTTP request: [`FeaturedQuery`], response: [`MultiCityResponse`].
 ///
-/// Returns a list of all featured cities.
+/// Returns a list of all featured cities, cities in countries matching `language` first (in order
+/// of preference of the countries, e.g. Czechia then Slovakia for `en`), or sorted by population
+/// if `sortByPopulation` is set (cities without population data last). Ties are ordered by city id.
+/// Optional `limit` and `offset` paginate over this sorted list, `total` is then its full length.
 #[openapi]
 #[get("/city/v1/featured?<query..>")]
 pub(crate) fn featured(
     query: Parse<'_, FeaturedQuery>,
+    strict: StrictParams,
+    app: AppState<'_>,
+) -> HandlerResult<WithServerTiming<Negotiated<MultiCityResponse>>> {
+    let query = query?;
+    strict.check::<FeaturedQuery>()?;
+    let locations_es_repo = LocationsElasticRepository(&app);
+
+    app.block_on(featured_response(&locations_es_repo, &app, &query))
+}
+
+/// Respond to `/city/v1/featured` using `repo`. Separated from [featured] so that it can be
+/// tested against a mock repository.
+async fn featured_response(
+    repo: &impl LocationsRepository,
+    app: &App,
+    query: &FeaturedQuery,
+) -> HandlerResult<WithServerTiming<Negotiated<MultiCityResponse>>> {
+    let paginated = query.limit.is_some() || query.offset.is_some();
+    let limit = if paginated { app.config.page_limit(query.limit, None)? } else { usize::MAX };
+    let timings = query.debug == Some(DebugMode::Timings) && app.config.debug_timings;
+
+    let started = Instant::now();
+    let mut es_cities = repo.get_featured_cities().await?;
+    let elasticsearch_elapsed = started.elapsed();
+
+    // Elasticsearch may return the cities in varying order, break ties by id so that the
+    // output is reproducible across calls (and pages of it consistent).
+    if query.sortByPopulation {
+        es_cities.sort_by_key(|c| (Reverse(c.population), c.id.0));
+    } else {
+        let preferred_country_isos = preferred_country_isos(query.language);
+        es_cities.sort_by_key(|c| {
+            let position = preferred_country_isos.iter().position(|&iso| iso == c.countryIso);
+            (position.unwrap_or(preferred_country_isos.len()), c.id.0)
+        });
+    }
+
+    let total = es_cities.len();
+    let es_cities = es_cities.into_iter().skip(query.offset.unwrap_or(0)).take(limit).collect();
+
+    let options = query.options(app, None);
+    let build_started = Instant::now();
+    let mut response = es_cities_into_resp(
+        repo,
+        es_cities,
+        query.language,
+        options,
+        app.config.max_concurrent_region_fetches,
+        app.config.max_response_cities,
+    )
+    .await?;
+    if paginated {
+        response.total = Some(total);
+    }
+    if timings {
+        response.timings =
+            Some(TimingsResponse::new(elasticsearch_elapsed, build_started.elapsed()));
+    }
+    let phases = response.timings.map(TimingsResponse::server_timing);
+    Ok(WithServerTiming { body: response, phases })
+}
+
+/// ISO codes of countries whose featured cities are listed first for users of `language`, in order
+/// of preference.
+fn preferred_country_isos(language: Language) -> &'static [&'static str] {
+    match language {
+        Language::CS => &["CZ"],
+        Language::DE => &["DE"],
+        Language::EN => &["CZ", "SK"],
+        Language::PL => &["PL"],
+        Language::SK => &["SK"],
+    }
+}
+
+/// The `/city/v1/featuredByCountry` endpoint. HTTP request: [`FeaturedByCountryQuery`], response:
+/// object with ISO 3166-1 alpha-2 country codes as keys and arrays of [`CityResponse`] as values.
+///
+/// Returns all featured cities grouped by their `countryIso`, each group sorted by name (in the
+/// collation of `language`), e.g. for a country picker.
+#[openapi]
+#[get("/city/v1/featuredByCountry?<query..>")]
+pub(crate) fn featured_by_country(
+    query: Parse<'_, FeaturedByCountryQuery>,
+    strict: StrictParams,
     app: AppState<'_>,
-) -> JsonResult<MultiCityResponse> {
+) -> JsonResult<BTreeMap<String, Vec<CityResponse>>> {
     let query = query?;
+    strict.check::<FeaturedByCountryQuery>()?;
     let locations_es_repo = LocationsElasticRepository(&app);
 
     app.block_on(async {
         let mut es_cities = locations_es_repo.get_featured_cities().await?;
+        let (name_key, collation) =
+            (query.language.name_key(), Collation::for_language(query.language));
+        es_cities.sort_by_cached_key(|c| {
+            (c.names.get(&name_key).map(|n| collation.sort_key(n)), c.id.0)
+        });
 
-        let preferred_country_iso = match query.language {
-            Language::CS => "CZ",
-            Language::DE => "DE",
-            Language::EN => "CZ",
-            Language::PL => "PL",
-            Language::SK => "SK",
-        };
-        es_cities.sort_by_key(|c| Reverse(c.countryIso == preferred_country_iso));
+        let options = query.options(&app, None);
+        let response = es_cities_into_resp(
+            &locations_es_repo,
+            es_cities,
+            query.language,
+            options,
+            app.config.max_concurrent_region_fetches,
+            app.config.max_response_cities,
+        )
+        .await?;
 
-        es_cities_into_resp(&app, es_cities, query.language).await
+        // Cities are already sorted by name, grouping keeps their order within each country.
+        let mut groups: BTreeMap<String, Vec<CityResponse>> = BTreeMap::new();
+        for city in response.0.cities {
+            groups.entry(city.countryIso.clone()).or_default().push(city);
+        }
+        Ok(Json(groups))
     })
 }
 
-/// Query for the `/city/v1/search` endpoint.
+/// The `/city/v1/featuredCount` endpoint. HTTP request: [`FeaturedCountQuery`], response:
+/// [`CountResponse`].
+///
+/// Returns the number of featured cities, i.e. `total` of `/city/v1/featured`, without fetching
+/// and localizing them, which makes it much cheaper.
+#[openapi]
+#[get("/city/v1/featuredCount?<query..>")]
+pub(crate) fn featured_count(
+    query: Parse<'_, FeaturedCountQuery>,
+    strict: StrictParams,
+    app: AppState<'_>,
+) -> JsonResult<CountResponse> {
+    let query = query?;
+    strict.check::<FeaturedCountQuery>()?;
+    let country_iso = query.countryIso.as_deref();
+    if let Some(iso_code) = country_iso.filter(|it| !is_valid_iso_code(it)) {
+        return Err(BadRequest(format!("Invalid `countryIso` `{}`.", iso_code)));
+    }
+    let locations_es_repo = LocationsElasticRepository(&app);
+
+    app.block_on(async {
+        let count = locations_es_repo.count_featured_cities(country_iso).await?;
+        Ok(Json(CountResponse { count }))
+    })
+}
+
+/// Query for the `/city/v1/list` endpoint.
 #[allow(non_snake_case)]
 #[derive(JsonSchema, FromForm)]
-pub(crate) struct SearchQuery {
-    /// The search query.
-    query: String,
-    /// ISO 3166-1 alpha-2 country code. Can be used to limit scope of the search to a given country.
-    countryIso: Option<String>,
+pub(crate) struct ListQuery {
     language: Language,
+    /// Whether to include `timezone` field in the response.
+    includeTimezone: bool,
+    /// Whether to include `countryName` field in the response.
+    includeCountryName: bool,
+    /// Whether to include `population` field in the response.
+    includePopulation: bool,
+    /// Whether to include `regions` field in the response.
+    includeRegionHierarchy: bool,
+    /// Whether to include `displayName` field in the response.
+    includeDisplayName: bool,
+    /// Whether to include `officialName` and `commonName` fields in the response.
+    includeNameVariants: bool,
+    /// Whether to include `regionId` field in the response.
+    includeIds: bool,
+    /// Whether to leave out `regionName` from the response and skip fetching the region, for
+    /// faster responses to clients that do not need it. The region is still fetched (as a part of
+    /// `regions`) if `includeRegionHierarchy` is requested too.
+    skipRegion: bool,
+    /// Maximum number of cities to return, at least 1. Defaults to 100 and is at most 1000 unless
+    /// configured otherwise.
+    limit: Option<usize>,
+    /// Cursor from `nextCursor` of the previous page. The first page is returned if not given.
+    cursor: Option<String>,
 }
 
-/// The `/city/v1/search` endpoint. HTTP request: [`SearchQuery`], response: [`MultiCityResponse`].
+/// The `/city/v1/list` endpoint. HTTP request: [`ListQuery`], response: [`MultiCityResponse`].
 ///
-/// Returns list of cities matching the 'query' parameter.
-/// The response is limited to 10 cities and no pagination is provided.
+/// Returns all cities in a stable order, one page at a time. Each page except the last one has
+/// `nextCursor` set, pass it as `cursor` to get the next page. Cursors are opaque tokens (not
+/// URLs), clients should neither interpret nor construct them.
 #[openapi]
-#[get("/city/v1/search?<query..>")]
-pub(crate) fn search(
-    query: Parse<'_, SearchQuery>,
+#[get("/city/v1/list?<query..>")]
+pub(crate) fn list(
+    query: Parse<'_, ListQuery>,
+    strict: StrictParams,
     app: AppState<'_>,
-) -> JsonResult<MultiCityResponse> {
+) -> NegotiatedResult<MultiCityResponse> {
     let query = query?;
+    strict.check::<ListQuery>()?;
+    let limit = app.config.page_limit(query.limit, None)?;
+    let search_after = query
+        .cursor
+        .as_deref()
+        .map(|it| SearchAfter::from_cursor(it, CursorKind::List))
+        .transpose()?;
     let locations_es_repo = LocationsElasticRepository(&app);
 
     app.block_on(async {
-        let es_cities = locations_es_repo
-            .search(&query.query, query.language, query.countryIso.as_deref())
-            .await?;
+        let (es_cities, next_search_after) =
+            locations_es_repo.list_cities(limit, search_after).await?;
 
-        es_cities_into_resp(&app, es_cities, query.language).await
+        let options = query.options(&app, None);
+        let mut response = es_cities_into_resp(
+            &locations_es_repo,
+            es_cities,
+            query.language,
+            options,
+            app.config.max_concurrent_region_fetches,
+            app.config.max_response_cities,
+        )
+        .await?;
+        response.nextCursor = next_search_after.map(|it| it.to_cursor(CursorKind::List));
+        Ok(response)
     })
 }
 
-/// Query for the `/city/v1/closest` endpoint.
+/// Query for the `/city/v1/changes` endpoint.
+#[allow(non_snake_case)]
 #[derive(JsonSchema, FromForm)]
-pub(crate) struct ClosestQuery {
-    /// Latitude in decimal degrees with . as decimal separator.
-    lat: Option<f64>,
-    /// Longitude in decimal degrees with . as decimal separator.
-    lon: Option<f64>,
+pub(crate) struct ChangesQuery {
     language: Language,
+    /// Whether to include `timezone` field in the response.
+    includeTimezone: bool,
+    /// Whether to include `countryName` field in the response.
+    includeCountryName: bool,
+    /// Whether to include `population` field in the response.
+    includePopulation: bool,
+    /// Whether to include `regions` field in the response.
+    includeRegionHierarchy: bool,
+    /// Whether to include `displayName` field in the response.
+    includeDisplayName: bool,
+    /// Whether to include `officialName` and `commonName` fields in the response.
+    includeNameVariants: bool,
+    /// Whether to include `regionId` field in the response.
+    includeIds: bool,
+    /// Whether to leave out `regionName` from the response and skip fetching the region, for
+    /// faster responses to clients that do not need it. The region is still fetched (as a part of
+    /// `regions`) if `includeRegionHierarchy` is requested too.
+    skipRegion: bool,
+    /// Maximum number of cities to return, at least 1. Defaults to 100 and is at most 1000 unless
+    /// configured otherwise.
+    limit: Option<usize>,
+    /// Return cities changed after this time in milliseconds since Unix epoch. Ignored if `cursor`
+    /// is given. All cities are returned if neither is given.
+    since: Option<u64>,
+    /// Cursor from `nextCursor` of the previous response.
+    cursor: Option<String>,
 }
 
-impl ClosestQuery {
-    /// Extract optional coordinates out of query, error if only one of them is given.
-    fn coordinates(&self) -> HandlerResult<Option<Coordinates>> {
-        match (self.lat, self.lon) {
-            (Some(lat), Some(lon)) => Ok(Some(Coordinates { lat, lon })),
-            (None, None) => Ok(None),
-            _ => Err(BadRequest("either both or none of `lat`, `lon` expected".to_string())),
-        }
-    }
-}
-
-/// The `/city/v1/closest` endpoint. HTTP request: [`ClosestQuery`], response: [`CityResponse`].
+/// The `/city/v1/changes` endpoint. HTTP request: [`ChangesQuery`], response: [`MultiCityResponse`].
 ///
-/// Returns a single city that is closest to the coordinates.
-/// If coordinates are not given we fallback to IP geo-location to find the closest featured city.
+/// Returns cities changed since the last call for incremental sync, ordered by time of change.
+/// Each response has `nextCursor` set (unless it is empty and no `cursor` was passed), pass it as
+/// `cursor` to get cities changed afterwards. An empty page means the client is up to date; it
+/// should retry later using the same cursor. Cursors are opaque tokens (not URLs).
+///
+/// Only cities whose documents have the `updatedAt` field are returned. Deletions are not
+/// supported yet: deleted cities simply stop appearing in the feed.
 #[openapi]
-#[get("/city/v1/closest?<query..>")]
-pub(crate) fn closest(
-    request_header_coords: Option<Coordinates>,
-    query: Parse<'_, ClosestQuery>,
+#[get("/city/v1/changes?<query..>")]
+pub(crate) fn changes(
+    query: Parse<'_, ChangesQuery>,
+    strict: StrictParams,
     app: AppState<'_>,
-) -> JsonResult<CityResponse> {
+) -> NegotiatedResult<MultiCityResponse> {
     let query = query?;
+    strict.check::<ChangesQuery>()?;
+    let limit = app.config.page_limit(query.limit, None)?;
+    let search_after = query
+        .cursor
+        .as_deref()
+        .map(|it| SearchAfter::from_cursor(it, CursorKind::Changes))
+        .transpose()?;
     let locations_es_repo = LocationsElasticRepository(&app);
 
     app.block_on(async {
-        let es_city = if let Some(coords) = query.coordinates()? {
-            coords.validate()?; // validate explicitly, we don't want to validate when loading from ES.
-            locations_es_repo.get_city_by_coords(coords, None).await?
-        } else if let Some(coords) = request_header_coords {
-            locations_es_repo.get_city_by_coords(coords, Some(true)).await?
-        } else {
-            let city_id = match query.language {
-                Language::CS => 101_748_113,   // Prague
-                Language::DE => 101_909_779,   // Berlin
-                Language::EN => 101_748_113,   // also Prague
-                Language::PL => 101_752_777,   // Warsaw
-                Language::SK => 1_108_800_123, // Bratislava
-            };
-            locations_es_repo.get_city(city_id).await?
-        };
+        let (es_cities, last_search_after) =
+            locations_es_repo.get_changed_cities(limit, query.since, search_after).await?;
 
-        Ok(Json(es_city.into_resp(&app, query.language).await?))
+        let options = query.options(&app, None);
+        let mut response = es_cities_into_resp(
+            &locations_es_repo,
+            es_cities,
+            query.language,
+            options,
+            app.config.max_concurrent_region_fetches,
+            app.config.max_response_cities,
+        )
+        .await?;
+        response.nextCursor = last_search_after
+            .map(|it| it.to_cursor(CursorKind::Changes))
+            .or_else(|| query.cursor.clone());
+        Ok(response)
     })
 }
 
-/// Query for the `/city/v1/associatedFeatured` endpoint.
+/// Default number of cities returned by the `/city/v1/boundingBox` and `/city/v1/withinPolygon`
+/// endpoints.
+const BOUNDING_BOX_DEFAULT_LIMIT: usize = 1000;
+
+/// Query for the `/city/v1/boundingBox` endpoint.
+#[allow(non_snake_case)]
 #[derive(JsonSchema, FromForm)]
-pub(crate) struct AssociatedFeaturedQuery {
-    /// Id of the city to get associated featured city for, positive integer.
-    id: u64,
+pub(crate) struct BoundingBoxQuery {
+    /// Latitude of the north edge of the box in decimal degrees, e.g. `50.2`.
+    north: f64,
+    /// Longitude of the west edge of the box in decimal degrees, e.g. `13.1`.
+    west: f64,
+    /// Latitude of the south edge of the box in decimal degrees, e.g. `49.6`.
+    south: f64,
+    /// Longitude of the east edge of the box in decimal degrees, e.g. `14.7`.
+    east: f64,
     language: Language,
+    /// Whether to include `timezone` field in the response.
+    includeTimezone: bool,
+    /// Whether to include `countryName` field in the response.
+    includeCountryName: bool,
+    /// Whether to include `population` field in the response.
+    includePopulation: bool,
+    /// Whether to include `regions` field in the response.
+    includeRegionHierarchy: bool,
+    /// Whether to include `displayName` field in the response.
+    includeDisplayName: bool,
+    /// Whether to include `officialName` and `commonName` fields in the response.
+    includeNameVariants: bool,
+    /// Whether to include `regionId` field in the response.
+    includeIds: bool,
+    /// Whether to leave out `regionName` from the response and skip fetching the region, for
+    /// faster responses to clients that do not need it. The region is still fetched (as a part of
+    /// `regions`) if `includeRegionHierarchy` is requested too.
+    skipRegion: bool,
+    /// Maximum number of cities to return, at least 1. Defaults to 1000 and is at most 1000 unless
+    /// configured otherwise.
+    limit: Option<usize>,
+    /// Return the top `limit` cities by this priority: `featured` (featured, then bigger cities
+    /// first) or `population` (bigger cities first). An arbitrary subset is returned if not given.
+    orderBy: Option<BoundingBoxOrder>,
 }
 
-/// The `/city/v1/associatedFeatured` endpoint. HTTP request: [`AssociatedFeaturedQuery`],
-/// response: [`CityResponse`].
+/// The `/city/v1/boundingBox` endpoint. HTTP request: [`BoundingBoxQuery`], response:
+/// [`MultiCityResponse`].
 ///
-/// For a given city id returns the closest featured city.
+/// Returns cities whose centroid lies within the bounding box, at most `limit` of them. Boxes with
+/// `west` greater than `east` cross the antimeridian. Without `orderBy` the cities are returned in
+/// no particular order, and if there are more cities in the box than `limit`, an arbitrary subset
+/// is returned with a `Warning` response header.
 #[openapi]
-#[get("/city/v1/associatedFeatured?<query..>")]
-pub(crate) fn associated_featured(
-    query: Parse<'_, AssociatedFeaturedQuery>,
+#[get("/city/v1/boundingBox?<query..>")]
+pub(crate) fn bounding_box(
+    query: Parse<'_, BoundingBoxQuery>,
+    strict: StrictParams,
     app: AppState<'_>,
-) -> JsonResult<CityResponse> {
+) -> HandlerResult<WithWarning<Negotiated<MultiCityResponse>>> {
     let query = query?;
+    strict.check::<BoundingBoxQuery>()?;
+    let top_left = Coordinates { lat: query.north, lon: query.west };
+    let bottom_right = Coordinates { lat: query.south, lon: query.east };
+    top_left.validate()?;
+    bottom_right.validate()?;
+    if query.south > query.north {
+        return Err(BadRequest("`south` must not be greater than `north`.".to_string()));
+    }
+    let limit = app.config.page_limit(query.limit, Some(BOUNDING_BOX_DEFAULT_LIMIT))?;
     let locations_es_repo = LocationsElasticRepository(&app);
 
     app.block_on(async {
-        let mut es_city = locations_es_repo.get_city(query.id).await?;
-        if !es_city.isFeatured {
-            es_city = locations_es_repo.get_closest_city(es_city.centroid, Some(true)).await?;
-        }
+        // Get one city more than needed to tell whether there are more cities in the box.
+        let mut es_cities = locations_es_repo
+            .get_cities_in_bounding_box(top_left, bottom_right, query.orderBy, limit + 1)
+            .await?;
+        let capped = es_cities.len() > limit;
+        es_cities.truncate(limit);
+
+        let options = query.options(&app, None);
+        let body = es_cities_into_resp(
+            &locations_es_repo,
+            es_cities,
+            query.language,
+            options,
+            app.config.max_concurrent_region_fetches,
+            app.config.max_response_cities,
+        )
+        .await?;
 
-        Ok(Json(es_city.into_resp(&app, query.language).await?))
+        let warning = match (capped, query.orderBy) {
+            (true, None) => Some(format!(
+                "More cities in the box, returning arbitrary {}. Pass orderBy to get the top ones.",
+                limit
+            )),
+            _ => None,
+        };
+        Ok(WithWarning { body, warning })
     })
 }
 
-/// Implement Rocket request guard to parse coords from request headers. "Forwards" if not found.
-impl<'a, 'r> FromRequest<'a, 'r> for Coordinates {
-    type Error = ();
-    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
-        get_request_fastly_geo_coords(request.headers()).or_forward(())
-    }
-}
+/// Maximum number of vertices of a polygon of the `/city/v1/withinPolygon` endpoint.
+const POLYGON_MAX_VERTICES: usize = 1000;
 
-/// Get [Coordinates] out of Fastly Geo headers or [None] if they are not set or are invalid.
-fn get_request_fastly_geo_coords(headers: &HeaderMap<'_>) -> Option<Coordinates> {
-    let lat = headers.get_one("Fastly-Geo-Lat")?;
-    let lon = headers.get_one("Fastly-Geo-Lon")?;
-    let coords = Coordinates { lat: lat.parse().ok()?, lon: lon.parse().ok()? };
+/// Query for the `/city/v1/withinPolygon` endpoint.
+#[allow(non_snake_case)]
+#[derive(JsonSchema, FromForm)]
+pub(crate) struct WithinPolygonQuery {
+    language: Language,
+    /// Whether to include `timezone` field in the response.
+    includeTimezone: bool,
+    /// Whether to include `countryName` field in the response.
+    includeCountryName: bool,
+    /// Whether to include `population` field in the response.
+    includePopulation: bool,
+    /// Whether to include `regions` field in the response.
+    includeRegionHierarchy: bool,
+    /// Whether to include `displayName` field in the response.
+    includeDisplayName: bool,
+    /// Whether to include `officialName` and `commonName` fields in the response.
+    includeNameVariants: bool,
+    /// Whether to include `regionId` field in the response.
+    includeIds: bool,
+    /// Whether to leave out `regionName` from the response and skip fetching the region, for
+    /// faster responses to clients that do not need it. The region is still fetched (as a part of
+    /// `regions`) if `includeRegionHierarchy` is requested too.
+    skipRegion: bool,
+    /// Maximum number of cities to return, at least 1. Defaults to 1000 and is at most 1000 unless
+    /// configured otherwise.
+    limit: Option<usize>,
+    /// Return the top `limit` cities by this priority: `featured` (featured, then bigger cities
+    /// first) or `population` (bigger cities first). An arbitrary subset is returned if not given.
+    orderBy: Option<BoundingBoxOrder>,
+}
 
-    if coords.lat == 0.0 && coords.lon == 0.0 {
-        return None; // Fastly returns 0, 0 in case it cannot determine IP geolocation.
+/// The `/city/v1/withinPolygon` endpoint. HTTP request: [`WithinPolygonQuery`] and a JSON array
+/// of [`Coordinates`] of polygon vertices in the body, response: [`MultiCityResponse`].
+///
+/// Returns cities whose centroid lies within the polygon, at most `limit` of them, like
+/// `/city/v1/boundingBox` does for a box. The polygon needs at least 3 distinct vertices, and at
+/// most 1000 are accepted. It is closed automatically, repeating the first vertex at the end is
+/// allowed but not necessary.
+#[openapi]
+#[post("/city/v1/withinPolygon?<query..>", format = "json", data = "<vertices>")]
+pub(crate) fn within_polygon(
+    query: Parse<'_, WithinPolygonQuery>,
+    strict: StrictParams,
+    vertices: Json<Vec<Coordinates>>,
+    app: AppState<'_>,
+) -> HandlerResult<WithWarning<Negotiated<MultiCityResponse>>> {
+    let query = query?;
+    strict.check::<WithinPolygonQuery>()?;
+    let mut vertices = vertices.into_inner();
+    if vertices.len() > POLYGON_MAX_VERTICES {
+        return Err(PayloadTooLarge(format!(
+            "At most {} vertices are accepted.",
+            POLYGON_MAX_VERTICES
+        )));
     }
-    Some(coords)
-}
+    for (i, coords) in vertices.iter().enumerate() {
+        coords.validate().map_err(|e| BadRequest(format!("Vertex {}: {}", i, e)))?;
+    }
+    if vertices.len() > 1 && vertices.first() == vertices.last() {
+        vertices.pop(); // Elasticsearch closes the polygon itself.
+    }
+    if vertices.len() < 3 {
+        return Err(BadRequest("The polygon must have at least 3 vertices.".to_string()));
+    }
+    let limit = app.config.page_limit(query.limit, Some(BOUNDING_BOX_DEFAULT_LIMIT))?;
+    let locations_es_repo = LocationsElasticRepository(&app);
 
-impl ElasticCity {
-    /// Transform ElasticCity into CityResponse, fetching the region.
-    async fn into_resp<T: WithElastic>(
-        self,
-        app: &T,
-        language: Language,
-    ) -> HandlerResult<CityResponse> {
-        let locations_es_repo = LocationsElasticRepository(app);
-        let es_region = locations_es_repo.get_region(self.regionId).await?;
+    app.block_on(async {
+        // Get one city more than needed to tell whether there are more cities in the polygon.
+        let mut es_cities =
+            locations_es_repo.get_cities_in_polygon(&vertices, query.orderBy, limit + 1).await?;
+        let capped = es_cities.len() > limit;
+        es_cities.truncate(limit);
 
-        let name_key = language.name_key();
-        let name = self.names.get(&name_key).ok_or_else(|| BadRequest(name_key.clone()))?;
-        let region_name = es_region.names.get(&name_key).ok_or_else(|| BadRequest(name_key))?;
+        let options = query.options(&app, None);
+        let body = es_cities_into_resp(
+            &locations_es_repo,
+            es_cities,
+            query.language,
+            options,
+            app.config.max_concurrent_region_fetches,
+            app.config.max_response_cities,
+        )
+        .await?;
 
-        Ok(CityResponse {
-            id: self.id,
-            isFeatured: self.isFeatured,
-            countryIso: self.countryIso,
-            name: name.to_string(),
-            regionName: region_name.to_string(),
-        })
-    }
+        let warning = match (capped, query.orderBy) {
+            (true, None) => Some(format!(
+                "More cities in the polygon, returning arbitrary {}. Pass orderBy to get the top \
+                 ones.",
+                limit
+            )),
+            _ => None,
+        };
+        Ok(WithWarning { body, warning })
+    })
+}
+
+/// Query for the `/city/v1/search` endpoint.
+#[allow(non_snake_case)]
+#[derive(JsonSchema, FromForm)]
+pub(crate) struct SearchQuery {
+    /// The search query. Decoded as a form value: `+` stands for a space (e.g. `New+York`), a
+    /// literal plus sign has to be sent percent-encoded as `%2B`.
+    query: String,
+    /// ISO 3166-1 alpha-2 country code. Can be used to limit scope of the search to a given country.
+    countryIso: Option<String>,
+    language: Language,
+    /// Whether to include `timezone` field in the response.
+    includeTimezone: bool,
+    /// Whether to include `countryName` field in the response.
+    includeCountryName: bool,
+    /// Whether to include `population` field in the response.
+    includePopulation: bool,
+    /// Whether to include `regions` field in the response.
+    includeRegionHierarchy: bool,
+    /// Whether to include `displayName` field in the response.
+    includeDisplayName: bool,
+    /// Whether to include `officialName` and `commonName` fields in the response.
+    includeNameVariants: bool,
+    /// Whether to include `regionId` field in the response.
+    includeIds: bool,
+    /// Whether to leave out `regionName` from the response and skip fetching the region, for
+    /// faster responses to clients that do not need it. The region is still fetched (as a part of
+    /// `regions`) if `includeRegionHierarchy` is requested too.
+    skipRegion: bool,
+    /// Whether to include `matchedOn` field in the response.
+    includeMatchInfo: bool,
+    /// Whether to match names in all supported languages nearly as well as names in `language`,
+    /// useful when users may type a city name in other language than their UI language.
+    crossLanguage: bool,
+    /// Whether to sort the found cities by population (descending) rather than by relevance.
+    sortByPopulation: bool,
+    /// Whether to collapse duplicate documents of the same real city into one.
+    dedupe: bool,
+    /// Whether to respond with Not Found rather than an empty list if no city matches.
+    emptyAs404: bool,
+    /// Whether to respond with suggested corrections of the query in `suggestions` if no city
+    /// matches.
+    suggest: bool,
+    /// Whether to put cities whose name equals the query (ignoring case and diacritics) first.
+    exactMatchFirst: bool,
+    /// Whether to match names only with the same diacritics as the query, e.g. "Plzen" then
+    /// doesn't find "Plzeň". Diacritics are ignored by default.
+    exact: bool,
+    /// Maximum number of cities to return, 10 by default. At least 1 and at most 25 unless
+    /// configured otherwise.
+    limit: Option<usize>,
+    /// Minimum relevance score of returned cities, overrides the server default for `language`.
+    /// Non-negative, `0` turns the filter off.
+    minScore: Option<f64>,
+    /// Debugging output to include in the response: `timings` for the `timings` field (only in
+    /// JSON responses) and the `Server-Timing` header, which also has the duration of serializing
+    /// the response. Ignored unless the server allows debugging timings.
+    debug: Option<DebugMode>,
+}
+
+/// Default number of cities returned by the `/city/v1/search` endpoint.
+pub(crate) const SEARCH_DEFAULT_LIMIT: usize = 10;
+
+/// The `/city/v1/search` endpoint. HTTP request: [`SearchQuery`], response: [`MultiCityResponse`].
+///
+/// Returns list of cities matching the 'query' parameter.
+/// The response is limited to `limit` (10 by default) cities and no pagination is provided. With
+/// `sortByPopulation` these most relevant cities are sorted by population (cities without
+/// population data last).
+/// Queries shorter than a per-language minimum (2 characters by default) yield an empty list,
+/// queries longer than 200 characters are rejected with HTTP 413 Payload Too Large.
+/// With `emptyAs404` an empty result (including that of a too short query) is HTTP 404 Not Found.
+///
+/// Names in all languages are always matched, but with much lower weight than names in
+/// `language`. With `crossLanguage` the weight of other languages is only halved: this finds
+/// cities typed in a foreign language (e.g. "Praga" for Prague in German), at the cost that a
+/// foreign-name match of a bigger city may outrank a `language` match of a smaller one.
+///
+/// Names are matched ignoring diacritics by default, so that e.g. "Plzen" finds "Plzeň" (names
+/// matching with the same diacritics as the query rank higher). With `exact` diacritics of the
+/// query must match, so that "Plzen" finds only cities literally named so. This is unrelated to
+/// typos: neither mode tolerates misspelled letters.
+///
+/// Cities whose name in `language` starts with the query rank above cities whose name merely
+/// contains a word starting with it. For queries of several words, cities whose name contains the
+/// words next to each other and in order (e.g. "Nové Město" rather than "Město Nové ...") rank
+/// above cities whose name merely contains them all; single-word queries are unaffected.
+/// Featured cities get a small boost, so that they rank above other cities of similar relevance.
+///
+/// With `dedupe` cities that are duplicates of the same real city (sharing `canonicalId` in the
+/// index, or localized name and country if they don't have it) are collapsed into the most
+/// relevant one of them, which represents them in the response.
+///
+/// With `exactMatchFirst` cities whose name in `language` equals the query, ignoring case and
+/// diacritics, are moved before all other found cities (keeping their mutual order), regardless
+/// of their relevance and `sortByPopulation`.
+///
+/// With `suggest` an empty result has `suggestions`: corrections of the query made of the closest
+/// words of city names in `language` (so the same typo may get different corrections in different
+/// languages). They are words as indexed, i.e. lowercase, and may lack diacritics or be stemmed
+/// depending on the language analysis. Not available with `emptyAs404` and for too short queries.
+///
+/// Cities with relevance score below `minScore` are left out. Score distributions differ between
+/// languages, so the server has a default per `language` (none unless configured), which
+/// `minScore` overrides.
+#[openapi]
+#[get("/city/v1/search?<query..>")]
+pub(crate) fn search(
+    query: Parse<'_, SearchQuery>,
+    strict: StrictParams,
+    app: AppState<'_>,
+) -> HandlerResult<WithServerTiming<Negotiated<MultiCityResponse>>> {
+    let query = query?;
+    strict.check::<SearchQuery>()?;
+    let locations_es_repo = LocationsElasticRepository(&app);
+
+    app.block_on(search_response(&locations_es_repo, &app, &query))
+}
+
+/// Respond to `/city/v1/search` using `repo`. Separated from [search] so that it can be tested
+/// against a mock repository.
+async fn search_response(
+    repo: &impl LocationsRepository,
+    app: &App,
+    query: &SearchQuery,
+) -> HandlerResult<WithServerTiming<Negotiated<MultiCityResponse>>> {
+    let search_query = normalize_text(&query.query);
+    check_search_query_length(&search_query)?;
+    let limit = query.limit.unwrap_or(SEARCH_DEFAULT_LIMIT);
+    if limit == 0 || limit > app.config.search_max_limit {
+        let message = format!("`limit` must be between 1 and {}.", app.config.search_max_limit);
+        return Err(BadRequest(message));
+    }
+    if let Some(min_score) = query.minScore {
+        if min_score.is_nan() || min_score < 0.0 {
+            return Err(BadRequest("`minScore` must not be negative.".to_string()));
+        }
+    }
+    // Configured defaults are validated at startup.
+    let min_score = query.minScore.unwrap_or_else(|| app.config.search_min_score(query.language));
+
+    if search_query.trim().chars().count() < app.config.search_min_length(query.language) {
+        check_search_found(0, query.emptyAs404)?;
+        let response = MultiCityResponse {
+            cities: Vec::new(),
+            total: None,
+            nextCursor: None,
+            partial: None,
+            timings: None,
+            suggestions: None,
+        };
+        return Ok(WithServerTiming { body: Negotiated(response), phases: None });
+    }
+    let timings = query.debug == Some(DebugMode::Timings) && app.config.debug_timings;
+
+    if let (true, Some(iso_code)) = (app.config.strict_country_iso, query.countryIso.as_deref()) {
+        if !repo.get_country_isos().await?.iter().any(|it| it == iso_code) {
+            return Err(BadRequest(format!("Unknown `countryIso` `{}`.", iso_code)));
+        }
+    }
+
+    let started = Instant::now();
+    let search_options = SearchOptions {
+        match_info: query.includeMatchInfo,
+        cross_language: query.crossLanguage,
+        exact: query.exact,
+        prefix_boost: app.config.search_prefix_boost,
+        phrase_boost: app.config.search_phrase_boost,
+        featured_boost: app.config.search_featured_boost,
+        dedupe: query.dedupe,
+        min_score,
+        size: limit,
+    };
+    let SearchResults { items: mut es_cities, partial } = repo
+        .search(&search_query, query.language, query.countryIso.as_deref(), search_options)
+        .await?;
+    let elasticsearch_elapsed = started.elapsed();
+    check_search_found(es_cities.len(), query.emptyAs404)?;
+    let suggestions = if query.suggest && es_cities.is_empty() {
+        Some(repo.suggest_city_names(&search_query, query.language).await?)
+    } else {
+        None
+    };
+    if query.sortByPopulation {
+        es_cities.sort_by_key(|c| Reverse(c.population));
+    }
+    if query.exactMatchFirst {
+        let (name_key, folded_query) = (query.language.name_key(), fold_text(&search_query));
+        // Stable sort, false (exact match) first.
+        es_cities.sort_by_cached_key(|c| {
+            c.names.get(&name_key).map_or(true, |name| fold_text(name) != folded_query)
+        });
+    }
+
+    let options = query.options(app, None);
+    let build_started = Instant::now();
+    let mut resp = es_cities_into_resp(
+        repo,
+        es_cities,
+        query.language,
+        options,
+        app.config.max_concurrent_region_fetches,
+        app.config.max_response_cities,
+    )
+    .await?;
+    resp.partial = Some(true).filter(|_| partial);
+    resp.suggestions = suggestions;
+    if timings {
+        resp.timings = Some(TimingsResponse::new(elasticsearch_elapsed, build_started.elapsed()));
+    }
+    let phases = resp.timings.map(TimingsResponse::server_timing);
+    Ok(WithServerTiming { body: resp, phases })
+}
+
+/// Query for the `/city/v1/closest` endpoint.
+#[allow(non_snake_case)]
+#[derive(JsonSchema, FromForm)]
+pub(crate) struct ClosestQuery {
+    /// Latitude in decimal degrees with . as decimal separator.
+    lat: Option<f64>,
+    /// Longitude in decimal degrees with . as decimal separator.
+    lon: Option<f64>,
+    /// ISO 3166-1 alpha-2 country code. Can be used to find the closest city within a given
+    /// country, even if a city in a neighbouring country is closer.
+    countryIso: Option<String>,
+    language: Language,
+    /// Whether to include `timezone` field in the response.
+    includeTimezone: bool,
+    /// Whether to include `countryName` field in the response.
+    includeCountryName: bool,
+    /// Whether to include `population` field in the response.
+    includePopulation: bool,
+    /// Whether to include `regions` field in the response.
+    includeRegionHierarchy: bool,
+    /// Whether to include `displayName` field in the response.
+    includeDisplayName: bool,
+    /// Whether to include `officialName` and `commonName` fields in the response.
+    includeNameVariants: bool,
+    /// Whether to include `regionId` field in the response.
+    includeIds: bool,
+    /// Whether to leave out `regionName` from the response and skip fetching the region, for
+    /// faster responses to clients that do not need it. The region is still fetched (as a part of
+    /// `regions`) if `includeRegionHierarchy` is requested too.
+    skipRegion: bool,
+    /// Whether to include `resolvedCoordinates` debugging field in the response.
+    includeResolvedCoordinates: bool,
+    /// Whether to include `candidates` debugging field in the response. Ignored unless the server
+    /// allows debugging.
+    debug: bool,
+    /// Whether to consider only featured cities.
+    featuredOnly: bool,
+    /// Number of alternative cities to respond with in addition to the closest one, at most 10.
+    /// Changes the response to a list of cities with `isPrimary` set.
+    alternatives: Option<usize>,
+    /// Whether to respond with HTTP 204 No Content rather than 404 Not Found if no city qualifies.
+    emptyAs204: bool,
+}
+
+impl ClosestQuery {
+    /// Extract optional coordinates out of query, error if only one of them is given.
+    fn coordinates(&self) -> HandlerResult<Option<Coordinates>> {
+        query_coordinates(self.lat, self.lon)
+    }
+
+    /// Extract optional country ISO code out of query, error if it is malformed.
+    fn country_iso(&self) -> HandlerResult<Option<&str>> {
+        match self.countryIso.as_deref() {
+            Some(iso_code) if !is_valid_iso_code(iso_code) => {
+                Err(BadRequest(format!("Invalid `countryIso` `{}`.", iso_code)))
+            }
+            country_iso => Ok(country_iso),
+        }
+    }
+}
+
+/// Maximum length in characters of the search query of search endpoints.
+const SEARCH_QUERY_MAX_LENGTH: usize = 200;
+
+/// Error with HTTP 413 Payload Too Large if search `query` is longer than allowed.
+pub(crate) fn check_search_query_length(query: &str) -> HandlerResult<()> {
+    if query.chars().count() > SEARCH_QUERY_MAX_LENGTH {
+        return Err(PayloadTooLarge(format!(
+            "`query` must be at most {} characters long.",
+            SEARCH_QUERY_MAX_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+/// Normalize user-entered `text` to Unicode NFC (composed) form, in which the index stores names,
+/// so that e.g. "Plzeň" typed using a combining caron matches.
+pub(crate) fn normalize_text(text: &str) -> String {
+    text.nfc().collect()
+}
+
+/// Fold `text` for comparison ignoring case, diacritics and surrounding whitespace, e.g. "Plzeň"
+/// to "plzen". Letters that don't decompose to a base letter and a diacritic (e.g. "ł") are kept.
+fn fold_text(text: &str) -> String {
+    text.trim().nfd().filter(|&c| !is_combining_mark(c)).flat_map(char::to_lowercase).collect()
+}
+
+/// Error with HTTP 404 Not Found if search found no (`count` is zero) entities and `empty_as_404`
+/// is requested by the client.
+pub(crate) fn check_search_found(count: usize, empty_as_404: bool) -> HandlerResult<()> {
+    if count == 0 && empty_as_404 {
+        return Err(NotFound("No results match the search query.".to_string()));
+    }
+    Ok(())
+}
+
+/// Error with HTTP 413 Payload Too Large if a list response would have more than `max_cities`
+/// (`count`) cities. A safety net against pathological queries independent of per-endpoint limits.
+pub(crate) fn check_response_size(count: usize, max_cities: usize) -> HandlerResult<()> {
+    if count > max_cities {
+        return Err(PayloadTooLarge(format!(
+            "Response would have {} cities, at most {} are allowed.",
+            count, max_cities
+        )));
+    }
+    Ok(())
+}
+
+/// Construct optional coordinates from query parameters, error if only one of them is given or if
+/// they are out of range.
+pub(crate) fn query_coordinates(
+    lat: Option<f64>,
+    lon: Option<f64>,
+) -> HandlerResult<Option<Coordinates>> {
+    match (lat, lon) {
+        (Some(lat), Some(lon)) => {
+            let coords = Coordinates { lat, lon };
+            coords.validate()?; // validate explicitly, we don't want to validate when loading from ES.
+            Ok(Some(coords))
+        }
+        (None, None) => Ok(None),
+        _ => Err(BadRequest("either both or none of `lat`, `lon` expected".to_string())),
+    }
+}
+
+/// Response of the `/city/v1/closest` endpoint: a single city by default, a list of cities if
+/// `alternatives` are requested.
+#[derive(JsonSchema, Serialize)]
+#[serde(untagged)]
+pub(crate) enum ClosestResponse {
+    City(CityResponse),
+    Cities(MultiCityResponse),
+}
+
+impl EncodeProtobuf for ClosestResponse {
+    fn encode_protobuf(self) -> Vec<u8> {
+        match self {
+            ClosestResponse::City(city) => city.encode_protobuf(),
+            ClosestResponse::Cities(cities) => cities.encode_protobuf(),
+        }
+    }
+}
+
+/// Maximum number of alternative cities of `/city/v1/closest`.
+const CLOSEST_MAX_ALTERNATIVES: usize = 10;
+
+/// The `/city/v1/closest` endpoint. HTTP request: [`ClosestQuery`], response: [`CityResponse`],
+/// or [`MultiCityResponse`] if `alternatives` are requested.
+///
+/// Returns a single city that is closest to the coordinates, Not Found if there are no cities.
+/// If coordinates are not given we fallback to IP geo-location. As it is only approximate, we
+/// find a nearby city preferring featured and bigger ones rather than the strictly closest one.
+/// With `countryIso` only cities of that country are considered.
+///
+/// The city is thus determined by the first available of, in order of precedence:
+/// 1. coordinates given by `lat` and `lon`,
+/// 2. IP geo-location,
+/// 3. `countryIso`: its biggest featured city (e.g. the capital), or the biggest one if it has no
+///    featured cities,
+/// 4. a default city for `language`, regardless of `countryIso` if the country has no cities.
+///
+/// With `featuredOnly` only featured cities are considered, which gives the same city as
+/// `/city/v1/associatedFeatured` of the closest city in most cases, but in a single call. The
+/// default cities for `language` are featured, so this holds for the fallback too.
+///
+/// With `alternatives` the response is a list with the closest city first (with `isPrimary` set to
+/// `true`) followed by up to `alternatives` next best cities (with `isPrimary` set to `false`),
+/// found the same way. All of them have `distanceKm` from the resolved coordinates. The list has
+/// just the fallback city (by `countryIso` or `language`) when no coordinates are available.
+///
+/// Coordinates may have no qualifying city when filtered by `countryIso` or `featuredOnly`. That
+/// is HTTP 404 Not Found by default, which is indistinguishable from a wrong URL; with
+/// `emptyAs204` it is HTTP 204 No Content with an empty body instead, i.e. a valid request with
+/// an empty result. The fallback without coordinates always yields a city.
+#[openapi]
+#[get("/city/v1/closest?<query..>")]
+pub(crate) fn closest(
+    request_header_coords: Option<Coordinates>,
+    query: Parse<'_, ClosestQuery>,
+    strict: StrictParams,
+    app: AppState<'_>,
+) -> HandlerResult<OrNoContent<Negotiated<ClosestResponse>>> {
+    let query = query?;
+    strict.check::<ClosestQuery>()?;
+    if query.alternatives.map_or(false, |it| it > CLOSEST_MAX_ALTERNATIVES) {
+        return Err(BadRequest(format!(
+            "At most {} `alternatives` are allowed.",
+            CLOSEST_MAX_ALTERNATIVES
+        )));
+    }
+    let locations_es_repo = LocationsElasticRepository(&app);
+
+    app.block_on(async {
+        let query_coords = query.coordinates()?;
+        let country_iso = query.country_iso()?;
+        let resolved_coords = query_coords.or(request_header_coords);
+        let is_featured = if query.featuredOnly { Some(true) } else { None };
+
+        let es_city = if let Some(coords) = query_coords {
+            locations_es_repo.get_city_by_coords(coords, is_featured, country_iso).await
+        } else if let Some(coords) = request_header_coords {
+            let scoring = app.config.approximate_scoring;
+            locations_es_repo
+                .get_city_by_approximate_coords(coords, scoring, is_featured, country_iso)
+                .await
+        } else {
+            let country_city = match country_iso {
+                Some(iso_code) => {
+                    locations_es_repo.get_country_main_city(iso_code, is_featured).await?
+                }
+                None => None,
+            };
+            match country_city {
+                Some(es_city) => Ok(es_city),
+                None => locations_es_repo.get_city(fallback_city_id(query.language)).await,
+            }
+        };
+        let es_city = match es_city {
+            Err(NotFound(_)) if query.emptyAs204 => return Ok(OrNoContent(None)),
+            result => result?,
+        };
+
+        let distance_from = if query.alternatives.is_some() { resolved_coords } else { None };
+        let options = query.options(&app, distance_from);
+        let mut resp = es_city.into_resp(&locations_es_repo, query.language, options).await?;
+        if query.includeResolvedCoordinates {
+            resp.resolvedCoordinates = resolved_coords;
+        }
+        // Score like the lookup above: by distance for precise coordinates from the request.
+        let scoring =
+            if query_coords.is_some() { None } else { Some(app.config.approximate_scoring) };
+        let debug_coords = resolved_coords.filter(|_| query.debug && app.config.closest_debug);
+        if let Some(coords) = debug_coords {
+            let es_candidates = locations_es_repo
+                .get_city_candidates(
+                    coords,
+                    scoring,
+                    is_featured,
+                    country_iso,
+                    CLOSEST_DEBUG_CANDIDATES,
+                )
+                .await?;
+            let name_key = query.language.name_key();
+            let candidates = es_candidates.into_iter().map(|(es_city, score)| CandidateResponse {
+                id: es_city.id,
+                name: es_city.names.get(&name_key).cloned().unwrap_or_default(),
+                distanceKm: es_city.centroid.distance_km(coords),
+                score,
+            });
+            resp.candidates = Some(candidates.collect());
+        }
+
+        let alternatives = match query.alternatives {
+            Some(alternatives) => alternatives,
+            None => return Ok(OrNoContent(Some(Negotiated(ClosestResponse::City(resp))))),
+        };
+        let primary_id = resp.id;
+        resp.isPrimary = Some(true);
+        let mut cities = vec![resp];
+        if let Some(coords) = resolved_coords {
+            // One more candidate than needed, as the primary city is usually among them.
+            let es_alternatives = locations_es_repo
+                .get_city_candidates(coords, scoring, is_featured, country_iso, alternatives + 1)
+                .await?
+                .into_iter()
+                .map(|(es_city, _)| es_city)
+                .filter(|es_city| es_city.id != primary_id)
+                .take(alternatives)
+                .collect();
+            let Negotiated(alternatives) = es_cities_into_resp(
+                &locations_es_repo,
+                es_alternatives,
+                query.language,
+                options,
+                app.config.max_concurrent_region_fetches,
+                app.config.max_response_cities,
+            )
+            .await?;
+            cities.extend(
+                alternatives
+                    .cities
+                    .into_iter()
+                    .map(|it| CityResponse { isPrimary: Some(false), ..it }),
+            );
+        }
+        let response = MultiCityResponse {
+            cities,
+            total: None,
+            nextCursor: None,
+            partial: None,
+            timings: None,
+            suggestions: None,
+        };
+        Ok(OrNoContent(Some(Negotiated(ClosestResponse::Cities(response)))))
+    })
+}
+
+/// Number of candidate cities in `/city/v1/closest` debugging output.
+const CLOSEST_DEBUG_CANDIDATES: usize = 5;
+
+/// Id of the city returned by `/city/v1/closest` for given `language` when client location is
+/// not known.
+pub(crate) fn fallback_city_id(language: Language) -> CityId {
+    match language {
+        Language::CS => CityId(101_748_113),   // Prague
+        Language::DE => CityId(101_909_779),   // Berlin
+        Language::EN => CityId(101_748_113),   // also Prague
+        Language::PL => CityId(101_752_777),   // Warsaw
+        Language::SK => CityId(1_108_800_123), // Bratislava
+    }
+}
+
+/// Maximum number of points in a single `/city/v1/closestBatch` request.
+const CLOSEST_BATCH_MAX_POINTS: usize = 100;
+
+/// Query for the `/city/v1/closestBatch` endpoint.
+#[allow(non_snake_case)]
+#[derive(JsonSchema, FromForm)]
+pub(crate) struct ClosestBatchQuery {
+    language: Language,
+    /// Whether to include `timezone` field in the response.
+    includeTimezone: bool,
+    /// Whether to include `countryName` field in the response.
+    includeCountryName: bool,
+    /// Whether to include `population` field in the response.
+    includePopulation: bool,
+    /// Whether to include `regions` field in the response.
+    includeRegionHierarchy: bool,
+    /// Whether to include `displayName` field in the response.
+    includeDisplayName: bool,
+    /// Whether to include `officialName` and `commonName` fields in the response.
+    includeNameVariants: bool,
+    /// Whether to include `regionId` field in the response.
+    includeIds: bool,
+    /// Whether to leave out `regionName` from the response and skip fetching the region, for
+    /// faster responses to clients that do not need it. The region is still fetched (as a part of
+    /// `regions`) if `includeRegionHierarchy` is requested too.
+    skipRegion: bool,
+}
+
+/// The `/city/v1/closestBatch` endpoint. HTTP request: [`ClosestBatchQuery`] and a JSON array of
+/// [`Coordinates`] in the body, response: [`MultiCityResponse`].
+///
+/// Returns the closest city for each of given points, in the same order. Each city has
+/// `distanceKm` from its point. At most 100 points are accepted in a single request.
+#[openapi]
+#[post("/city/v1/closestBatch?<query..>", format = "json", data = "<points>")]
+pub(crate) fn closest_batch(
+    query: Parse<'_, ClosestBatchQuery>,
+    strict: StrictParams,
+    points: Json<Vec<Coordinates>>,
+    app: AppState<'_>,
+) -> NegotiatedResult<MultiCityResponse> {
+    let query = query?;
+    strict.check::<ClosestBatchQuery>()?;
+    let points = points.into_inner();
+    if points.len() > CLOSEST_BATCH_MAX_POINTS {
+        return Err(PayloadTooLarge(format!(
+            "At most {} points are accepted.",
+            CLOSEST_BATCH_MAX_POINTS
+        )));
+    }
+    check_response_size(points.len(), app.config.max_response_cities)?;
+    for (i, coords) in points.iter().enumerate() {
+        coords.validate().map_err(|e| BadRequest(format!("Point {}: {}", i, e)))?;
+    }
+    if points.is_empty() {
+        return Ok(Negotiated(MultiCityResponse {
+            cities: Vec::new(),
+            total: None,
+            nextCursor: None,
+            partial: None,
+            timings: None,
+            suggestions: None,
+        }));
+    }
+    let locations_es_repo = LocationsElasticRepository(&app);
+
+    app.block_on(async {
+        let es_cities = locations_es_repo.get_cities_by_coords(points.clone()).await?;
+
+        let options = query.options(&app, None);
+        let city_futures = es_cities.into_iter().zip(points).map(|(es_city, coords)| {
+            let options = CityResponseOptions { distance_from: Some(coords), ..options };
+            es_city.into_resp(&locations_es_repo, query.language, options)
+        });
+        let cities = stream::iter(city_futures)
+            .buffered(app.config.max_concurrent_region_fetches)
+            .try_collect()
+            .await?;
+
+        Ok(Negotiated(MultiCityResponse {
+            cities,
+            total: None,
+            nextCursor: None,
+            partial: None,
+            timings: None,
+            suggestions: None,
+        }))
+    })
+}
+
+/// Query for the `/city/v1/associatedFeatured` endpoint.
+#[allow(non_snake_case)]
+#[derive(JsonSchema, FromForm)]
+pub(crate) struct AssociatedFeaturedQuery {
+    /// Id of the city to get associated featured city for, positive integer.
+    id: CityId,
+    language: Language,
+    /// Whether to include `timezone` field in the response.
+    includeTimezone: bool,
+    /// Whether to include `countryName` field in the response.
+    includeCountryName: bool,
+    /// Whether to include `population` field in the response.
+    includePopulation: bool,
+    /// Whether to include `regions` field in the response.
+    includeRegionHierarchy: bool,
+    /// Whether to include `displayName` field in the response.
+    includeDisplayName: bool,
+    /// Whether to include `officialName` and `commonName` fields in the response.
+    includeNameVariants: bool,
+    /// Whether to include `regionId` field in the response.
+    includeIds: bool,
+    /// Whether to leave out `regionName` from the response and skip fetching the region, for
+    /// faster responses to clients that do not need it. The region is still fetched (as a part of
+    /// `regions`) if `includeRegionHierarchy` is requested too.
+    skipRegion: bool,
+}
+
+/// The `/city/v1/associatedFeatured` endpoint. HTTP request: [`AssociatedFeaturedQuery`],
+/// response: [`CityResponse`].
+///
+/// For a given city id returns the closest featured city, with `distanceKm` from the given city.
+/// Returns the given city itself (without `distanceKm`) if it is featured.
+#[openapi]
+#[get("/city/v1/associatedFeatured?<query..>")]
+pub(crate) fn associated_featured(
+    query: Parse<'_, AssociatedFeaturedQuery>,
+    strict: StrictParams,
+    app: AppState<'_>,
+) -> NegotiatedResult<CityResponse> {
+    let query = query?;
+    strict.check::<AssociatedFeaturedQuery>()?;
+    let locations_es_repo = LocationsElasticRepository(&app);
+
+    app.block_on(async {
+        let mut es_city = locations_es_repo.get_city(query.id).await?;
+        let mut distance_from = None;
+        if !es_city.isFeatured {
+            distance_from = Some(es_city.centroid);
+            es_city =
+                locations_es_repo.get_closest_city(es_city.centroid, Some(true), None).await?;
+        }
+
+        let options = query.options(&app, distance_from);
+        Ok(Negotiated(es_city.into_resp(&locations_es_repo, query.language, options).await?))
+    })
+}
+
+/// Default number of cities returned by the `/city/v1/neighbors` endpoint.
+const NEIGHBORS_DEFAULT_LIMIT: usize = 10;
+
+/// Query for the `/city/v1/neighbors` endpoint.
+#[allow(non_snake_case)]
+#[derive(JsonSchema, FromForm)]
+pub(crate) struct NeighborsQuery {
+    /// Id of the city to get neighbors of, positive integer.
+    id: CityId,
+    language: Language,
+    /// Maximum number of cities to return, at least 1. Defaults to 10 and is at most 1000 unless
+    /// configured otherwise.
+    limit: Option<usize>,
+    /// Whether to include `timezone` field in the response.
+    includeTimezone: bool,
+    /// Whether to include `countryName` field in the response.
+    includeCountryName: bool,
+    /// Whether to include `population` field in the response.
+    includePopulation: bool,
+    /// Whether to include `regions` field in the response.
+    includeRegionHierarchy: bool,
+    /// Whether to include `displayName` field in the response.
+    includeDisplayName: bool,
+    /// Whether to include `officialName` and `commonName` fields in the response.
+    includeNameVariants: bool,
+    /// Whether to include `regionId` field in the response.
+    includeIds: bool,
+    /// Whether to leave out `regionName` from the response and skip fetching the region, for
+    /// faster responses to clients that do not need it. The region is still fetched (as a part of
+    /// `regions`) if `includeRegionHierarchy` is requested too.
+    skipRegion: bool,
+}
+
+/// The `/city/v1/neighbors` endpoint. HTTP request: [`NeighborsQuery`],
+/// response: [`MultiCityResponse`].
+///
+/// For a given city id returns `limit` other cities closest to it, closest first, each with
+/// `distanceKm` from the given city. Not Found if the city does not exist.
+#[openapi]
+#[get("/city/v1/neighbors?<query..>")]
+pub(crate) fn neighbors(
+    query: Parse<'_, NeighborsQuery>,
+    strict: StrictParams,
+    app: AppState<'_>,
+) -> NegotiatedResult<MultiCityResponse> {
+    let query = query?;
+    strict.check::<NeighborsQuery>()?;
+    let limit = app.config.page_limit(query.limit, Some(NEIGHBORS_DEFAULT_LIMIT))?;
+    let locations_es_repo = LocationsElasticRepository(&app);
+
+    app.block_on(async {
+        let es_city = locations_es_repo.get_city(query.id).await?;
+        let es_cities =
+            locations_es_repo.get_neighbor_cities(es_city.id, es_city.centroid, limit).await?;
+
+        let options = query.options(&app, Some(es_city.centroid));
+        es_cities_into_resp(
+            &locations_es_repo,
+            es_cities,
+            query.language,
+            options,
+            app.config.max_concurrent_region_fetches,
+            app.config.max_response_cities,
+        )
+        .await
+    })
+}
+
+/// Maximum number of countries of the `/city/v1/closestPerCountry` endpoint.
+const CLOSEST_PER_COUNTRY_MAX_COUNTRIES: usize = 50;
+
+/// Query for the `/city/v1/closestPerCountry` endpoint.
+#[allow(non_snake_case)]
+#[derive(JsonSchema, FromForm)]
+pub(crate) struct ClosestPerCountryQuery {
+    /// Latitude in decimal degrees with dot as a separator, e.g. `50.1`.
+    lat: f64,
+    /// Longitude in decimal degrees with dot as a separator, e.g. `14.4`.
+    lon: f64,
+    language: Language,
+    /// Comma-separated ISO 3166-1 alpha-2 country codes, e.g. `CZ,SK,PL`. At most 50.
+    countryIsos: String,
+    /// Whether to include `timezone` field in the response.
+    includeTimezone: bool,
+    /// Whether to include `countryName` field in the response.
+    includeCountryName: bool,
+    /// Whether to include `population` field in the response.
+    includePopulation: bool,
+    /// Whether to include `regions` field in the response.
+    includeRegionHierarchy: bool,
+    /// Whether to include `displayName` field in the response.
+    includeDisplayName: bool,
+    /// Whether to include `officialName` and `commonName` fields in the response.
+    includeNameVariants: bool,
+    /// Whether to include `regionId` field in the response.
+    includeIds: bool,
+    /// Whether to leave out `regionName` from the response and skip fetching the region, for
+    /// faster responses to clients that do not need it. The region is still fetched (as a part of
+    /// `regions`) if `includeRegionHierarchy` is requested too.
+    skipRegion: bool,
+}
+
+/// The `/city/v1/closestPerCountry` endpoint. HTTP request: [`ClosestPerCountryQuery`],
+/// response: [`MultiCityResponse`].
+///
+/// Returns the city closest to the point within each of `countryIsos`, in their order (duplicates
+/// ignored), each with `distanceKm` from the point. Countries without any city are left out.
+#[openapi]
+#[get("/city/v1/closestPerCountry?<query..>")]
+pub(crate) fn closest_per_country(
+    query: Parse<'_, ClosestPerCountryQuery>,
+    strict: StrictParams,
+    app: AppState<'_>,
+) -> NegotiatedResult<MultiCityResponse> {
+    let query = query?;
+    strict.check::<ClosestPerCountryQuery>()?;
+    let coords = Coordinates { lat: query.lat, lon: query.lon };
+    coords.validate()?;
+    let country_isos = parse_country_isos(&query.countryIsos)?;
+    if country_isos.len() > CLOSEST_PER_COUNTRY_MAX_COUNTRIES {
+        return Err(PayloadTooLarge(format!(
+            "At most {} countries are accepted.",
+            CLOSEST_PER_COUNTRY_MAX_COUNTRIES
+        )));
+    }
+    let locations_es_repo = LocationsElasticRepository(&app);
+
+    app.block_on(async {
+        let es_cities = locations_es_repo
+            .get_closest_city_per_country(coords, &country_isos)
+            .await?
+            .into_iter()
+            .flatten()
+            .collect();
+
+        let options = query.options(&app, Some(coords));
+        es_cities_into_resp(
+            &locations_es_repo,
+            es_cities,
+            query.language,
+            options,
+            app.config.max_concurrent_region_fetches,
+            app.config.max_response_cities,
+        )
+        .await
+    })
+}
+
+/// Parse comma-separated list of country ISO codes like [parse_city_ids()] does for city ids.
+fn parse_country_isos(country_isos: &str) -> HandlerResult<Vec<String>> {
+    let mut iso_codes: Vec<String> = Vec::new();
+    for token in country_isos.split(',').map(str::trim) {
+        if !is_valid_iso_code(token) {
+            return Err(BadRequest(format!("Invalid country code `{}` in `countryIsos`.", token)));
+        }
+        if !iso_codes.iter().any(|it| it == token) {
+            iso_codes.push(token.to_string());
+        }
+    }
+    Ok(iso_codes)
+}
+
+/// Query for the `/city/v1/region` endpoint.
+#[derive(JsonSchema, FromForm)]
+pub(crate) struct CityRegionQuery {
+    /// Id of the city to get region of, positive integer.
+    id: CityId,
+    language: Language,
+}
+
+/// The `/city/v1/region` endpoint. HTTP request: [`CityRegionQuery`], response: [`RegionResponse`].
+///
+/// Get region of the city of given ID localized to given language, without the city itself.
+#[openapi]
+#[get("/city/v1/region?<query..>")]
+pub(crate) fn region(
+    query: Parse<'_, CityRegionQuery>,
+    strict: StrictParams,
+    app: AppState<'_>,
+) -> JsonResult<RegionResponse> {
+    let query = query?;
+    strict.check::<CityRegionQuery>()?;
+    let locations_es_repo = LocationsElasticRepository(&app);
+
+    app.block_on(async {
+        let es_city = locations_es_repo.get_city(query.id).await?;
+        let es_region = locations_es_repo.get_region(es_city.regionId).await?;
+
+        Ok(Json(es_region.into_resp(query.language)?))
+    })
+}
+
+/// Implement Rocket request guard to parse coords from request headers. "Forwards" if not found.
+impl<'a, 'r> FromRequest<'a, 'r> for Coordinates {
+    type Error = ();
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        get_request_fastly_geo_coords(request.headers()).or_forward(())
+    }
+}
+
+/// Get [Coordinates] out of Fastly Geo headers or [None] if they are not set or are invalid.
+fn get_request_fastly_geo_coords(headers: &HeaderMap<'_>) -> Option<Coordinates> {
+    let lat = headers.get_one("Fastly-Geo-Lat")?;
+    let lon = headers.get_one("Fastly-Geo-Lon")?;
+    let coords = Coordinates { lat: lat.parse().ok()?, lon: lon.parse().ok()? };
+    // Range validation lets NaN through, as it fails all comparisons.
+    if coords.lat.is_nan() || coords.lon.is_nan() {
+        return None;
+    }
+    coords.validate().ok()?;
+
+    if is_fastly_unknown_location(coords) {
+        return None;
+    }
+    Some(coords)
+}
+
+/// Whether `coords` from Fastly Geo headers are the sentinel Fastly sends in case it cannot
+/// determine IP geolocation: exactly `0, 0`, however formatted (e.g. `0.000` or `-0`). Locations
+/// merely near 0, 0 are real ones, only the exact point (in the ocean) is ambiguous.
+fn is_fastly_unknown_location(coords: Coordinates) -> bool {
+    coords.lat == 0.0 && coords.lon == 0.0 // Also true for -0.0.
+}
+
+impl ElasticCity {
+    /// Transform ElasticCity into CityResponse, fetching the region unless skipped.
+    pub(crate) async fn into_resp(
+        self,
+        locations_repo: &impl LocationsRepository,
+        language: Language,
+        options: CityResponseOptions,
+    ) -> HandlerResult<CityResponse> {
+        let name_key = language.name_key();
+        // Name is the common name, index documents may have just one of the common and official.
+        let official_name = self.names.get(&language.official_name_key());
+        let name = self
+            .names
+            .get(&name_key)
+            .or(official_name)
+            .ok_or_else(|| BadRequest(name_key.clone()))?;
+
+        let region_name = if options.skip_region {
+            None
+        } else {
+            match locations_repo.get_region(self.regionId).await {
+                Ok(es_region) => Some(
+                    es_region.names.get(&name_key).ok_or_else(|| BadRequest(name_key))?.to_string(),
+                ),
+                Err(e) if options.degrade_on_region_error => {
+                    warn!("Cannot fetch region {} of city {}: {:?}.", self.regionId, self.id, e);
+                    Some(String::new())
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        let display_name = if options.include_display_name {
+            Some(display_name(name, region_name.as_deref().unwrap_or_default(), language))
+        } else {
+            None
+        };
+
+        let regions = if options.include_region_hierarchy {
+            match locations_repo.get_region_hierarchy(self.regionId).await {
+                Ok(es_regions) => Some(
+                    es_regions
+                        .into_iter()
+                        .map(|it| it.into_resp(language))
+                        .collect::<HandlerResult<_>>()?,
+                ),
+                Err(e) if options.degrade_on_region_error => {
+                    warn!("Cannot fetch regions of city {}: {:?}.", self.id, e);
+                    Some(Vec::new())
+                }
+                Err(e) => return Err(e),
+            }
+        } else {
+            None
+        };
+
+        let localized_country_name = if options.include_country_name {
+            Some(country_name(&self.countryIso, language).to_string())
+        } else {
+            None
+        };
+
+        Ok(CityResponse {
+            id: self.id,
+            isFeatured: self.isFeatured,
+            countryIso: self.countryIso,
+            name: name.to_string(),
+            regionName: region_name,
+            regionId: if options.include_ids { Some(self.regionId) } else { None },
+            displayName: display_name,
+            officialName: if options.include_name_variants {
+                Some(official_name.unwrap_or(name).to_string())
+            } else {
+                None
+            },
+            commonName: if options.include_name_variants { Some(name.to_string()) } else { None },
+            regions,
+            timezone: if options.include_timezone { self.timezone } else { None },
+            countryName: localized_country_name,
+            population: if options.include_population { self.population } else { None },
+            distanceKm: options.distance_from.map(|coords| self.centroid.distance_km(coords)),
+            matchedOn: self.matched_on,
+            resolvedCoordinates: None,
+            candidates: None,
+            isPrimary: None,
+        })
+    }
+}
+
+/// Name of city `name` with `region_name` appended as is customary in `language`, just `name` if
+/// `region_name` is empty (region could not be fetched) or adds no information.
+fn display_name(name: &str, region_name: &str, language: Language) -> String {
+    if region_name.is_empty() || region_name == name {
+        return name.to_string();
+    }
+    match language {
+        Language::DE => format!("{} ({})", name, region_name),
+        Language::CS | Language::EN | Language::PL | Language::SK => {
+            format!("{}, {}", name, region_name)
+        }
+    }
 }
 
 /// Convert a vector of [ElasticCity] into [MultiCityResponse], maintaining order and fetching
-/// required regions asynchronously all in parallel (which is somewhat redundant with
-/// [ElasticRegion] cache).
-async fn es_cities_into_resp<T: WithElastic>(
-    app: &T,
+/// required regions asynchronously in parallel, at most `concurrency` at a time (which is
+/// somewhat redundant with [ElasticRegion] cache).
+pub(crate) async fn es_cities_into_resp(
+    locations_repo: &impl LocationsRepository,
     es_cities: Vec<ElasticCity>,
     language: Language,
-) -> JsonResult<MultiCityResponse> {
-    let city_futures: FuturesOrdered<_> =
-        es_cities.into_iter().map(|it| it.into_resp(app, language)).collect();
+    options: CityResponseOptions,
+    concurrency: usize,
+    max_cities: usize,
+) -> NegotiatedResult<MultiCityResponse> {
+    check_response_size(es_cities.len(), max_cities)?;
+    let city_futures =
+        es_cities.into_iter().map(|it| it.into_resp(locations_repo, language, options));
 
-    city_futures.try_collect().await.map(|cities| Json(MultiCityResponse { cities }))
+    stream::iter(city_futures).buffered(concurrency).try_collect().await.map(|cities| {
+        Negotiated(MultiCityResponse {
+            cities,
+            total: None,
+            nextCursor: None,
+            partial: None,
+            timings: None,
+            suggestions: None,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        response::ErrorResponse::InternalServerError,
+        services::mock_locations_repo::{block_on, city, region, MockLocationsRepository},
+    };
+
+    /// Parse query struct of a handler from URL query string `query`, leniently as Rocket does.
+    fn parse<'f, T: FromForm<'f>>(query: &'f str) -> T {
+        T::from_form(&mut FormItems::from(query), false)
+            .unwrap_or_else(|_| panic!("Query `{}` cannot be parsed.", query))
+    }
+
+    fn featured_city(id: u64, country_iso: &str, population: Option<u64>) -> ElasticCity {
+        let country_iso = country_iso.to_string();
+        ElasticCity { isFeatured: true, countryIso: country_iso, population, ..city(id, "Město") }
+    }
+
+    fn repo_with(cities: Vec<ElasticCity>) -> MockLocationsRepository {
+        MockLocationsRepository { cities, regions: vec![region(1, "Kraj")], ..Default::default() }
+    }
+
+    fn city_ids(response: &MultiCityResponse) -> Vec<u64> {
+        response.cities.iter().map(|c| c.id.0).collect()
+    }
+
+    #[test]
+    fn featured_lists_preferred_countries_first() {
+        let repo = repo_with(vec![
+            featured_city(5, "SK", None),
+            featured_city(2, "DE", None),
+            featured_city(4, "CZ", None),
+            city(1, "Nefeaturované"),
+            featured_city(3, "CZ", None),
+        ]);
+
+        let query = parse("language=en");
+        let response = block_on(featured_response(&repo, &App::for_tests(), &query)).unwrap();
+        assert_eq!(city_ids(&response.body), vec![3, 4, 5, 2]);
+
+        let query = parse("language=cs");
+        let response = block_on(featured_response(&repo, &App::for_tests(), &query)).unwrap();
+        assert_eq!(city_ids(&response.body), vec![3, 4, 2, 5]);
+    }
+
+    #[test]
+    fn featured_sorts_by_population() {
+        let repo = repo_with(vec![
+            featured_city(1, "CZ", None),
+            featured_city(2, "SK", Some(1000)),
+            featured_city(3, "CZ", Some(5000)),
+            featured_city(4, "DE", Some(1000)),
+        ]);
+
+        let query = parse("language=cs&sortByPopulation=true");
+        let response = block_on(featured_response(&repo, &App::for_tests(), &query)).unwrap();
+        assert_eq!(city_ids(&response.body), vec![3, 2, 4, 1]);
+    }
+
+    #[test]
+    fn featured_order_does_not_depend_on_repository_order() {
+        use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+        let mut cities = vec![
+            featured_city(1, "CZ", Some(1000)),
+            featured_city(2, "SK", Some(1000)),
+            featured_city(3, "CZ", Some(1000)),
+            featured_city(4, "DE", None),
+            featured_city(5, "CZ", None),
+            featured_city(6, "SK", Some(1000)),
+        ];
+        let mut rng = StdRng::seed_from_u64(42);
+        for &(query_str, expected) in &[
+            ("language=cs", [1, 3, 5, 2, 4, 6]),
+            ("language=cs&sortByPopulation=true", [1, 2, 3, 6, 4, 5]),
+        ] {
+            for _ in 0..10 {
+                cities.shuffle(&mut rng);
+                let repo = repo_with(cities.clone());
+                let response =
+                    block_on(featured_response(&repo, &App::for_tests(), &parse(query_str)));
+                assert_eq!(city_ids(&response.unwrap().body), expected, "{:?}", query_str);
+            }
+        }
+    }
+
+    #[test]
+    fn region_errors_fail_request_by_default() {
+        let repo =
+            MockLocationsRepository { failing_regions: true, ..repo_with(vec![city(1, "Plzeň")]) };
+        let options = CityResponseOptions::default();
+
+        let result = block_on(city(1, "Plzeň").into_resp(&repo, Language::CS, options));
+        assert!(matches!(result, Err(InternalServerError(_))));
+    }
+
+    #[test]
+    fn skipped_region_is_fetched_only_for_hierarchy() {
+        let repo =
+            MockLocationsRepository { failing_regions: true, ..repo_with(vec![city(1, "Plzeň")]) };
+        let options = CityResponseOptions { skip_region: true, ..Default::default() };
+
+        let response = block_on(city(1, "Plzeň").into_resp(&repo, Language::CS, options)).unwrap();
+        assert_eq!(response.regionName, None);
+
+        let options = CityResponseOptions { include_region_hierarchy: true, ..options };
+        let result = block_on(city(1, "Plzeň").into_resp(&repo, Language::CS, options));
+        assert!(matches!(result, Err(InternalServerError(_))));
+    }
+
+    #[test]
+    fn region_errors_degrade_to_empty_region() {
+        let repo =
+            MockLocationsRepository { failing_regions: true, ..repo_with(vec![city(1, "Plzeň")]) };
+        let options = CityResponseOptions {
+            include_display_name: true,
+            include_region_hierarchy: true,
+            degrade_on_region_error: true,
+            ..Default::default()
+        };
+
+        let response = block_on(city(1, "Plzeň").into_resp(&repo, Language::CS, options)).unwrap();
+        assert_eq!(response.regionName.as_deref(), Some(""));
+        assert_eq!(response.displayName.as_deref(), Some("Plzeň"));
+        assert_eq!(response.regions.map(|it| it.len()), Some(0));
+
+        let app = App {
+            config: Config { degrade_on_region_error: true, ..Config::for_tests() },
+            ..App::for_tests()
+        };
+        let query = parse("language=cs&query=plz");
+        let response = block_on(search_response(&repo, &app, &query)).unwrap();
+        assert_eq!(city_ids(&response.body), vec![1]);
+    }
+
+    fn fastly_geo_coords(lat: &str, lon: &str) -> Option<Coordinates> {
+        let mut headers = HeaderMap::new();
+        headers.add_raw("Fastly-Geo-Lat", lat.to_string());
+        headers.add_raw("Fastly-Geo-Lon", lon.to_string());
+        get_request_fastly_geo_coords(&headers)
+    }
+
+    #[test]
+    fn fastly_geo_coords_within_range_are_accepted() {
+        for &(lat, lon) in &[("90", "180"), ("-90", "-180"), ("90.0", "-180.0"), ("50.1", "14.4")] {
+            let expected = Coordinates { lat: lat.parse().unwrap(), lon: lon.parse().unwrap() };
+            assert_eq!(fastly_geo_coords(lat, lon), Some(expected), "{}, {}", lat, lon);
+        }
+    }
+
+    #[test]
+    fn fastly_geo_coords_out_of_range_are_rejected() {
+        let invalid = [
+            ("90.0001", "0.1"),
+            ("-90.0001", "0.1"),
+            ("0.1", "180.0001"),
+            ("0.1", "-180.0001"),
+            ("NaN", "0.1"),
+            ("0.1", "NaN"),
+            ("inf", "0.1"),
+            ("abc", "0.1"),
+            ("", "0.1"),
+        ];
+        for &(lat, lon) in invalid.iter() {
+            assert_eq!(fastly_geo_coords(lat, lon), None, "{}, {}", lat, lon);
+        }
+        assert_eq!(get_request_fastly_geo_coords(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn normalize_text_composes_decomposed_input() {
+        let decomposed = "Plzen\u{30c}, C\u{30c}eske\u{301} Bude\u{30c}jovice";
+        assert_eq!(normalize_text(decomposed), "Plzeň, České Budějovice");
+        assert_eq!(normalize_text("Plzeň"), "Plzeň");
+        assert_eq!(normalize_text("Kraków"), normalize_text("Krako\u{301}w"));
+    }
+
+    #[test]
+    fn fastly_unknown_location_is_exactly_zero() {
+        let unknown = |lat: f64, lon: f64| is_fastly_unknown_location(Coordinates { lat, lon });
+        assert!(unknown(0.0, 0.0));
+        assert!(unknown(-0.0, 0.0));
+        assert!(unknown(0.0, -0.0));
+        assert!(unknown("0.000".parse().unwrap(), "-0".parse().unwrap()));
+        assert!(!unknown(0.0001, 0.0));
+        assert!(!unknown(0.0, -0.0001));
+        assert!(!unknown(50.1, 14.4));
+
+        assert_eq!(fastly_geo_coords("0.000", "0.000"), None);
+        assert_eq!(fastly_geo_coords("0.0001", "0"), Some(Coordinates { lat: 0.0001, lon: 0.0 }));
+    }
+
+    #[test]
+    fn oversized_city_lists_are_rejected() {
+        let cities: Vec<_> = (1..=5).map(|id| city(id, "Město")).collect();
+        let repo = repo_with(cities.clone());
+        let into_resp = |cities: Vec<ElasticCity>, max_cities| {
+            let options = CityResponseOptions::default();
+            block_on(es_cities_into_resp(&repo, cities, Language::CS, options, 2, max_cities))
+        };
+
+        let response = into_resp(cities.clone(), 5).unwrap();
+        assert_eq!(city_ids(&response), vec![1, 2, 3, 4, 5]);
+        assert!(matches!(into_resp(cities, 4), Err(PayloadTooLarge(_))));
+
+        assert!(check_response_size(0, 0).is_ok());
+        assert!(check_response_size(1000, 1000).is_ok());
+        assert!(matches!(check_response_size(1001, 1000), Err(PayloadTooLarge(_))));
+    }
+
+    #[test]
+    fn fold_text_ignores_case_diacritics_and_whitespace() {
+        assert_eq!(fold_text(" Plzeň "), "plzen");
+        assert_eq!(fold_text("ČESKÉ Budějovice"), "ceske budejovice");
+        assert_eq!(fold_text("Krako\u{301}w"), "krakow");
+        assert_eq!(fold_text("Łódź"), "łodz");
+    }
+
+    #[test]
+    fn exact_match_is_moved_first() {
+        let repo =
+            repo_with(vec![city(1, "Praha-východ"), city(2, "Praha"), city(3, "Praha-západ")]);
+
+        let query = parse("language=cs&query=PRAHA");
+        let response = block_on(search_response(&repo, &App::for_tests(), &query)).unwrap();
+        assert_eq!(city_ids(&response.body), vec![1, 2, 3]);
+
+        let query = parse("language=cs&query=PRAHA&exactMatchFirst=true");
+        let response = block_on(search_response(&repo, &App::for_tests(), &query)).unwrap();
+        assert_eq!(city_ids(&response.body), vec![2, 1, 3]);
+    }
+
+    #[test]
+    fn search_limit_is_capped_by_configured_maximum() {
+        let repo = repo_with((1..=6).map(|id| city(id, "Plzeň")).collect());
+        let app = App {
+            config: Config { search_max_limit: 5, ..Config::for_tests() },
+            ..App::for_tests()
+        };
+        let search = |query_str: &str| block_on(search_response(&repo, &app, &parse(query_str)));
+
+        let response = search("language=cs&query=plz&limit=5").unwrap();
+        assert_eq!(city_ids(&response.body), vec![1, 2, 3, 4, 5]);
+        for &query_str in &["language=cs&query=plz&limit=6", "language=cs&query=plz&limit=0"] {
+            let result = search(query_str);
+            let expected = "`limit` must be between 1 and 5.";
+            assert!(matches!(result, Err(BadRequest(m)) if m == expected), "{}", query_str);
+        }
+    }
+
+    #[test]
+    fn strict_country_iso_rejects_countries_without_cities() {
+        let repo = repo_with(vec![
+            city(1, "Plzeň"),
+            ElasticCity { countryIso: "SK".into(), ..city(2, "Plzeň") },
+        ]);
+        let search = |app: &App, query_str: &str| {
+            block_on(search_response(&repo, app, &parse(query_str))).map(|it| city_ids(&it.body))
+        };
+        let strict = App {
+            config: Config { strict_country_iso: true, ..Config::for_tests() },
+            ..App::for_tests()
+        };
+
+        assert_eq!(search(&strict, "language=cs&query=plz&countryIso=SK").unwrap(), vec![2]);
+        assert_eq!(search(&strict, "language=cs&query=plz").unwrap(), vec![1, 2]);
+        let result = search(&strict, "language=cs&query=plz&countryIso=PL");
+        assert!(matches!(result, Err(BadRequest(m)) if m == "Unknown `countryIso` `PL`."));
+
+        let lenient = App::for_tests();
+        assert_eq!(search(&lenient, "language=cs&query=plz&countryIso=PL").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn search_query_decodes_plus_as_space() {
+        let query: SearchQuery = parse("language=en&query=New+York");
+        assert_eq!(query.query, "New York");
+        let query: SearchQuery = parse("language=en&query=C%2B%2B+City");
+        assert_eq!(query.query, "C++ City");
+        let query: SearchQuery = parse("language=en&query=New%20York");
+        assert_eq!(query.query, "New York");
+    }
+
+    #[test]
+    fn min_score_defaults_per_language_and_is_overridden_by_query() {
+        let repo = repo_with(vec![city(1, "Plzeň")]);
+        let search_min_scores = vec![(Language::CS, 2.0)].into_iter().collect();
+        let app =
+            App { config: Config { search_min_scores, ..Config::for_tests() }, ..App::for_tests() };
+        let search = |query_str: &str| {
+            block_on(search_response(&repo, &app, &parse(query_str))).map(|it| city_ids(&it.body))
+        };
+
+        // Mock repository scores all matches 1.
+        assert_eq!(search("language=cs&query=plz").unwrap(), vec![]);
+        assert_eq!(search("language=de&query=plz").unwrap(), vec![1]);
+        assert_eq!(search("language=cs&query=plz&minScore=0.5").unwrap(), vec![1]);
+        assert_eq!(search("language=de&query=plz&minScore=1.5").unwrap(), vec![]);
+        for &query_str in
+            &["language=cs&query=plz&minScore=-1", "language=de&query=plz&minScore=NaN"]
+        {
+            let result = search(query_str);
+            let expected = "`minScore` must not be negative.";
+            assert!(matches!(result, Err(BadRequest(m)) if m == expected), "{}", query_str);
+        }
+    }
+
+    #[test]
+    fn exact_search_requires_diacritics_to_match() {
+        let repo = repo_with(vec![city(1, "Plzeň"), city(2, "Plzenec")]);
+        let search = |query_str: &str| {
+            let response =
+                block_on(search_response(&repo, &App::for_tests(), &parse(query_str))).unwrap();
+            city_ids(&response.body)
+        };
+
+        assert_eq!(search("language=cs&query=plzen"), vec![1, 2]);
+        assert_eq!(search("language=cs&query=plzen&exact=true"), vec![2]);
+        assert_eq!(search("language=cs&query=PLZEŇ&exact=true"), vec![1]);
+        assert_eq!(search("language=cs&query=plzeň"), vec![1, 2]);
+    }
+
+    #[test]
+    fn city_ids_are_parsed_and_deduplicated() {
+        let ids = |text: &str| {
+            parse_city_ids(text).map(|ids| ids.iter().map(|id| id.0).collect::<Vec<_>>())
+        };
+        assert_eq!(ids("1").unwrap(), vec![1]);
+        assert_eq!(ids(" 3 , 1,2,1, 3").unwrap(), vec![3, 1, 2]);
+
+        let big: Vec<String> = (1..=1000).chain(1..=1000).map(|id| id.to_string()).collect();
+        assert_eq!(ids(&big.join(",")).unwrap().len(), 1000);
+    }
+
+    #[test]
+    fn city_ids_can_be_comma_separated_or_repeated() {
+        let ids = |query_str: &str| {
+            let query: CitiesQuery = parse(query_str);
+            query.ids.0.iter().map(|id| id.0).collect::<Vec<_>>()
+        };
+        assert_eq!(ids("language=cs&ids=1,2"), vec![1, 2]);
+        assert_eq!(ids("language=cs&ids=1&ids=2"), vec![1, 2]);
+        assert_eq!(ids("ids=3,%201&language=cs&ids=2,3&ids=1"), vec![3, 1, 2]);
+    }
+
+    #[test]
+    fn invalid_or_missing_city_ids_are_rejected() {
+        for &query_str in &["language=cs&ids=1&ids=x", "language=cs&ids=1,,2", "language=cs"] {
+            let result = CitiesQuery::from_form(&mut FormItems::from(query_str), false);
+            assert!(result.is_err(), "{}", query_str);
+        }
+        let strict = CitiesQuery::from_form(&mut FormItems::from("ids=1&language=cs&x=1"), true);
+        assert!(matches!(strict, Err(BadRequest(message)) if message.starts_with("Unknown")));
+    }
+
+    #[test]
+    fn invalid_city_ids_are_named() {
+        for &(text, token) in
+            &[("1,x,2", "x"), ("1,,2", ""), ("0", "0"), ("2,-1", "-1"), ("1,2.5", "2.5")]
+        {
+            match parse_city_ids(text) {
+                Err(BadRequest(message)) => {
+                    assert_eq!(message, format!("Invalid city id `{}` in `ids`.", token))
+                }
+                result => panic!("Unexpected result for `{}`: {:?}.", text, result),
+            }
+        }
+    }
+
+    #[test]
+    fn get_many_names_invalid_ids() {
+        use rocket::{http::Status, local::Client, routes};
+
+        let rocket = rocket::ignite().manage(App::for_tests()).mount("/", routes![get_many]);
+        let client = Client::new(rocket).expect("valid rocket instance");
+        for &(query, token) in &[
+            ("ids=1,x&language=cs", "x"),
+            ("ids=1,,2&language=cs", ""),
+            ("ids=1&ids=x&language=cs", "x"),
+            ("ids=1&ids=2,0&language=cs", "0"),
+        ] {
+            let mut response = client.get(format!("/city/v1/getMany?{}", query)).dispatch();
+            assert_eq!(response.status(), Status::BadRequest, "{}", query);
+            let body = response.body_string().unwrap_or_default();
+            let message = format!("Invalid city id `{}` in `ids`.", token);
+            assert!(body.contains(&message), "{}: {}", query, body);
+        }
+    }
 }
@@ -0,0 +1,41 @@
+//! Handler for the `/config` endpoint.
+
+use crate::AppState;
+use rocket::get;
+use rocket_contrib::json::Json;
+use rocket_okapi::{openapi, JsonSchema};
+use serde::Serialize;
+
+/// Configuration of the service instance that affects its API.
+#[allow(non_snake_case)]
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ConfigResponse {
+    /// Page limits of list endpoints.
+    pageLimits: PageLimits,
+}
+
+/// Page limits of list endpoints configured using `GOOUT_DEFAULT_PAGE_LIMIT` and
+/// `GOOUT_MAX_PAGE_LIMIT`.
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct PageLimits {
+    /// Number of cities in a page of `/city/v1/list`, `/city/v1/changes` and paginated
+    /// `/city/v1/featured` if the request does not give `limit`, e.g. `100`.
+    default: usize,
+    /// Maximum `limit` accepted by list endpoints, e.g. `1000`.
+    max: usize,
+}
+
+/// The `/config` endpoint. HTTP request: none, response: [`ConfigResponse`].
+///
+/// Returns configuration of the service instance that serves the request which clients may need
+/// to know, e.g. its page limits.
+#[openapi]
+#[get("/config")]
+pub(crate) fn config(app: AppState<'_>) -> Json<ConfigResponse> {
+    Json(ConfigResponse {
+        pageLimits: PageLimits {
+            default: app.config.default_page_limit,
+            max: app.config.max_page_limit,
+        },
+    })
+}
@@ -0,0 +1,188 @@
+//! Handlers for `/geo/*` endpoints.
+
+use crate::{
+    handlers::city::{CityResponse, CityResponseOptions, Parse, StrictParams},
+    response::{
+        ErrorResponse::{BadRequest, InternalServerError, NotFound, NotImplemented},
+        HandlerResult, JsonResult,
+    },
+    services::locations_repo::{
+        Coordinates, Language, LocationsElasticRepository, LocationsRepository,
+    },
+    App, AppState,
+};
+use maxminddb::{geoip2, MaxMindDBError};
+use rocket::{get, FromForm};
+use rocket_contrib::json::Json;
+use rocket_okapi::{openapi, JsonSchema};
+use serde::Serialize;
+use std::net::IpAddr;
+use validator::Validate;
+
+/// Query for the `/geo/v1/resolve` endpoint.
+#[derive(JsonSchema, FromForm)]
+pub(crate) struct ResolveQuery {
+    /// IPv4 or IPv6 address to resolve, e.g. `81.95.96.1`.
+    ip: String,
+}
+
+/// Approximate location of an IP address.
+#[allow(non_snake_case)]
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ResolveResponse {
+    /// Latitude in decimal degrees, e.g. `50.08`.
+    lat: f64,
+    /// Longitude in decimal degrees, e.g. `14.42`.
+    lon: f64,
+    /// ISO 3166-1 alpha-2 code of the country of the address, e.g. `"CZ"`. Present only if the
+    /// geo-location database knows it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    countryIso: Option<String>,
+}
+
+/// The `/geo/v1/resolve` endpoint. HTTP request: [`ResolveQuery`], response: [`ResolveResponse`].
+///
+/// Resolves an IP address to approximate coordinates using the IP geo-location database of the
+/// server, useful to debug geo-location of clients. Responds with Not Found if the database has
+/// no location of the address and with Not Implemented if the server has no database configured.
+#[openapi]
+#[get("/geo/v1/resolve?<query..>")]
+pub(crate) fn resolve(
+    query: Parse<'_, ResolveQuery>,
+    strict: StrictParams,
+    app: AppState<'_>,
+) -> JsonResult<ResolveResponse> {
+    let query = query?;
+    strict.check::<ResolveQuery>()?;
+    let database = app
+        .geoip_database
+        .as_ref()
+        .ok_or_else(|| NotImplemented("No IP geo-location database is configured.".to_string()))?;
+    let ip: IpAddr =
+        query.ip.parse().map_err(|_| BadRequest(format!("Invalid IP address `{}`.", query.ip)))?;
+
+    let not_found = || NotFound(format!("Location of {} not found.", ip));
+    let city: geoip2::City = database.lookup(ip).map_err(|e| match e {
+        MaxMindDBError::AddressNotFoundError(_) => not_found(),
+        e => InternalServerError(format!("IP geo-location database error: {}", e)),
+    })?;
+    let location = city.location.ok_or_else(not_found)?;
+    let coords = match (location.latitude, location.longitude) {
+        (Some(lat), Some(lon)) => Coordinates { lat, lon }.rounded(),
+        _ => return Err(not_found()),
+    };
+    let country_iso = city.country.and_then(|it| it.iso_code).map(|it| it.to_string());
+
+    Ok(Json(ResolveResponse { lat: coords.lat, lon: coords.lon, countryIso: country_iso }))
+}
+
+/// Query for the `/geo/v1/covered` endpoint.
+#[derive(JsonSchema, FromForm)]
+pub(crate) struct CoveredQuery {
+    /// Latitude in decimal degrees with dot as a separator, e.g. `50.1`.
+    lat: f64,
+    /// Longitude in decimal degrees with dot as a separator, e.g. `14.4`.
+    lon: f64,
+    /// Language to localize `nearestCity` to. The response has no `nearestCity` if not given.
+    language: Option<Language>,
+}
+
+/// Whether a point is within coverage of the service.
+#[allow(non_snake_case)]
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct CoveredResponse {
+    /// `true` if there is a city within the coverage radius of the point.
+    covered: bool,
+    /// The city closest to the point, with `distanceKm` from it. Present only if `covered` and if
+    /// `language` is given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nearestCity: Option<CityResponse>,
+}
+
+/// The `/geo/v1/covered` endpoint. HTTP request: [`CoveredQuery`], response: [`CoveredResponse`].
+///
+/// Tells whether the point is covered by the service, i.e. whether some city lies within the
+/// coverage radius configured on the server (50 km by default), so that clients can decide before
+/// geo-locating whether it is worth it.
+#[openapi]
+#[get("/geo/v1/covered?<query..>")]
+pub(crate) fn covered(
+    query: Parse<'_, CoveredQuery>,
+    strict: StrictParams,
+    app: AppState<'_>,
+) -> JsonResult<CoveredResponse> {
+    let query = query?;
+    strict.check::<CoveredQuery>()?;
+    let locations_es_repo = LocationsElasticRepository(&app);
+
+    app.block_on(covered_response(&locations_es_repo, &app, &query)).map(Json)
+}
+
+/// Logic of the `/geo/v1/covered` endpoint, separated from the handler to be testable against a
+/// mock repository.
+async fn covered_response(
+    repo: &impl LocationsRepository,
+    app: &App,
+    query: &CoveredQuery,
+) -> HandlerResult<CoveredResponse> {
+    let coords = Coordinates { lat: query.lat, lon: query.lon };
+    coords.validate()?;
+
+    let es_city = match repo.get_city_by_coords(coords, None, None).await {
+        Ok(es_city) => Some(es_city),
+        // There are no cities at all.
+        Err(NotFound(_)) => None,
+        Err(e) => return Err(e),
+    };
+    let es_city =
+        es_city.filter(|it| it.centroid.distance_km(coords) <= app.config.coverage_radius_km);
+    let covered = es_city.is_some();
+
+    let nearest_city = match (es_city, query.language) {
+        (Some(es_city), Some(language)) => {
+            let options = CityResponseOptions {
+                distance_from: Some(coords),
+                degrade_on_region_error: app.config.degrade_on_region_error,
+                ..Default::default()
+            };
+            Some(es_city.into_resp(repo, language, options).await?)
+        }
+        _ => None,
+    };
+    Ok(CoveredResponse { covered, nearestCity: nearest_city })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::mock_locations_repo::{block_on, city, region, MockLocationsRepository};
+    use rocket::request::{FormItems, FromForm};
+
+    fn covered(query_str: &str) -> CoveredResponse {
+        let repo = MockLocationsRepository {
+            cities: vec![city(1, "Plzeň")],
+            regions: vec![region(1, "Kraj")],
+            ..Default::default()
+        };
+        let query = CoveredQuery::from_form(&mut FormItems::from(query_str), false).unwrap();
+        block_on(covered_response(&repo, &App::for_tests(), &query)).unwrap()
+    }
+
+    #[test]
+    fn nearest_city_is_included_only_with_language() {
+        let response = covered("lat=0.1&lon=0.1&language=cs");
+        assert!(response.covered);
+        assert!(response.nearestCity.is_some());
+
+        let response = covered("lat=0.1&lon=0.1");
+        assert!(response.covered);
+        assert!(response.nearestCity.is_none());
+    }
+
+    #[test]
+    fn points_beyond_coverage_radius_are_not_covered() {
+        let response = covered("lat=10&lon=10&language=cs");
+        assert!(!response.covered);
+        assert!(response.nearestCity.is_none());
+    }
+}
@@ -0,0 +1,28 @@
+//! Handlers for the `/livez` and `/readyz` probes, deliberately left out of the OpenAPI spec.
+
+use crate::{
+    response::HandlerResult, services::locations_repo, stateful::elasticsearch::WithElastic,
+    AppState,
+};
+use rocket::get;
+
+/// The `/livez` endpoint. HTTP request: none, response: plain `OK`.
+///
+/// Liveness probe: succeeds whenever the process runs, regardless of Elasticsearch, so that an
+/// Elasticsearch outage does not make the orchestrator restart healthy instances.
+#[get("/livez")]
+pub(crate) fn livez() -> &'static str {
+    "OK"
+}
+
+/// The `/readyz` endpoint. HTTP request: none, response: plain `OK`.
+///
+/// Readiness probe: succeeds only if Elasticsearch is reachable and the city and region indices
+/// exist, HTTP 503 Service Unavailable otherwise.
+#[get("/readyz")]
+pub(crate) fn readyz(app: AppState<'_>) -> HandlerResult<&'static str> {
+    app.block_on(async {
+        locations_repo::check_ready(&app.elasticsearch()).await?;
+        Ok("OK")
+    })
+}
@@ -0,0 +1,32 @@
+//! Handler for the `/metrics` endpoint.
+
+use crate::services::{
+    cache::CacheMetrics,
+    locations_repo::{self, ElasticMetrics},
+};
+use rocket::get;
+use rocket_contrib::json::Json;
+use rocket_okapi::{openapi, JsonSchema};
+use serde::Serialize;
+
+/// Runtime metrics of the service.
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct MetricsResponse {
+    /// Metrics of all in-memory caches of the service.
+    caches: Vec<CacheMetrics>,
+    /// Metrics of Elasticsearch searches, to tell time spent in Elasticsearch from overhead.
+    elasticsearch: ElasticMetrics,
+}
+
+/// The `/metrics` endpoint. HTTP request: none, response: [`MetricsResponse`].
+///
+/// Returns metrics of the service instance that serves the request, e.g. hit ratios of its
+/// caches and time spent in Elasticsearch. Counters are cumulative since start of the instance.
+#[openapi]
+#[get("/metrics")]
+pub(crate) fn metrics() -> Json<MetricsResponse> {
+    Json(MetricsResponse {
+        caches: locations_repo::cache_metrics(),
+        elasticsearch: locations_repo::elastic_metrics(),
+    })
+}
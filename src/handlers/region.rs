@@ -0,0 +1,197 @@
+//! Handlers for `/region/*` endpoints.
+
+use crate::{
+    handlers::city::{
+        check_search_found, check_search_query_length, es_cities_into_resp, normalize_text,
+        query_coordinates, MultiCityResponse, Parse, StrictParams,
+    },
+    protobuf::{self, IntoProtobuf},
+    response::{ErrorResponse::BadRequest, HandlerResult, JsonResult, NegotiatedResult},
+    services::{
+        collation::Collation,
+        locations_repo::{
+            ElasticRegion, Language, LocationsElasticRepository, LocationsRepository, RegionId,
+            SearchResults,
+        },
+    },
+    AppState,
+};
+use rocket::{get, FromForm};
+use rocket_contrib::json::Json;
+use rocket_okapi::{openapi, JsonSchema};
+use serde::Serialize;
+use std::cmp::Ordering;
+
+/// `Region` API entity.
+#[allow(non_snake_case)]
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct RegionResponse {
+    /// Id of the region, e.g. `123`.
+    id: RegionId,
+    /// ISO 3166-1 alpha-2 country code, or a custom 4-letter code, e.g. `"CZ"`.
+    countryIso: String,
+    /// E.g. `"Plzeňský kraj"`.
+    name: String,
+}
+
+impl IntoProtobuf for RegionResponse {
+    type Message = protobuf::Region;
+
+    fn into_protobuf(self) -> protobuf::Region {
+        protobuf::Region { id: self.id.0, country_iso: self.countryIso, name: self.name }
+    }
+}
+
+/// A list of `Region` API entities.
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct MultiRegionResponse {
+    regions: Vec<RegionResponse>,
+    /// `true` if the server cut the search short to bound its cost, so that the list may miss
+    /// some matching regions. Absent otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    partial: Option<bool>,
+}
+
+/// Query for the `/region/v1/search` endpoint.
+#[allow(non_snake_case)]
+#[derive(JsonSchema, FromForm)]
+pub(crate) struct RegionSearchQuery {
+    /// The search query. Decoded as a form value: `+` stands for a space (e.g. `New+York`), a
+    /// literal plus sign has to be sent percent-encoded as `%2B`.
+    query: String,
+    /// ISO 3166-1 alpha-2 country code. Can be used to limit scope of the search to a given country.
+    countryIso: Option<String>,
+    language: Language,
+    /// Whether to respond with Not Found rather than an empty list if no region matches.
+    emptyAs404: bool,
+}
+
+/// The `/region/v1/search` endpoint. HTTP request: [`RegionSearchQuery`],
+/// response: [`MultiRegionResponse`].
+///
+/// Returns list of regions matching the 'query' parameter.
+/// The response is limited to 10 regions and no pagination is provided.
+/// Queries shorter than a per-language minimum (2 characters by default) yield an empty list,
+/// queries longer than 200 characters are rejected with HTTP 413 Payload Too Large.
+/// With `emptyAs404` an empty result (including that of a too short query) is HTTP 404 Not Found.
+#[openapi]
+#[get("/region/v1/search?<query..>")]
+pub(crate) fn search(
+    query: Parse<'_, RegionSearchQuery>,
+    strict: StrictParams,
+    app: AppState<'_>,
+) -> JsonResult<MultiRegionResponse> {
+    let query = query?;
+    strict.check::<RegionSearchQuery>()?;
+    let search_query = normalize_text(&query.query);
+    check_search_query_length(&search_query)?;
+    let locations_es_repo = LocationsElasticRepository(&app);
+
+    if search_query.trim().chars().count() < app.config.search_min_length(query.language) {
+        check_search_found(0, query.emptyAs404)?;
+        return Ok(Json(MultiRegionResponse { regions: Vec::new(), partial: None }));
+    }
+
+    app.block_on(async {
+        let SearchResults { items: es_regions, partial } = locations_es_repo
+            .search_regions(&search_query, query.language, query.countryIso.as_deref())
+            .await?;
+        check_search_found(es_regions.len(), query.emptyAs404)?;
+
+        let regions: HandlerResult<_> =
+            es_regions.into_iter().map(|it| it.into_resp(query.language)).collect();
+        let partial = Some(true).filter(|_| partial);
+        Ok(Json(MultiRegionResponse { regions: regions?, partial }))
+    })
+}
+
+/// Query for the `/region/v1/cities` endpoint.
+#[allow(non_snake_case)]
+#[derive(JsonSchema, FromForm)]
+pub(crate) struct RegionCitiesQuery {
+    /// Id of the region to list cities of, positive integer.
+    id: RegionId,
+    /// Latitude in decimal degrees with . as decimal separator. Sort by distance if given.
+    lat: Option<f64>,
+    /// Longitude in decimal degrees with . as decimal separator. Sort by distance if given.
+    lon: Option<f64>,
+    language: Language,
+    /// Whether to include `timezone` field in the response.
+    includeTimezone: bool,
+    /// Whether to include `countryName` field in the response.
+    includeCountryName: bool,
+    /// Whether to include `population` field in the response.
+    includePopulation: bool,
+    /// Whether to include `regions` field in the response.
+    includeRegionHierarchy: bool,
+    /// Whether to include `displayName` field in the response.
+    includeDisplayName: bool,
+    /// Whether to include `officialName` and `commonName` fields in the response.
+    includeNameVariants: bool,
+    /// Whether to include `regionId` field in the response.
+    includeIds: bool,
+    /// Whether to leave out `regionName` from the response and skip fetching the region, for
+    /// faster responses to clients that do not need it. The region is still fetched (as a part of
+    /// `regions`) if `includeRegionHierarchy` is requested too.
+    skipRegion: bool,
+}
+
+impl_city_response_options!(RegionCitiesQuery);
+
+/// The `/region/v1/cities` endpoint. HTTP request: [`RegionCitiesQuery`],
+/// response: [`MultiCityResponse`].
+///
+/// Returns all cities of given region sorted by their localized name. If both `lat` and `lon` are
+/// given, cities are sorted by distance from that point instead and `distanceKm` is filled in.
+#[openapi]
+#[get("/region/v1/cities?<query..>")]
+pub(crate) fn cities(
+    query: Parse<'_, RegionCitiesQuery>,
+    strict: StrictParams,
+    app: AppState<'_>,
+) -> NegotiatedResult<MultiCityResponse> {
+    let query = query?;
+    strict.check::<RegionCitiesQuery>()?;
+    let coords = query_coordinates(query.lat, query.lon)?;
+    let locations_es_repo = LocationsElasticRepository(&app);
+
+    app.block_on(async {
+        locations_es_repo.get_region(query.id).await?; // Respond with Not Found for unknown region.
+        let mut es_cities = locations_es_repo.get_region_cities(query.id).await?;
+
+        match coords {
+            Some(coords) => es_cities.sort_by(|a, b| {
+                let (a_distance, b_distance) =
+                    (a.centroid.distance_km(coords), b.centroid.distance_km(coords));
+                a_distance.partial_cmp(&b_distance).unwrap_or(Ordering::Equal)
+            }),
+            None => {
+                let (name_key, collation) =
+                    (query.language.name_key(), Collation::for_language(query.language));
+                es_cities
+                    .sort_by_cached_key(|c| c.names.get(&name_key).map(|n| collation.sort_key(n)));
+            }
+        }
+
+        let options = query.options(&app, coords);
+        es_cities_into_resp(
+            &locations_es_repo,
+            es_cities,
+            query.language,
+            options,
+            app.config.max_concurrent_region_fetches,
+            app.config.max_response_cities,
+        )
+        .await
+    })
+}
+
+impl ElasticRegion {
+    /// Transform ElasticRegion into RegionResponse.
+    pub(crate) fn into_resp(self, language: Language) -> HandlerResult<RegionResponse> {
+        let name_key = language.name_key();
+        let name = self.names.get(&name_key).ok_or_else(|| BadRequest(name_key.clone()))?;
+
+        Ok(RegionResponse { id: self.id, countryIso: self.countryIso, name: name.to_string() })
+    }
+}
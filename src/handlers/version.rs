@@ -0,0 +1,36 @@
+//! Handler for the `/version` endpoint.
+
+use rocket::get;
+use rocket_contrib::json::Json;
+use rocket_okapi::{openapi, JsonSchema};
+use serde::Serialize;
+use time::Timespec;
+
+/// Build info of the running service.
+#[allow(non_snake_case)]
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct VersionResponse {
+    /// Version of the service crate, e.g. `"0.1.0"`.
+    version: &'static str,
+    /// Hash of the git commit the service was built from, `"unknown"` if it was not available
+    /// at build time.
+    gitCommit: &'static str,
+    /// Time of the build in RFC 3339 format, e.g. `"2020-08-26T12:00:00Z"`.
+    buildTimestamp: String,
+}
+
+/// The `/version` endpoint. HTTP request: none, response: [`VersionResponse`].
+///
+/// Returns version, git commit and build time of the service, useful to verify deployments.
+#[openapi]
+#[get("/version")]
+pub(crate) fn version() -> Json<VersionResponse> {
+    let build_timestamp: i64 =
+        env!("GOOUT_BUILD_TIMESTAMP").parse().expect("build script sets a valid timestamp");
+
+    Json(VersionResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        gitCommit: env!("GOOUT_GIT_COMMIT"),
+        buildTimestamp: time::at_utc(Timespec::new(build_timestamp, 0)).rfc3339().to_string(),
+    })
+}
@@ -0,0 +1,48 @@
+//! Initialization of logging in human-readable or JSON format.
+
+use crate::{config::LoggingConfig, fairings::request_id};
+use serde_json::json;
+use std::{io::Write, str::FromStr};
+
+/// Format of log lines.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum LogFormat {
+    /// Human-readable colored lines, suitable for local development.
+    Text,
+    /// One JSON object per line, suitable for log aggregation.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown log format `{}`, expected `text` or `json`", s)),
+        }
+    }
+}
+
+/// Initialize logging with level (filter) and format given by `config`.
+pub(crate) fn init(config: &LoggingConfig) {
+    match config.format {
+        LogFormat::Text => {
+            pretty_env_logger::formatted_timed_builder().parse_filters(&config.filter).init()
+        }
+        LogFormat::Json => env_logger::Builder::new()
+            .parse_filters(&config.filter)
+            .format(|buf, record| {
+                let line = json!({
+                    "timestamp": buf.timestamp_millis().to_string(),
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                    "request_id": request_id::current(),
+                });
+                writeln!(buf, "{}", line)
+            })
+            .init(),
+    }
+}
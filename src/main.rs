@@ -13,90 +13,209 @@
 // Rocket v0.4 needs this nightly feature
 #![feature(decl_macro)]
 
-use crate::stateful::elasticsearch::WithElastic;
+use crate::{
+    config::{Config, LoggingConfig},
+    response::HandlerResult,
+    services::locations_repo::{self, Language},
+    stateful::elasticsearch::WithElastic,
+};
 use elasticsearch::Elasticsearch;
-use env_logger::DEFAULT_FILTER_ENV;
 use log::info;
-use rocket::{catchers, State};
+use once_cell::unsync::OnceCell;
+use rocket::{catchers, routes, State};
 use rocket_okapi::{
     handlers::RedirectHandler,
     routes_with_openapi,
     swagger_ui::{make_swagger_ui, SwaggerUIConfig},
 };
-use std::{cell::RefCell, env, future::Future, rc::Rc, thread};
-use tokio::runtime::{self, Runtime};
+use std::{cell::RefCell, future::Future, rc::Rc, thread};
+use tokio::{
+    runtime::{self, Runtime},
+    time::{timeout_at, Instant},
+};
 
+/// Module for Rocket fairings - middleware that applies to all requests or responses.
+mod fairings {
+    pub(crate) mod cache_control;
+    pub(crate) mod es_took;
+    pub(crate) mod json_only;
+    pub(crate) mod param_aliases;
+    pub(crate) mod query_limits;
+    pub(crate) mod request_id;
+    pub(crate) mod security_headers;
+    pub(crate) mod timeout;
+    pub(crate) mod trailing_slash;
+    pub(crate) mod vary;
+}
 /// Module for endpoint handlers (also known as controllers). This module also serves as an HTTP
 /// REST API documentation for clients.
 mod handlers {
+    pub(crate) mod admin;
+    #[macro_use] // For impl_city_response_options!() used by region.
     pub(crate) mod city;
+    pub(crate) mod config;
+    pub(crate) mod geo;
+    pub(crate) mod health;
+    pub(crate) mod metrics;
+    pub(crate) mod region;
+    pub(crate) mod version;
 }
+mod config;
+mod logging;
+mod protobuf;
 mod response;
 /// Module for stateless services (that may depend on stateful ones from [stateful] module).
 mod services {
+    pub(crate) mod cache;
+    pub(crate) mod collation;
+    pub(crate) mod countries;
     pub(crate) mod locations_repo;
+    #[cfg(test)]
+    pub(crate) mod mock_locations_repo;
+    pub(crate) mod query_templates;
 }
 /// Module for "stateful" services - those that need initialisation on startup and a living state.
 mod stateful {
     pub(crate) mod elasticsearch;
+    pub(crate) mod geoip;
 }
 
 fn main() {
-    // Set default log level to info and then init logging.
-    if env::var(DEFAULT_FILTER_ENV).is_err() {
-        env::set_var(DEFAULT_FILTER_ENV, "info");
-    }
-    pretty_env_logger::init_timed();
+    logging::init(&LoggingConfig::from_env());
 
-    let app_state = App::new();
+    let app_state = App::new(Config::from_env()); // Parse and validate configuration or panic.
+    let config = &app_state.config;
 
-    rocket::ignite()
-        .manage(app_state)
+    let mut rocket = rocket::ignite()
+        .attach(fairings::request_id::RequestId)
+        .attach(fairings::trailing_slash::TrailingSlash)
+        .attach(fairings::timeout::Timeout::from_config(config))
+        .attach(fairings::es_took::EsTook::from_config(config))
+        .attach(fairings::query_limits::QueryLimits::from_config(config))
+        .attach(fairings::param_aliases::ParamAliases)
+        .attach(fairings::json_only::JsonOnly)
+        .attach(fairings::security_headers::SecurityHeaders::from_config(config))
+        .attach(fairings::cache_control::CacheControl::from_config(config))
+        .attach(fairings::vary::Vary)
         .register(catchers![response::not_found, response::internal_server_error])
         .mount(
             "/",
             routes_with_openapi![
                 handlers::city::get,
+                handlers::city::get_many,
+                handlers::city::get_by_name,
                 handlers::city::featured,
+                handlers::city::featured_by_country,
+                handlers::city::featured_count,
+                handlers::city::list,
+                handlers::city::changes,
+                handlers::city::bounding_box,
+                handlers::city::within_polygon,
                 handlers::city::search,
                 handlers::city::closest,
+                handlers::city::closest_batch,
                 handlers::city::associated_featured,
+                handlers::city::neighbors,
+                handlers::city::closest_per_country,
+                handlers::city::region,
+                handlers::region::cities,
+                handlers::region::search,
+                handlers::geo::resolve,
+                handlers::geo::covered,
+                handlers::version::version,
+                handlers::admin::cache_clear,
+                handlers::metrics::metrics,
+                handlers::config::config,
             ],
         )
+        // Probes are for the orchestrator, not for API clients, so they are left out of the spec.
+        .mount("/", routes![handlers::health::livez, handlers::health::readyz])
+        .mount("/", routes![fairings::query_limits::rejected])
+        .mount("/", routes![fairings::json_only::not_acceptable_route])
         // I was unable to customize OpenAPI spec location, so just redirect to it:
-        .mount("/", vec![RedirectHandler::to("/openapi.json").into_route("/api-docs")])
-        .mount(
-            "/docs",
+        .mount("/", vec![RedirectHandler::to("/openapi.json").into_route("/api-docs")]);
+
+    // Interactive docs are useful during development, but rather a liability in production.
+    if config.swagger_ui.unwrap_or_else(|| rocket.config().environment.is_dev()) {
+        let path = &config.swagger_ui_path;
+        info!("Serving Swagger UI at {}.", path);
+        rocket = rocket.mount(
+            path,
             make_swagger_ui(&SwaggerUIConfig {
                 url: "/openapi.json".to_owned(),
                 ..Default::default()
             }),
-        )
-        .launch();
+        );
+    }
+
+    rocket.manage(app_state).launch();
 }
 
-struct App {}
+struct App {
+    /// Configuration parsed from env variables at startup.
+    config: Config,
+    /// IP geo-location database used by `/geo/v1/resolve`, if configured.
+    geoip_database: Option<stateful::geoip::GeoIpDatabase>,
+}
 
 thread_local! {
     // RefCell because Runtime::block_on() needs mutable reference.
     static RT: RefCell<Runtime> = RefCell::new(create_async_rt());
-    // Rc because we want the Elasticsearch reference to escape LocalKey::with().
-    static ES: Rc<Elasticsearch> = Rc::new(stateful::elasticsearch::new_pingless());
+    // Rc because we want the Elasticsearch reference to escape LocalKey::with(). OnceCell because
+    // the client is created from config of the app state, not available here.
+    static ES: OnceCell<Rc<Elasticsearch>> = OnceCell::new();
 }
 
 type AppState<'a> = State<'a, App>;
 
 impl App {
-    fn new() -> Self {
+    fn new(config: Config) -> Self {
         // Don't use thread-local variables here - main thread is not reused for Rocket workers.
-        create_async_rt().block_on(stateful::elasticsearch::new()); // Ping Elastic or panic.
+        let mut rt = create_async_rt();
+        let elasticsearch = rt.block_on(stateful::elasticsearch::new(&config.elastic)); // Or panic.
+        rt.block_on(locations_repo::check_indices(&elasticsearch)); // Resolve indices or panic.
+        if config.check_index_mappings {
+            rt.block_on(locations_repo::check_mappings(&elasticsearch)); // Check fields or panic.
+        }
+        services::query_templates::init(&config.query_templates); // Load templates or panic.
+        locations_repo::init_caches(config.region_cache_ttl);
+        locations_repo::init_response_precision(
+            config.coordinates_decimals,
+            config.distance_km_decimals,
+        );
+        if config.check_fallback_cities {
+            let ids: Vec<_> =
+                Language::ALL.iter().map(|&it| handlers::city::fallback_city_id(it)).collect();
+            rt.block_on(locations_repo::check_cities_exist(Rc::new(elasticsearch), &ids));
+        }
+
+        // Load the database or panic.
+        let geoip_database = config.geoip_database.as_deref().map(stateful::geoip::load);
+        info!("IP geo-location database configured: {}.", geoip_database.is_some());
 
-        Self {}
+        Self { config, geoip_database }
     }
 
-    /// Run given future in async runtime and block current thread until it resolves.
-    fn block_on<F: Future>(&self, future: F) -> F::Output {
-        RT.with(|rt_cell| rt_cell.borrow_mut().block_on(future))
+    /// Run given future in async runtime and block current thread until it resolves. If it doesn't
+    /// resolve until the deadline of the current request, drop it and respond with HTTP 503.
+    fn block_on<T>(&self, future: impl Future<Output = HandlerResult<T>>) -> HandlerResult<T> {
+        let request_deadline = match fairings::timeout::current() {
+            Some(request_deadline) => request_deadline,
+            None => return RT.with(|rt_cell| rt_cell.borrow_mut().block_on(future)),
+        };
+
+        let deadline = Instant::from_std(request_deadline.deadline);
+        RT.with(|rt_cell| rt_cell.borrow_mut().block_on(timeout_at(deadline, future)))
+            .unwrap_or_else(|_| Err(request_deadline.timed_out()))
+    }
+}
+
+#[cfg(test)]
+impl App {
+    /// App state with default configuration, independent of env variables. Its Elasticsearch
+    /// client is never created by handler logic running against a mock repository.
+    fn for_tests() -> Self {
+        Self { config: Config::for_tests(), geoip_database: None }
     }
 }
 
@@ -111,6 +230,22 @@ fn create_async_rt() -> Runtime {
 
 impl WithElastic for AppState<'_> {
     fn elasticsearch(&self) -> Rc<Elasticsearch> {
-        ES.with(|es| Rc::clone(es))
+        ES.with(|es| {
+            Rc::clone(es.get_or_init(|| {
+                Rc::new(stateful::elasticsearch::new_pingless(&self.config.elastic))
+            }))
+        })
+    }
+
+    fn elastic_preference(&self) -> Option<&str> {
+        self.config.elastic.preference.as_deref()
+    }
+
+    fn elastic_search_timeout(&self) -> Option<&str> {
+        self.config.elastic.search_timeout.as_deref()
+    }
+
+    fn elastic_search_terminate_after(&self) -> Option<u64> {
+        self.config.elastic.search_terminate_after
     }
 }
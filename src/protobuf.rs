@@ -0,0 +1,140 @@
+//! Protocol Buffers messages of city responses, implemented using [prost] derive macros so that
+//! building doesn't need `protoc`. Schema of the messages is in `proto/locations.proto`, keep them
+//! in sync.
+
+use crate::services::locations_repo;
+use prost::{Enumeration, Message};
+
+/// API entity that can be encoded as a Protocol Buffers message.
+pub(crate) trait IntoProtobuf {
+    type Message: Message;
+
+    fn into_protobuf(self) -> Self::Message;
+}
+
+/// API response that can be encoded as a Protocol Buffers message. Implemented for all
+/// [IntoProtobuf] entities, and directly by responses whose message type depends on the request.
+pub(crate) trait EncodeProtobuf {
+    fn encode_protobuf(self) -> Vec<u8>;
+}
+
+impl<T: IntoProtobuf> EncodeProtobuf for T {
+    fn encode_protobuf(self) -> Vec<u8> {
+        let message = self.into_protobuf();
+        let mut body = Vec::with_capacity(message.encoded_len());
+        message.encode(&mut body).expect("Vec has enough capacity for the message");
+        body
+    }
+}
+
+/// `City` message, see [crate::handlers::city::CityResponse].
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct City {
+    #[prost(uint64, required, tag = "1")]
+    pub(crate) id: u64,
+    #[prost(bool, required, tag = "2")]
+    pub(crate) is_featured: bool,
+    #[prost(string, required, tag = "3")]
+    pub(crate) country_iso: String,
+    #[prost(string, required, tag = "4")]
+    pub(crate) name: String,
+    #[prost(string, required, tag = "5")]
+    pub(crate) region_name: String,
+    #[prost(message, repeated, tag = "6")]
+    pub(crate) regions: Vec<Region>,
+    #[prost(string, optional, tag = "7")]
+    pub(crate) timezone: Option<String>,
+    #[prost(string, optional, tag = "8")]
+    pub(crate) country_name: Option<String>,
+    #[prost(uint64, optional, tag = "9")]
+    pub(crate) population: Option<u64>,
+    #[prost(double, optional, tag = "10")]
+    pub(crate) distance_km: Option<f64>,
+    #[prost(enumeration = "MatchedOn", optional, tag = "11")]
+    pub(crate) matched_on: Option<i32>,
+    #[prost(message, optional, tag = "12")]
+    pub(crate) resolved_coordinates: Option<Coordinates>,
+    #[prost(message, repeated, tag = "13")]
+    pub(crate) candidates: Vec<Candidate>,
+    #[prost(bool, optional, tag = "14")]
+    pub(crate) is_primary: Option<bool>,
+    #[prost(string, optional, tag = "15")]
+    pub(crate) display_name: Option<String>,
+    #[prost(uint64, optional, tag = "16")]
+    pub(crate) region_id: Option<u64>,
+    #[prost(string, optional, tag = "17")]
+    pub(crate) official_name: Option<String>,
+    #[prost(string, optional, tag = "18")]
+    pub(crate) common_name: Option<String>,
+}
+
+/// `MultiCity` message, see [crate::handlers::city::MultiCityResponse].
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct MultiCity {
+    #[prost(message, repeated, tag = "1")]
+    pub(crate) cities: Vec<City>,
+    #[prost(uint64, optional, tag = "2")]
+    pub(crate) total: Option<u64>,
+    #[prost(string, optional, tag = "3")]
+    pub(crate) next_cursor: Option<String>,
+    #[prost(bool, optional, tag = "4")]
+    pub(crate) partial: Option<bool>,
+    #[prost(string, repeated, tag = "5")]
+    pub(crate) suggestions: Vec<String>,
+}
+
+/// `Region` message, see [crate::handlers::region::RegionResponse].
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct Region {
+    #[prost(uint64, required, tag = "1")]
+    pub(crate) id: u64,
+    #[prost(string, required, tag = "2")]
+    pub(crate) country_iso: String,
+    #[prost(string, required, tag = "3")]
+    pub(crate) name: String,
+}
+
+/// `Candidate` message, see [crate::handlers::city::CandidateResponse].
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct Candidate {
+    #[prost(uint64, required, tag = "1")]
+    pub(crate) id: u64,
+    #[prost(string, required, tag = "2")]
+    pub(crate) name: String,
+    #[prost(double, required, tag = "3")]
+    pub(crate) distance_km: f64,
+    #[prost(double, optional, tag = "4")]
+    pub(crate) score: Option<f64>,
+}
+
+/// `Coordinates` message, see [crate::services::locations_repo::Coordinates].
+#[derive(Clone, Copy, PartialEq, Message)]
+pub(crate) struct Coordinates {
+    #[prost(double, required, tag = "1")]
+    pub(crate) lat: f64,
+    #[prost(double, required, tag = "2")]
+    pub(crate) lon: f64,
+}
+
+/// `MatchedOn` enum, see [crate::services::locations_repo::MatchedOn].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Enumeration)]
+#[repr(i32)]
+pub(crate) enum MatchedOn {
+    Name = 0,
+    Alias = 1,
+}
+
+impl From<locations_repo::Coordinates> for Coordinates {
+    fn from(coords: locations_repo::Coordinates) -> Self {
+        Self { lat: coords.lat, lon: coords.lon }
+    }
+}
+
+impl From<locations_repo::MatchedOn> for MatchedOn {
+    fn from(matched_on: locations_repo::MatchedOn) -> Self {
+        match matched_on {
+            locations_repo::MatchedOn::Name => Self::Name,
+            locations_repo::MatchedOn::Alias => Self::Alias,
+        }
+    }
+}
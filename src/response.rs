@@ -1,13 +1,16 @@
 //! OK and error response types to be used by endpoints.
 
-use okapi::openapi3::Responses;
+use crate::protobuf::EncodeProtobuf;
+use okapi::openapi3::{
+    MediaType as OpenApiMediaType, RefOr, Response as OpenApiResponse, Responses,
+};
 use rocket::{
     catch,
-    http::Status,
+    http::{ContentType, MediaType, Status},
     request::FormParseError,
     response,
     response::{status::Custom, Responder},
-    Request,
+    Request, Response,
 };
 use rocket_contrib::json::Json;
 use rocket_okapi::{
@@ -15,6 +18,12 @@ use rocket_okapi::{
     OpenApiError,
 };
 use serde::Serialize;
+use std::{
+    io::Cursor,
+    ops::{Deref, DerefMut},
+    time::Instant,
+};
+use time::Timespec;
 use validator::ValidationErrors;
 
 /// Convenience alias for [Result] whose error is [ErrorResponse], to be used by supportive code.
@@ -23,37 +32,95 @@ pub(crate) type HandlerResult<T> = Result<T, ErrorResponse>;
 /// Result type to be used by endpoints. Either OK [Json] or error [ErrorResponse].
 pub(crate) type JsonResult<T> = HandlerResult<Json<T>>;
 
+/// Result type to be used by endpoints that negotiate format of their response, see [Negotiated].
+pub(crate) type NegotiatedResult<T> = HandlerResult<Negotiated<T>>;
+
 /// Possible error endpoint responses.
-#[derive(Debug, thiserror::Error)]
+#[derive(Clone, Debug, thiserror::Error)]
 pub(crate) enum ErrorResponse {
     /// HTTP 400 Bad Request: client sent something wrong.
     #[error("Bad Request: {0}")]
     BadRequest(String),
+    /// HTTP 400 Bad Request: some fields sent by client are invalid. Has a summary message and
+    /// per-field details.
+    #[error("Bad Request: {0}")]
+    InvalidFields(String, Vec<FieldError>),
+    /// HTTP 403 Forbidden: client is not allowed to access this endpoint.
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
     /// HTTP 404 Not Found: this path or entity does not exist.
     #[error("Not Found: {0}")]
     NotFound(String),
+    /// HTTP 406 Not Acceptable: the response cannot be encoded in any format the client accepts.
+    #[error("Not Acceptable: {0}")]
+    NotAcceptable(String),
+    /// HTTP 413 Payload Too Large: client sent a request parameter over a size limit.
+    #[error("Payload Too Large: {0}")]
+    PayloadTooLarge(String),
+    /// HTTP 414 URI Too Long: client sent a query string over a size limit.
+    #[error("URI Too Long: {0}")]
+    UriTooLong(String),
     /// HTTP 500 Internal Server Error: something went real wrong on the server.
     #[error("Internal Server Error: {0}")]
     InternalServerError(String),
+    /// HTTP 501 Not Implemented: the server is not configured to support this endpoint.
+    #[error("Not Implemented: {0}")]
+    NotImplemented(String),
+    /// HTTP 503 Service Unavailable: the server could not handle the request in time.
+    #[error("Service Unavailable: {0}")]
+    ServiceUnavailable(String),
+    /// HTTP 503 Service Unavailable: a backend of the server is overloaded. Has a message and a
+    /// number of seconds after which the client should retry, sent in the `Retry-After` header.
+    #[error("Service Unavailable: {0}")]
+    Overloaded(String, u64),
+}
+
+/// Validation error of a single request field.
+#[derive(Clone, Debug, JsonSchema, Serialize)]
+pub(crate) struct FieldError {
+    /// Name of the invalid field, e.g. `"lat"`.
+    field: String,
+    /// Code of the failed validation, e.g. `"range"`.
+    code: String,
 }
 
 #[derive(JsonSchema, Serialize)]
 struct ErrorPayload {
     message: String,
+    /// Per-field validation errors, present only if some request fields are invalid.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<Vec<FieldError>>,
 }
 
 /// Make Rocket understand our error responses.
 impl<'r> Responder<'r> for ErrorResponse {
     fn respond_to(self, req: &Request<'_>) -> response::Result<'r> {
-        let http_status = match self {
-            Self::BadRequest(_) => Status::BadRequest,
-            Self::NotFound(_) => Status::NotFound,
-            Self::InternalServerError(_) => Status::InternalServerError,
+        let message = self.to_string();
+        let retry_after = match self {
+            Self::Overloaded(_, retry_after) => Some(retry_after),
+            _ => None,
+        };
+        let (http_status, errors) = match self {
+            Self::BadRequest(_) => (Status::BadRequest, None),
+            Self::InvalidFields(_, errors) => (Status::BadRequest, Some(errors)),
+            Self::Forbidden(_) => (Status::Forbidden, None),
+            Self::NotFound(_) => (Status::NotFound, None),
+            Self::NotAcceptable(_) => (Status::NotAcceptable, None),
+            Self::PayloadTooLarge(_) => (Status::PayloadTooLarge, None),
+            Self::UriTooLong(_) => (Status::UriTooLong, None),
+            Self::InternalServerError(_) => (Status::InternalServerError, None),
+            Self::NotImplemented(_) => (Status::NotImplemented, None),
+            Self::ServiceUnavailable(_) | Self::Overloaded(..) => {
+                (Status::ServiceUnavailable, None)
+            }
         };
 
-        let payload = ErrorPayload { message: self.to_string() };
-        let response = Custom(http_status, Json(payload));
-        response.respond_to(req)
+        let payload = ErrorPayload { message, errors };
+        let mut response = Custom(http_status, Json(payload)).respond_to(req)?;
+        if let Some(retry_after) = retry_after {
+            response.set_raw_header("Retry-After", retry_after.to_string());
+        }
+        Ok(response)
     }
 }
 
@@ -62,13 +129,263 @@ impl OpenApiResponder<'_> for ErrorResponse {
         // implementation stolen from rocket_okapi::response::responder_impls
         let mut responses = Responses::default();
         let schema = gen.json_schema::<ErrorPayload>();
-        for &status_code in &[400, 404, 500] {
+        for &status_code in &[400, 403, 404, 406, 413, 414, 500, 501, 503] {
             add_schema_response(&mut responses, status_code, "application/json", schema.clone())?;
         }
         Ok(responses)
     }
 }
 
+/// OK response that supports conditional requests based on modification time of its entity.
+///
+/// Sets `Last-Modified` header and responds with HTTP 304 Not Modified if the request has an
+/// `If-Modified-Since` header not older than the modification time. Sets `Cache-Control: no-cache`
+/// when the modification time is not known.
+#[derive(Debug)]
+pub(crate) struct WithLastModified<T> {
+    pub(crate) body: T,
+    /// Modification time of the response entity in seconds since Unix epoch, if known.
+    pub(crate) last_modified: Option<i64>,
+}
+
+impl<'r, T: Responder<'r>> Responder<'r> for WithLastModified<T> {
+    fn respond_to(self, req: &Request<'_>) -> response::Result<'r> {
+        let last_modified = match self.last_modified {
+            Some(last_modified) => last_modified,
+            None => {
+                return Response::build_from(self.body.respond_to(req)?)
+                    .raw_header("Cache-Control", "no-cache")
+                    .ok()
+            }
+        };
+        let http_date = time::at_utc(Timespec::new(last_modified, 0)).rfc822().to_string();
+
+        let if_modified_since = req
+            .headers()
+            .get_one("If-Modified-Since")
+            .and_then(|value| time::strptime(value, "%a, %d %b %Y %T GMT").ok())
+            .map(|tm| tm.to_timespec().sec);
+        if if_modified_since.map_or(false, |since| last_modified <= since) {
+            return Response::build()
+                .status(Status::NotModified)
+                .raw_header("Last-Modified", http_date)
+                .ok();
+        }
+
+        Response::build_from(self.body.respond_to(req)?).raw_header("Last-Modified", http_date).ok()
+    }
+}
+
+impl<'r, T: OpenApiResponder<'r>> OpenApiResponder<'r> for WithLastModified<T> {
+    fn responses(gen: &mut OpenApiGenerator) -> Result<Responses, OpenApiError> {
+        let mut responses = T::responses(gen)?;
+        let not_modified =
+            OpenApiResponse { description: "Not Modified".to_string(), ..Default::default() };
+        responses.responses.insert("304".to_string(), RefOr::Object(not_modified));
+        Ok(responses)
+    }
+}
+
+/// OK response that is either `T` or empty HTTP 204 No Content, for endpoints where "nothing
+/// found" is a valid result rather than an error.
+#[derive(Debug)]
+pub(crate) struct OrNoContent<T>(pub(crate) Option<T>);
+
+impl<'r, T: Responder<'r>> Responder<'r> for OrNoContent<T> {
+    fn respond_to(self, req: &Request<'_>) -> response::Result<'r> {
+        match self.0 {
+            Some(body) => body.respond_to(req),
+            None => Response::build().status(Status::NoContent).ok(),
+        }
+    }
+}
+
+impl<'r, T: OpenApiResponder<'r>> OpenApiResponder<'r> for OrNoContent<T> {
+    fn responses(gen: &mut OpenApiGenerator) -> Result<Responses, OpenApiError> {
+        let mut responses = T::responses(gen)?;
+        let no_content =
+            OpenApiResponse { description: "No Content".to_string(), ..Default::default() };
+        responses.responses.insert("204".to_string(), RefOr::Object(no_content));
+        Ok(responses)
+    }
+}
+
+/// Media type of Protocol Buffers responses.
+const PROTOBUF_MEDIA_TYPE: (&str, &str) = ("application", "x-protobuf");
+
+/// OK response encoded as JSON by default, or as a Protocol Buffers message if the client prefers
+/// `application/x-protobuf` in its `Accept` header. Responds with HTTP 406 Not Acceptable if the
+/// client accepts neither of them (`*/*` and `application/*` accept both). Sets `Vary: Accept`.
+///
+/// Like [Json], dereferences to the wrapped value so that handlers can amend it.
+#[derive(Debug)]
+pub(crate) struct Negotiated<T>(pub(crate) T);
+
+impl<T> Deref for Negotiated<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Negotiated<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<'r, T: EncodeProtobuf + Serialize> Responder<'r> for Negotiated<T> {
+    fn respond_to(self, req: &Request<'_>) -> response::Result<'r> {
+        let format = match negotiate_format(req) {
+            Some(format) => format,
+            None => {
+                return Response::build_from(not_acceptable(req).respond_to(req)?)
+                    .raw_header("Vary", "Accept")
+                    .ok();
+            }
+        };
+        if format == Format::Json {
+            return Response::build_from(Json(self.0).respond_to(req)?)
+                .raw_header("Vary", "Accept")
+                .ok();
+        }
+
+        let (top, sub) = PROTOBUF_MEDIA_TYPE;
+        let body = self.0.encode_protobuf();
+        Response::build()
+            .header(ContentType(MediaType::new(top, sub)))
+            .raw_header("Vary", "Accept")
+            .sized_body(Cursor::new(body))
+            .ok()
+    }
+}
+
+/// Format of [Negotiated] responses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Json,
+    Protobuf,
+}
+
+/// Choose format of response to `req` from its `Accept` header: the one accepted with the highest
+/// weight, the first one listed on a tie. JSON if there is no (valid) `Accept` header, [None] if
+/// no supported format is accepted.
+fn negotiate_format(req: &Request<'_>) -> Option<Format> {
+    let accept = match req.accept() {
+        Some(accept) => accept,
+        None => return Some(Format::Json),
+    };
+
+    let mut best: Option<(f32, Format)> = None;
+    for media_type in accept.iter() {
+        let weight = media_type.weight_or(1.0);
+        let format = match media_format(media_type.media_type()) {
+            Some(format) => format,
+            None => continue,
+        };
+        if weight > 0.0 && best.map_or(true, |(best_weight, _)| weight > best_weight) {
+            best = Some((weight, format));
+        }
+    }
+    best.map(|(_, format)| format)
+}
+
+/// Format of responses acceptable as `media_type` from an `Accept` header, JSON for wildcards.
+fn media_format(media_type: &MediaType) -> Option<Format> {
+    let (protobuf_top, protobuf_sub) = PROTOBUF_MEDIA_TYPE;
+    let (top, sub) = (media_type.top(), media_type.sub());
+    if top == "*" || (top == "application" && (sub == "*" || sub == "json")) {
+        Some(Format::Json)
+    } else if top == protobuf_top && sub == protobuf_sub {
+        Some(Format::Protobuf)
+    } else {
+        None
+    }
+}
+
+/// Whether the client accepts JSON responses according to `Accept` header of `req`, also if there
+/// is no (valid) `Accept` header. Used for endpoints that respond only with JSON.
+pub(crate) fn accepts_json(req: &Request<'_>) -> bool {
+    req.accept().map_or(true, |accept| {
+        accept.iter().any(|media_type| {
+            let format = media_format(media_type.media_type());
+            media_type.weight_or(1.0) > 0.0 && format == Some(Format::Json)
+        })
+    })
+}
+
+/// HTTP 406 Not Acceptable error for `req` whose `Accept` header allows no format of the response.
+pub(crate) fn not_acceptable(req: &Request<'_>) -> ErrorResponse {
+    let accept = req.headers().get("Accept").collect::<Vec<_>>().join(", ");
+    ErrorResponse::NotAcceptable(format!("Cannot respond with any of `{}`.", accept))
+}
+
+impl<'r, T: JsonSchema + Serialize> OpenApiResponder<'r> for Negotiated<T> {
+    fn responses(gen: &mut OpenApiGenerator) -> Result<Responses, OpenApiError> {
+        let mut responses = <Json<T> as OpenApiResponder<'r>>::responses(gen)?;
+        if let Some(RefOr::Object(ok)) = responses.responses.get_mut("200") {
+            let (top, sub) = PROTOBUF_MEDIA_TYPE;
+            ok.content.insert(format!("{}/{}", top, sub), OpenApiMediaType::default());
+        }
+        Ok(responses)
+    }
+}
+
+/// OK response with an optional `Warning` header telling the client that the response is not
+/// what it may expect, e.g. that it is capped.
+#[derive(Debug)]
+pub(crate) struct WithWarning<T> {
+    pub(crate) body: T,
+    /// Human-readable warning text without double quotes, sent with the "miscellaneous" code 199.
+    pub(crate) warning: Option<String>,
+}
+
+impl<'r, T: Responder<'r>> Responder<'r> for WithWarning<T> {
+    fn respond_to(self, req: &Request<'_>) -> response::Result<'r> {
+        let mut response = self.body.respond_to(req)?;
+        if let Some(warning) = self.warning {
+            response.set_raw_header("Warning", format!("199 - \"{}\"", warning));
+        }
+        Ok(response)
+    }
+}
+
+impl<'r, T: OpenApiResponder<'r>> OpenApiResponder<'r> for WithWarning<T> {
+    fn responses(gen: &mut OpenApiGenerator) -> Result<Responses, OpenApiError> {
+        T::responses(gen)
+    }
+}
+
+/// OK response with an optional `Server-Timing` header listing durations of phases of handling
+/// the request, completed by duration of serializing the response itself.
+#[derive(Debug)]
+pub(crate) struct WithServerTiming<T> {
+    pub(crate) body: T,
+    /// Pairs of phase name and its duration in milliseconds, [None] not to send the header.
+    pub(crate) phases: Option<Vec<(&'static str, f64)>>,
+}
+
+impl<'r, T: Responder<'r>> Responder<'r> for WithServerTiming<T> {
+    fn respond_to(self, req: &Request<'_>) -> response::Result<'r> {
+        let started = Instant::now();
+        let mut response = self.body.respond_to(req)?;
+        if let Some(mut phases) = self.phases {
+            phases.push(("serialize", started.elapsed().as_micros() as f64 / 1000.0));
+            let metrics: Vec<_> =
+                phases.iter().map(|(name, millis)| format!("{};dur={}", name, millis)).collect();
+            response.set_raw_header("Server-Timing", metrics.join(", "));
+        }
+        Ok(response)
+    }
+}
+
+impl<'r, T: OpenApiResponder<'r>> OpenApiResponder<'r> for WithServerTiming<T> {
+    fn responses(gen: &mut OpenApiGenerator) -> Result<Responses, OpenApiError> {
+        T::responses(gen)
+    }
+}
+
 /// Convert Elasticsearch errors into internal server errors.
 impl From<elasticsearch::Error> for ErrorResponse {
     fn from(err: elasticsearch::Error) -> Self {
@@ -76,10 +393,21 @@ impl From<elasticsearch::Error> for ErrorResponse {
     }
 }
 
-/// Convert from [validator] errors into bad requests.
+/// Convert from [validator] errors into bad requests with per-field details.
 impl From<ValidationErrors> for ErrorResponse {
     fn from(err: ValidationErrors) -> Self {
-        Self::BadRequest(err.to_string())
+        let mut errors: Vec<FieldError> = err
+            .field_errors()
+            .into_iter()
+            .flat_map(|(field, errors)| {
+                errors
+                    .iter()
+                    .map(move |e| FieldError { field: field.to_string(), code: e.code.to_string() })
+            })
+            .collect();
+        errors.sort_by(|a, b| a.field.cmp(&b.field)); // Fields come from a HashMap, make it stable.
+
+        Self::InvalidFields(err.to_string(), errors)
     }
 }
 
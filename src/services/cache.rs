@@ -0,0 +1,234 @@
+//! In-memory caches sharing instrumentation, so that their efficiency can be monitored.
+
+use dashmap::DashMap;
+use rocket_okapi::JsonSchema;
+use serde::Serialize;
+use std::{
+    hash::Hash,
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+    time::{Duration, Instant},
+};
+
+/// Source of current time of caches with expiring entries. Injected into caches so that their
+/// expiry can be driven by a fake clock instead of waiting for the wall clock.
+pub(crate) trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// The real (monotonic) clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Counters of cache events, updated by cache implementations.
+#[derive(Debug)]
+pub(crate) struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl CacheCounters {
+    pub(crate) const fn new() -> Self {
+        Self { hits: AtomicU64::new(0), misses: AtomicU64::new(0), evictions: AtomicU64::new(0) }
+    }
+
+    /// Record a lookup that was served by the cache.
+    pub(crate) fn hit(&self) {
+        self.hits.fetch_add(1, Relaxed);
+    }
+
+    /// Record a lookup that was not served by the cache.
+    pub(crate) fn miss(&self) {
+        self.misses.fetch_add(1, Relaxed);
+    }
+
+    /// Record eviction of `count` entries.
+    pub(crate) fn evicted(&self, count: usize) {
+        self.evictions.fetch_add(count as u64, Relaxed);
+    }
+}
+
+/// Metrics of a single cache at a point in time.
+#[allow(non_snake_case)]
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct CacheMetrics {
+    /// Name of the cache, e.g. `"region"`.
+    name: &'static str,
+    /// Number of lookups served by the cache since start of the service.
+    hits: u64,
+    /// Number of lookups not served by the cache since start of the service.
+    misses: u64,
+    /// Number of entries evicted from the cache since start of the service.
+    evictions: u64,
+    /// Current number of entries in the cache.
+    size: usize,
+    /// Ratio of hits to all lookups, e.g. `0.95`. Absent if there were no lookups yet.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hitRatio: Option<f64>,
+}
+
+/// A cache whose metrics are exported by the `/metrics` endpoint.
+pub(crate) trait InstrumentedCache {
+    /// Name of the cache in metrics.
+    fn name(&self) -> &'static str;
+
+    /// Counters of events of the cache.
+    fn counters(&self) -> &CacheCounters;
+
+    /// Current number of entries in the cache.
+    fn size(&self) -> usize;
+
+    /// Take a snapshot of metrics of the cache.
+    fn metrics(&self) -> CacheMetrics {
+        let counters = self.counters();
+        let (hits, misses) = (counters.hits.load(Relaxed), counters.misses.load(Relaxed));
+        let lookups = hits + misses;
+        CacheMetrics {
+            name: self.name(),
+            hits,
+            misses,
+            evictions: counters.evictions.load(Relaxed),
+            size: self.size(),
+            hitRatio: if lookups > 0 { Some(hits as f64 / lookups as f64) } else { None },
+        }
+    }
+}
+
+/// Unbounded map cache shared by all threads, for entities that change rarely. Entries optionally
+/// expire after a time-to-live measured by clock `C`.
+#[derive(Debug)]
+pub(crate) struct MapCache<K: Eq + Hash, V, C: Clock = SystemClock> {
+    name: &'static str,
+    /// Values with the time of their insertion.
+    map: DashMap<K, (V, Instant)>,
+    ttl: Option<Duration>,
+    clock: C,
+    counters: CacheCounters,
+}
+
+impl<K: Eq + Hash, V: Clone, C: Clock> MapCache<K, V, C> {
+    /// Create a cache whose entries expire `ttl` after their insertion, or never if [None].
+    pub(crate) fn new(name: &'static str, ttl: Option<Duration>, clock: C) -> Self {
+        Self { name, map: DashMap::new(), ttl, clock, counters: CacheCounters::new() }
+    }
+
+    /// Get a clone of value cached under `key`, if any. An expired value is evicted and not
+    /// returned.
+    pub(crate) fn get(&self, key: &K) -> Option<V> {
+        // Clone out of the map so that its lock is released before a possible eviction.
+        let entry = self.map.get(key).map(|record| record.value().clone());
+        let value = match entry {
+            Some((_, inserted_at)) if self.is_expired(inserted_at) => {
+                // Don't evict a fresh value inserted concurrently in the meantime.
+                if self.map.remove_if(key, |_, (_, it)| *it == inserted_at).is_some() {
+                    self.counters.evicted(1);
+                }
+                None
+            }
+            entry => entry.map(|(value, _)| value),
+        };
+        match value {
+            Some(_) => self.counters.hit(),
+            None => self.counters.miss(),
+        }
+        value
+    }
+
+    pub(crate) fn insert(&self, key: K, value: V) {
+        self.map.insert(key, (value, self.clock.now()));
+    }
+
+    fn is_expired(&self, inserted_at: Instant) -> bool {
+        self.ttl.map_or(false, |ttl| self.clock.now() >= inserted_at + ttl)
+    }
+
+    /// Evict all entries, return their number.
+    pub(crate) fn clear(&self) -> usize {
+        let count = self.map.len();
+        self.map.clear();
+        self.counters.evicted(count);
+        count
+    }
+}
+
+impl<K: Eq + Hash, V, C: Clock> InstrumentedCache for MapCache<K, V, C> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn counters(&self) -> &CacheCounters {
+        &self.counters
+    }
+
+    fn size(&self) -> usize {
+        self.map.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// Clock that stands still until advanced explicitly.
+    struct FakeClock(Cell<Instant>);
+
+    impl FakeClock {
+        fn advance(&self, duration: Duration) {
+            self.0.set(self.0.get() + duration);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    fn cache(ttl: Option<Duration>) -> MapCache<u64, &'static str, FakeClock> {
+        MapCache::new("test", ttl, FakeClock(Cell::new(Instant::now())))
+    }
+
+    #[test]
+    fn entries_expire_after_ttl() {
+        let cache = cache(Some(Duration::from_secs(60)));
+        cache.insert(1, "one");
+        assert_eq!(cache.get(&1), Some("one"));
+
+        cache.clock.advance(Duration::from_secs(59));
+        assert_eq!(cache.get(&1), Some("one"));
+
+        cache.clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.size(), 0);
+
+        let metrics = cache.metrics();
+        assert_eq!((metrics.hits, metrics.misses, metrics.evictions), (2, 1, 1));
+    }
+
+    #[test]
+    fn reinserted_entries_live_for_another_ttl() {
+        let cache = cache(Some(Duration::from_secs(60)));
+        cache.insert(1, "one");
+        cache.clock.advance(Duration::from_secs(50));
+        cache.insert(1, "uno");
+        cache.clock.advance(Duration::from_secs(50));
+        assert_eq!(cache.get(&1), Some("uno"));
+    }
+
+    #[test]
+    fn entries_without_ttl_never_expire() {
+        let cache = cache(None);
+        cache.insert(1, "one");
+        cache.clock.advance(Duration::from_secs(365 * 24 * 3600));
+        assert_eq!(cache.get(&1), Some("one"));
+        assert_eq!(cache.clear(), 1);
+        assert_eq!(cache.get(&1), None);
+    }
+}
@@ -0,0 +1,182 @@
+//! Locale-aware collation (alphabetical ordering) of city and region names, so that all endpoints
+//! sorting by name agree with how a native speaker of the requested language orders them.
+//!
+//! Supported collation locales (named as in ICU) and their specifics:
+//! - `cs` (Czech): `č`, `ch`, `ř`, `š` and `ž` are separate letters sorted after `c`, `h`, `r`,
+//!   `s` and `z` respectively, other accented letters sort with their base letter.
+//! - `de` (German, DIN 5007-1): umlauts sort with their base vowel, `ß` as `ss`.
+//! - `en` (ICU root): accented letters sort with their base letter.
+//! - `pl` (Polish): `ą`, `ć`, `ę`, `ł`, `ń`, `ó`, `ś`, `ź` and `ż` are separate letters sorted after
+//!   their base letter.
+//! - `sk` (Slovak): `ä`, `č`, `ch`, `ô`, `š` and `ž` are separate letters sorted after `a`, `c`, `h`,
+//!   `o`, `s` and `z` respectively, other accented letters sort with their base letter.
+
+use crate::services::locations_repo::Language;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Collation locale used to sort names, see the [module documentation](self).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Collation {
+    Cs,
+    De,
+    En,
+    Pl,
+    Sk,
+}
+
+impl Collation {
+    /// Collation used to sort names localized to `language`.
+    pub(crate) fn for_language(language: Language) -> Self {
+        match language {
+            Language::CS => Collation::Cs,
+            Language::DE => Collation::De,
+            Language::EN => Collation::En,
+            Language::PL => Collation::Pl,
+            Language::SK => Collation::Sk,
+        }
+    }
+
+    /// Letters (lowercase, possibly multi-character) that sort as separate letters right after
+    /// the given base letter.
+    fn extra_letters(self) -> &'static [(&'static str, char)] {
+        match self {
+            Collation::Cs => &[("č", 'c'), ("ch", 'h'), ("ř", 'r'), ("š", 's'), ("ž", 'z')],
+            Collation::De | Collation::En => &[],
+            Collation::Pl => &[
+                ("ą", 'a'),
+                ("ć", 'c'),
+                ("ę", 'e'),
+                ("ł", 'l'),
+                ("ń", 'n'),
+                ("ó", 'o'),
+                ("ś", 's'),
+                ("ź", 'z'),
+                ("ż", 'z'),
+            ],
+            Collation::Sk => {
+                &[("ä", 'a'), ("č", 'c'), ("ch", 'h'), ("ô", 'o'), ("š", 's'), ("ž", 'z')]
+            }
+        }
+    }
+
+    /// Key by which `name` sorts in this collation. Names that collate equally are ordered by
+    /// their lowercase and then exact form, so that the order is deterministic.
+    pub(crate) fn sort_key(self, name: &str) -> (Vec<u32>, String, String) {
+        let lowercase: String = name.nfc().flat_map(char::to_lowercase).collect();
+        let extra_letters = self.extra_letters();
+
+        let mut weights = Vec::with_capacity(lowercase.len());
+        let mut rest = lowercase.as_str();
+        while let Some(c) = rest.chars().next() {
+            // Prefer longest match so that e.g. Czech `ch` wins over `c`.
+            let extra = extra_letters
+                .iter()
+                .enumerate()
+                .filter(|(_, (letter, _))| rest.starts_with(letter))
+                .max_by_key(|(_, (letter, _))| letter.len());
+            match extra {
+                // Index in the list keeps extra letters with the same base letter in order.
+                Some((index, (letter, base))) => {
+                    weights.push(letter_weight(*base) + 1 + index as u32);
+                    rest = &rest[letter.len()..];
+                }
+                None => {
+                    match c {
+                        'ß' => weights.extend(&[letter_weight('s'), letter_weight('s')]),
+                        // Stroke is not a combining mark, NFD keeps the letter whole.
+                        'ł' => weights.push(letter_weight('l')),
+                        c => weights.extend(
+                            c.nfd().filter(|&it| !is_combining_mark(it)).map(letter_weight),
+                        ),
+                    }
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        }
+
+        (weights, lowercase, name.to_string())
+    }
+}
+
+/// Primary weight of (lowercase, unaccented) character `c`. Letters `a`-`z` are spaced so that
+/// extra letters of any collation fit right after their base letter. Other characters (spaces,
+/// punctuation, digits) sort before letters, and letters of other scripts after them.
+fn letter_weight(c: char) -> u32 {
+    const LETTERS_START: u32 = 0x1_0000;
+    const LETTER_SPACING: u32 = 16;
+
+    match c {
+        'a'..='z' => LETTERS_START + (c as u32 - 'a' as u32) * LETTER_SPACING,
+        c if c.is_alphabetic() => LETTERS_START + 26 * LETTER_SPACING + c as u32,
+        c => c as u32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(collation: Collation, names: &[&'static str]) -> Vec<&'static str> {
+        let mut names = names.to_vec();
+        names.sort_by_cached_key(|name| collation.sort_key(name));
+        names
+    }
+
+    #[test]
+    fn czech_ch_sorts_after_h() {
+        let names = ["Chrudim", "Ivančice", "Hradec Králové", "Cheb", "Cvikov", "Hustopeče"];
+        assert_eq!(
+            sorted(Collation::Cs, &names),
+            vec!["Cvikov", "Hradec Králové", "Hustopeče", "Cheb", "Chrudim", "Ivančice"]
+        );
+        assert_eq!(
+            sorted(Collation::En, &names),
+            vec!["Cheb", "Chrudim", "Cvikov", "Hradec Králové", "Hustopeče", "Ivančice"]
+        );
+    }
+
+    #[test]
+    fn czech_caron_letters_sort_after_base_letter() {
+        let names = ["Dačice", "Čáslav", "Cvikov", "Říčany", "Rychnov", "Žatec", "Zlín"];
+        assert_eq!(
+            sorted(Collation::Cs, &names),
+            vec!["Cvikov", "Čáslav", "Dačice", "Rychnov", "Říčany", "Zlín", "Žatec"]
+        );
+    }
+
+    #[test]
+    fn slovak_diacritics() {
+        let names = ["Príbelce", "Pôtor", "Pozba", "Čadca", "Cífer", "Chynorany", "Hurbanovo"];
+        assert_eq!(
+            sorted(Collation::Sk, &names),
+            vec!["Cífer", "Čadca", "Hurbanovo", "Chynorany", "Pozba", "Pôtor", "Príbelce"]
+        );
+        // Czech has no `ô`, it sorts with `o`.
+        assert_eq!(sorted(Collation::Cs, &["Pozba", "Pôtor"]), vec!["Pôtor", "Pozba"]);
+    }
+
+    #[test]
+    fn polish_diacritics() {
+        let names = ["Mława", "Łódź", "Lublin", "Dąbrowa", "Ćmielów", "Cieszyn", "Żory", "Zamość"];
+        assert_eq!(
+            sorted(Collation::Pl, &names),
+            vec!["Cieszyn", "Ćmielów", "Dąbrowa", "Lublin", "Łódź", "Mława", "Zamość", "Żory"]
+        );
+        assert_eq!(sorted(Collation::En, &["Lublin", "Łódź"]), vec!["Łódź", "Lublin"]);
+    }
+
+    #[test]
+    fn german_umlauts_and_sharp_s() {
+        let names = ["Mz", "Müller", "Ma", "Strasse", "Straße", "Strasbourg"];
+        assert_eq!(
+            sorted(Collation::De, &names),
+            vec!["Ma", "Müller", "Mz", "Strasbourg", "Strasse", "Straße"]
+        );
+    }
+
+    #[test]
+    fn ties_are_broken_by_case_and_exact_name() {
+        let names = ["praha", "Praha", "PRAHA"];
+        assert_eq!(sorted(Collation::Cs, &names), vec!["PRAHA", "Praha", "praha"]);
+    }
+}
@@ -0,0 +1,54 @@
+//! Built-in table of localized country names.
+
+use crate::services::locations_repo::Language;
+
+/// Localized names of countries keyed by ISO 3166-1 alpha-2 code. Names are in order of
+/// [Language] variants: `cs`, `de`, `en`, `pl`, `sk`.
+const COUNTRY_NAMES: &[(&str, [&str; 5])] = &[
+    ("AT", ["Rakousko", "Österreich", "Austria", "Austria", "Rakúsko"]),
+    ("CH", ["Švýcarsko", "Schweiz", "Switzerland", "Szwajcaria", "Švajčiarsko"]),
+    ("CZ", ["Česko", "Tschechien", "Czechia", "Czechy", "Česko"]),
+    ("DE", ["Německo", "Deutschland", "Germany", "Niemcy", "Nemecko"]),
+    ("FR", ["Francie", "Frankreich", "France", "Francja", "Francúzsko"]),
+    (
+        "GB",
+        [
+            "Spojené království",
+            "Vereinigtes Königreich",
+            "United Kingdom",
+            "Wielka Brytania",
+            "Spojené kráľovstvo",
+        ],
+    ),
+    ("HR", ["Chorvatsko", "Kroatien", "Croatia", "Chorwacja", "Chorvátsko"]),
+    ("HU", ["Maďarsko", "Ungarn", "Hungary", "Węgry", "Maďarsko"]),
+    ("IT", ["Itálie", "Italien", "Italy", "Włochy", "Taliansko"]),
+    ("NL", ["Nizozemsko", "Niederlande", "Netherlands", "Holandia", "Holandsko"]),
+    ("PL", ["Polsko", "Polen", "Poland", "Polska", "Poľsko"]),
+    ("SI", ["Slovinsko", "Slowenien", "Slovenia", "Słowenia", "Slovinsko"]),
+    ("SK", ["Slovensko", "Slowakei", "Slovakia", "Słowacja", "Slovensko"]),
+    ("UA", ["Ukrajina", "Ukraine", "Ukraine", "Ukraina", "Ukrajina"]),
+];
+
+/// Get name of country given by its ISO code localized to `language`. Falls back to the ISO code
+/// itself for countries not in the built-in table (including custom 4-letter codes).
+pub(crate) fn country_name(iso_code: &str, language: Language) -> &str {
+    let index = match language {
+        Language::CS => 0,
+        Language::DE => 1,
+        Language::EN => 2,
+        Language::PL => 3,
+        Language::SK => 4,
+    };
+
+    match COUNTRY_NAMES.iter().find(|(iso, _)| *iso == iso_code) {
+        Some((_, names)) => names[index],
+        None => iso_code,
+    }
+}
+
+/// Whether `iso_code` is well-formed: an ISO 3166-1 alpha-2 code or a custom 4-letter code, in
+/// upper case. The country need not be in the built-in table.
+pub(crate) fn is_valid_iso_code(iso_code: &str) -> bool {
+    matches!(iso_code.len(), 2 | 4) && iso_code.bytes().all(|b| b.is_ascii_uppercase())
+}
@@ -1,36 +1,65 @@
 //! Stateless Locations repository backed by Elasticsearch.
 
 use crate::{
+    config::{DEFAULT_COORDINATES_DECIMALS, DEFAULT_DISTANCE_KM_DECIMALS},
+    fairings::es_took,
     response::{
-        ErrorResponse::{InternalServerError, NotFound},
+        ErrorResponse::{
+            BadRequest, InternalServerError, NotFound, Overloaded, ServiceUnavailable,
+        },
         HandlerResult,
     },
+    services::{
+        cache::{CacheCounters, CacheMetrics, InstrumentedCache, MapCache, SystemClock},
+        query_templates::{self, QueryKind},
+    },
     stateful::elasticsearch::WithElastic,
 };
-use dashmap::DashMap;
 use elasticsearch::{
-    http::{response::Response as EsResponse, StatusCode},
-    Error as EsError,
+    http::{request::JsonBody, response::Response as EsResponse, StatusCode},
+    indices::{IndicesGetMappingParts, IndicesGetParts},
+    CountParts, Elasticsearch, Error as EsError,
     GetParts::IndexTypeId,
+    MsearchParts,
     SearchParts::Index,
 };
-use log::{debug, error};
-use once_cell::sync::Lazy;
-use rocket::FromFormValue;
+use futures::future::{Future, FutureExt, LocalBoxFuture, Shared};
+use log::{debug, error, info, warn};
+use once_cell::sync::{Lazy, OnceCell};
+use rocket::{http::RawStr, request::FromFormValue, FromFormValue};
 use rocket_okapi::JsonSchema;
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize, Serializer};
 use serde_json::{json, to_string_pretty, Value as JsonValue};
-use single::Single;
-use std::{collections::HashMap, fmt};
+use single::{Error as SingleError, Single};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt,
+    hash::Hash,
+    rc::Rc,
+    str::FromStr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed},
+    thread::LocalKey,
+    time::Duration,
+};
+use tokio::time::delay_for;
 use validator::Validate;
 
 const REGION_INDEX: &str = "region";
 const CITY_INDEX: &str = "city";
-const EXCLUDED_FIELDS: &[&str] = &["geometry", "population"];
+/// Maximum number of countries returned by [LocationsRepository::get_country_isos()].
+const MAX_COUNTRIES: usize = 1000;
+/// Maximum number of suggestions returned by [LocationsRepository::suggest_city_names()].
+const MAX_SUGGESTIONS: usize = 3;
+/// Time after which the cached list of countries is fetched again, so that new countries appear.
+const COUNTRY_ISOS_CACHE_TTL: Duration = Duration::from_secs(3600);
+/// Maximum number of levels of region hierarchy, guards against cycles in parent regions.
+const MAX_REGION_HIERARCHY_DEPTH: usize = 8;
 
 /// Language for response localization. Serialized as two-letter ISO 639-1 lowercase language code.
+/// The `language` query parameter can also be given as `lang`.
 #[serde(rename_all = "lowercase")] // Not used by Rocket itself, but *is* used by rocket_okapi.
-#[derive(Clone, Copy, Debug, FromFormValue, JsonSchema)]
+#[derive(Clone, Copy, Debug, Eq, FromFormValue, Hash, JsonSchema, PartialEq)]
 pub(crate) enum Language {
     CS,
     DE,
@@ -40,13 +69,98 @@ pub(crate) enum Language {
 }
 
 impl Language {
+    /// All supported languages.
+    pub(crate) const ALL: [Language; 5] =
+        [Language::CS, Language::DE, Language::EN, Language::PL, Language::SK];
+
+    /// Canonical two-letter lowercase code of the language, e.g. `cs`.
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Language::CS => "cs",
+            Language::DE => "de",
+            Language::EN => "en",
+            Language::PL => "pl",
+            Language::SK => "sk",
+        }
+    }
+
     pub(crate) fn name_key(self) -> String {
-        format!("name.{:?}", self).to_lowercase()
+        format!("name.{}", self)
+    }
+
+    /// Key of the official name in this language, which index documents may carry in addition
+    /// to the (common) name under [Language::name_key()].
+    pub(crate) fn official_name_key(self) -> String {
+        format!("officialName.{}", self)
+    }
+}
+
+impl fmt::Display for Language {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Parse the two-letter code as produced by [Language::as_str()], ignoring case like query
+/// parameters do.
+impl FromStr for Language {
+    type Err = String;
+
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        let language = Self::ALL.iter().find(|it| it.as_str().eq_ignore_ascii_case(code));
+        language.copied().ok_or_else(|| format!("Unsupported language `{}`.", code))
+    }
+}
+
+/// Id of a City entity. Serialized as a plain integer.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, JsonSchema, PartialEq, Serialize)]
+pub(crate) struct CityId(pub(crate) u64);
+
+/// Id of a Region entity. Serialized as a plain integer.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, JsonSchema, PartialEq, Serialize)]
+pub(crate) struct RegionId(pub(crate) u64);
+
+impl fmt::Display for CityId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for RegionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Make ids usable as fields of Rocket query structs, parsed the same way as plain integers.
+impl<'v> FromFormValue<'v> for CityId {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Self, Self::Error> {
+        u64::from_form_value(form_value).map(Self)
+    }
+}
+
+impl<'v> FromFormValue<'v> for RegionId {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Self, Self::Error> {
+        u64::from_form_value(form_value).map(Self)
     }
 }
 
+/// Number of decimal places of coordinates in API responses, set by [init_response_precision()].
+static COORDINATES_DECIMALS: OnceCell<i32> = OnceCell::new();
+
+/// Number of decimal places of distances in kilometres in API responses, set by
+/// [init_response_precision()].
+static DISTANCE_KM_DECIMALS: OnceCell<i32> = OnceCell::new();
+
 /// Simple structure to represent a geo point, with latitude and longitude in decimal degrees.
-#[derive(Clone, Copy, Debug, Deserialize, Serialize, Validate)]
+///
+/// Ranges of coordinates are declared using [Validate] and checked explicitly on all client input
+/// (query parameters, request bodies and headers), but not when loading from Elasticsearch.
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Serialize, Validate)]
 pub(crate) struct Coordinates {
     #[validate(range(min = -90.0, max = 90.0))]
     pub(crate) lat: f64,
@@ -55,265 +169,1961 @@ pub(crate) struct Coordinates {
 }
 
 impl Coordinates {
+    /// Great-circle distance to `other` in kilometres, computed using the haversine formula.
+    pub(crate) fn distance_km(self, other: Coordinates) -> f64 {
+        const EARTH_RADIUS_KM: f64 = 6371.0;
+
+        let (lat1, lat2) = (self.lat.to_radians(), other.lat.to_radians());
+        let (half_d_lat, half_d_lon) =
+            ((lat2 - lat1) / 2.0, (other.lon - self.lon).to_radians() / 2.0);
+        let a = half_d_lat.sin().powi(2) + lat1.cos() * lat2.cos() * half_d_lon.sin().powi(2);
+        2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+
+    /// These coordinates rounded to [COORDINATES_DECIMALS] for API responses.
+    pub(crate) fn rounded(self) -> Self {
+        Self {
+            lat: round_to_decimals(self.lat, coordinates_decimals()),
+            lon: round_to_decimals(self.lon, coordinates_decimals()),
+        }
+    }
+
     /// Return [GeoJSON](http://geojson.org) representation of these coordinates as [serde_json::Value].
     fn geojson(self) -> JsonValue {
         json!({"type": "Point", "coordinates": [self.lon, self.lat]}) // Yes, it is [lon, lat].
     }
 }
 
-/// Repository of Elastic City, Region Locations entities. Thin wrapper around app state.
-pub(crate) struct LocationsElasticRepository<'a, S: WithElastic>(pub(crate) &'a S);
+/// Round distance in kilometres to [DISTANCE_KM_DECIMALS] for API responses.
+pub(crate) fn round_distance_km(distance_km: f64) -> f64 {
+    let decimals = DISTANCE_KM_DECIMALS.get().copied().unwrap_or(DEFAULT_DISTANCE_KM_DECIMALS);
+    round_to_decimals(distance_km, decimals)
+}
 
-// Actual implementation of Locations repository on any app state that impleents [WithElasticsearch].
-impl<S: WithElastic> LocationsElasticRepository<'_, S> {
-    /// Get [ElasticCity] from Elasticsearch given its `id`. Async.
-    pub(crate) async fn get_city(&self, id: u64) -> HandlerResult<ElasticCity> {
-        self.get_entity(id, CITY_INDEX, "City").await
-    }
+/// Value of [COORDINATES_DECIMALS], the default one if not set.
+fn coordinates_decimals() -> i32 {
+    COORDINATES_DECIMALS.get().copied().unwrap_or(DEFAULT_COORDINATES_DECIMALS)
+}
+
+/// Serialize distance in kilometres rounded using [round_distance_km], for `serialize_with`.
+pub(crate) fn serialize_distance_km<S: Serializer>(
+    distance_km: &f64,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(round_distance_km(*distance_km))
+}
+
+/// Serialize optional distance in kilometres rounded using [round_distance_km], for
+/// `serialize_with`.
+pub(crate) fn serialize_opt_distance_km<S: Serializer>(
+    distance_km: &Option<f64>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    distance_km.map(round_distance_km).serialize(serializer)
+}
+
+/// Serialize optional coordinates rounded using [Coordinates::rounded], for `serialize_with`.
+pub(crate) fn serialize_opt_coordinates<S: Serializer>(
+    coords: &Option<Coordinates>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    coords.map(Coordinates::rounded).serialize(serializer)
+}
+
+fn round_to_decimals(value: f64, decimals: i32) -> f64 {
+    let factor = 10_f64.powi(decimals);
+    (value * factor).round() / factor
+}
+
+/// Tunable weights of scoring used to find a city for approximate (e.g. IP-based) coordinates.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ApproximateScoring {
+    /// Distance at which the distance score decays to a half, in kilometres. Cities farther than
+    /// 4 times this distance are not considered at all.
+    pub(crate) distance_scale_km: f64,
+    /// Score multiplier of featured cities.
+    pub(crate) featured_boost: f64,
+    /// Factor applied to city population before taking its logarithm: cities score
+    /// `ln(2 + population_factor * population)`.
+    pub(crate) population_factor: f64,
+}
+
+/// Options of city search, see [LocationsRepository::search()].
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct SearchOptions {
+    /// Fill [ElasticCity::matched_on] of returned cities.
+    pub(crate) match_info: bool,
+    /// Match names in each supported language almost as well as names in the requested language.
+    pub(crate) cross_language: bool,
+    /// Match names only with the diacritics of the query, e.g. "Plzen" doesn't match "Plzeň".
+    pub(crate) exact: bool,
+    /// Score added to cities whose localized name starts with the query, so that prefix matches
+    /// rank above matches elsewhere in the name. Zero turns the boost off.
+    pub(crate) prefix_boost: f64,
+    /// Boost of cities whose localized name contains all words of a multi-word query next to each
+    /// other in the same order. Zero turns the boost off.
+    pub(crate) phrase_boost: f64,
+    /// Score added to featured cities, so that they rank above other cities of similar relevance.
+    /// Zero turns the boost off.
+    pub(crate) featured_boost: f64,
+    /// Collapse cities that are duplicates of the same real city, keeping the most relevant one.
+    /// Duplicates share [ElasticCity::canonicalId] if they have it, otherwise their localized name
+    /// and country.
+    pub(crate) dedupe: bool,
+    /// Minimum relevance score of returned cities. Zero turns the filter off.
+    pub(crate) min_score: f64,
+    /// Maximum number of cities to return.
+    pub(crate) size: usize,
+}
+
+/// Entities found by a full-text search, see [LocationsRepository::search()].
+#[derive(Debug)]
+pub(crate) struct SearchResults<T> {
+    pub(crate) items: Vec<T>,
+    /// Whether Elasticsearch stopped the search early due to configured `timeout` or
+    /// `terminate_after`, so that `items` may miss some (even the best) matches.
+    pub(crate) partial: bool,
+}
+
+/// Which part of a city matched a search query.
+#[derive(Clone, Copy, Debug, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum MatchedOn {
+    /// The name localized to the requested language.
+    Name,
+    /// A name in some other language.
+    Alias,
+}
+
+/// Priority of cities when only some cities within a bounding box are returned.
+#[serde(rename_all = "camelCase")] // Not used by Rocket itself, but *is* used by rocket_okapi.
+#[derive(Clone, Copy, Debug, Eq, FromFormValue, JsonSchema, PartialEq)]
+pub(crate) enum BoundingBoxOrder {
+    /// Featured cities first, then bigger cities first.
+    Featured,
+    /// Bigger cities first, cities without population data last.
+    Population,
+}
 
-    /// Get [ElasticRegion] from Elasticsearch given its `id`. Async.
-    pub(crate) async fn get_region(&self, id: u64) -> HandlerResult<ElasticRegion> {
-        static CACHE: Lazy<DashMap<u64, ElasticRegion>> = Lazy::new(DashMap::new);
+/// Listing a [SearchAfter] cursor belongs to. Cursors are tagged with it, so that a cursor passed
+/// to other endpoint than the one that issued it is rejected instead of failing in Elasticsearch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum CursorKind {
+    /// Cities sorted by id, see [LocationsRepository::list_cities()].
+    List,
+    /// Changed cities sorted by update time and id, see
+    /// [LocationsRepository::get_changed_cities()].
+    Changes,
+}
 
-        if let Some(record) = CACHE.get(&id) {
-            return Ok(record.value().clone());
+impl CursorKind {
+    /// Tag prepended to cursors of this kind.
+    fn tag(self) -> &'static str {
+        match self {
+            Self::List => "l",
+            Self::Changes => "c",
         }
+    }
 
-        let entity: ElasticRegion = self.get_entity(id, REGION_INDEX, "Region").await?;
-        CACHE.insert(id, entity.clone());
-        Ok(entity)
+    /// Number of (integer) sort values of the listing.
+    fn sort_len(self) -> usize {
+        match self {
+            Self::List => 1,
+            Self::Changes => 2,
+        }
     }
+}
 
-    /// Get a list of featured cities. Async.
-    pub(crate) async fn get_featured_cities(&self) -> HandlerResult<Vec<ElasticCity>> {
-        self.search_city(
-            json!({
-                "query": {
-                    "term": {
-                        "isFeatured": true,
-                    }
-                },
-                "sort": [
-                    "countryIso",
-                    { "population": "desc" },
-                ],
-            }),
-            1000,
-        )
-        .await
+/// Position in a sorted list of cities, used for Elasticsearch `search_after` pagination.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct SearchAfter(pub(crate) Vec<JsonValue>);
+
+impl SearchAfter {
+    /// Encode into an opaque cursor token of listing `kind`: its tag followed by hex-encoded JSON
+    /// of Elasticsearch sort values.
+    pub(crate) fn to_cursor(&self, kind: CursorKind) -> String {
+        let json = serde_json::to_vec(self).expect("JSON values can be serialized");
+        let hex: String = json.iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("{}{}", kind.tag(), hex)
+    }
+
+    /// Decode from a cursor token produced by [SearchAfter::to_cursor()] for listing `kind`.
+    /// Tokens of any other shape or of other listings are rejected, as Elasticsearch would fail
+    /// on them.
+    pub(crate) fn from_cursor(cursor: &str, kind: CursorKind) -> HandlerResult<Self> {
+        let invalid = || BadRequest(format!("Invalid cursor `{}`.", cursor));
+
+        let hex = match cursor.get(..1) {
+            Some(tag) if tag == kind.tag() => &cursor[1..],
+            Some(tag) if tag == CursorKind::List.tag() || tag == CursorKind::Changes.tag() => {
+                let message = format!("Cursor `{}` belongs to another endpoint.", cursor);
+                return Err(BadRequest(message));
+            }
+            _ => return Err(invalid()),
+        };
+        if hex.len() % 2 != 0 {
+            return Err(invalid());
+        }
+        let json: Option<Vec<u8>> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect();
+        let search_after: Self =
+            serde_json::from_slice(&json.ok_or_else(invalid)?).map_err(|_| invalid())?;
+        if search_after.0.len() != kind.sort_len() || !search_after.0.iter().all(JsonValue::is_u64)
+        {
+            return Err(invalid());
+        }
+        Ok(search_after)
     }
+}
+
+/// Operations on Locations entities needed by handlers. Abstracted out so that handlers can be
+/// exercised against a mock implementation returning canned entities instead of Elasticsearch.
+pub(crate) trait LocationsRepository {
+    /// Get [ElasticCity] given its `id`.
+    fn get_city(&self, id: CityId) -> LocalBoxFuture<'_, HandlerResult<ElasticCity>>;
+
+    /// Get [ElasticCity] entities given their `ids`, in no particular order. Ids of cities that
+    /// don't exist are skipped.
+    fn get_cities(&self, ids: &[CityId]) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>>;
+
+    /// Get [ElasticRegion] given its `id`.
+    fn get_region(&self, id: RegionId) -> LocalBoxFuture<'_, HandlerResult<ElasticRegion>>;
+
+    /// Get hierarchy of regions starting with region given by its `id` and following their
+    /// parents up to the top-level region (at most [MAX_REGION_HIERARCHY_DEPTH] regions).
+    fn get_region_hierarchy(
+        &self,
+        id: RegionId,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticRegion>>>;
+
+    /// Get a list of featured cities.
+    fn get_featured_cities(&self) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>>;
+
+    /// Get the main city of a country given its ISO code: the biggest featured one, or the biggest
+    /// one if the country has no featured cities. Optionally filter by `is_featured`. [None] if
+    /// there are no matching cities in the country.
+    fn get_country_main_city(
+        &self,
+        country_iso: &str,
+        is_featured: Option<bool>,
+    ) -> LocalBoxFuture<'_, HandlerResult<Option<ElasticCity>>>;
+
+    /// Count featured cities, optionally only those of a country given its ISO code.
+    fn count_featured_cities(
+        &self,
+        country_iso: Option<&str>,
+    ) -> LocalBoxFuture<'_, HandlerResult<u64>>;
+
+    /// Get all cities of region given by its `id` (capped to 1000 cities), in no particular order.
+    fn get_region_cities(
+        &self,
+        id: RegionId,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>>;
+
+    /// Get a page of `size` cities sorted by id, starting after `search_after` if given. Also
+    /// return position to pass to the next call, or [None] if this is the last page. Unlike scroll,
+    /// `search_after` keeps no search context in Elasticsearch, so there is nothing to clean up when
+    /// a client abandons the listing.
+    fn list_cities(
+        &self,
+        size: usize,
+        search_after: Option<SearchAfter>,
+    ) -> LocalBoxFuture<'_, HandlerResult<(Vec<ElasticCity>, Option<SearchAfter>)>>;
+
+    /// Get a page of `size` cities updated after `since` (in milliseconds since Unix epoch) if
+    /// given, ordered by update time and id, starting after `search_after` if given. Also return
+    /// position of the last returned city, or [None] if the page is empty. Cities without update
+    /// time are never returned.
+    fn get_changed_cities(
+        &self,
+        size: usize,
+        since: Option<u64>,
+        search_after: Option<SearchAfter>,
+    ) -> LocalBoxFuture<'_, HandlerResult<(Vec<ElasticCity>, Option<SearchAfter>)>>;
+
+    /// Get ISO codes of all countries that have some cities, sorted. Cached for
+    /// [COUNTRY_ISOS_CACHE_TTL].
+    fn get_country_isos(&self) -> LocalBoxFuture<'_, HandlerResult<Vec<String>>>;
 
     /// Search for cities. Optionally limit to a country given its ISO code.
-    pub(crate) async fn search(
+    fn search(
         &self,
         query: &str,
         language: Language,
         country_iso: Option<&str>,
-    ) -> HandlerResult<Vec<ElasticCity>> {
-        let name_key = language.name_key();
+        options: SearchOptions,
+    ) -> LocalBoxFuture<'_, HandlerResult<SearchResults<ElasticCity>>>;
 
-        self.search_city(
-            json!({
-                "query": {
-                    "function_score": {
-                        "query": {
-                            "bool": {
-                                "must": [{
-                                    "multi_match": {
-                                        "query": query,
-                                        "fields": [
-                                            // Match against the specified language with diacritics.
-                                            // Use the highest boost (8) because these three fields are most specific.
-                                            format!("{}.autocomplete^8.0", name_key),
-                                            format!("{}.autocomplete._2gram^8.0", name_key),
-                                            format!("{}.autocomplete._3gram^8.0", name_key),
-                                            // Match against ascii versions of the name to match queries without diacritics.
-                                            // Lower boost by factor of two, to prefer cities that matched with diacritics.
-                                            format!("{}.autocomplete_ascii^4.0", name_key),
-                                            format!("{}.autocomplete_ascii._2gram^4.0", name_key),
-                                            format!("{}.autocomplete_ascii._3gram^4.0", name_key),
-                                            // Match against all language mutations with diacritics.
-                                            // Lower the boost by factor of 4 to prefer matches in specified language.
-                                            "name.all.autocomplete^2.0",
-                                            "name.all.autocomplete._2gram^2.0",
-                                            "name.all.autocomplete._3gram^2.0",
-                                            // Match against ascii version of all language mutations.
-                                            // Lower the boost by factor of 8 because this is the least specific field.
-                                            "name.all.autocomplete_ascii^1.0",
-                                            "name.all.autocomplete_ascii._2gram^1.0",
-                                            "name.all.autocomplete_ascii._3gram^1.0",
-                                        ],
-                                        "type": "bool_prefix",
-                                    }
-                                }],
-                                "filter": match country_iso {
-                                    Some(iso_code) => json!([{
-                                        "term": {
-                                            "countryIso": iso_code
-                                        }}]),
-                                    None => json!([])
-                                },
-                            }
-                        },
-                        // Boost cities with higher population.
-                        "functions": [{
-                            "field_value_factor": {
-                                "field": "population",
-                                // Take logarithm of the city's population to account for human's logarithmic perception of size.
-                                // Add 2 before taking the logarithm to make the score function strictly positive,
-                                // because it's multiplied with the MultiMatch score.
-                                "modifier": "ln2p",
-                                // For missing values assume 500 humans live there.
-                                "missing": 500,
-                            }
-                        }],
-                    }
-                },
-            }),
-            10,
-        )
-        .await
-    }
+    /// Suggest corrections of search `query` out of words of city names localized to `language`,
+    /// most likely first.
+    fn suggest_city_names(
+        &self,
+        query: &str,
+        language: Language,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<String>>>;
 
-    /// Get city intersecting with or closest to `coords`, optionally filter by `is_featured`.
-    pub(crate) async fn get_city_by_coords(
+    /// Get cities whose name localized to `language` is exactly `name` within given country.
+    /// Returns at most 2 cities, which is enough to tell whether the name is ambiguous.
+    fn get_cities_by_name(
+        &self,
+        name: &str,
+        language: Language,
+        country_iso: &str,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>>;
+
+    /// Search for regions. Optionally limit to a country given its ISO code.
+    fn search_regions(
+        &self,
+        query: &str,
+        language: Language,
+        country_iso: Option<&str>,
+    ) -> LocalBoxFuture<'_, HandlerResult<SearchResults<ElasticRegion>>>;
+
+    /// Get city intersecting with or closest to `coords`, optionally filter by `is_featured` and
+    /// limit to a country given its ISO code.
+    fn get_city_by_coords(
         &self,
         coords: Coordinates,
         is_featured: Option<bool>,
-    ) -> HandlerResult<ElasticCity> {
-        match self.get_intersecting_city(coords, is_featured).await? {
-            Some(city) => Ok(city),
-            None => self.get_closest_city(coords, is_featured).await,
-        }
-    }
+        country_iso: Option<&str>,
+    ) -> LocalBoxFuture<'_, HandlerResult<ElasticCity>>;
+
+    /// Get the most relevant city for approximate `coords`, e.g. from IP geo-location. Rather than
+    /// the strictly nearest one, prefer nearby featured and bigger cities as given by `scoring`.
+    /// Falls back to the closest city if there is no city near `coords`. Optionally filter by
+    /// `is_featured` and limit to a country given its ISO code.
+    fn get_city_by_approximate_coords(
+        &self,
+        coords: Coordinates,
+        scoring: ApproximateScoring,
+        is_featured: Option<bool>,
+        country_iso: Option<&str>,
+    ) -> LocalBoxFuture<'_, HandlerResult<ElasticCity>>;
 
-    /// Get city closest to `coords` (by centroid distance), optionally filter by `is_featured`.
-    pub(crate) async fn get_closest_city(
+    /// Get city closest to `coords` (by centroid distance), optionally filter by `is_featured` and
+    /// limit to a country given its ISO code.
+    fn get_closest_city(
         &self,
         coords: Coordinates,
         is_featured: Option<bool>,
-    ) -> HandlerResult<ElasticCity> {
+        country_iso: Option<&str>,
+    ) -> LocalBoxFuture<'_, HandlerResult<ElasticCity>>;
+
+    /// Get city closest to `coords` (by centroid distance) within each of `country_isos`, in the
+    /// same order, [None] for countries without cities. Runs as a single multi-search.
+    fn get_closest_city_per_country(
+        &self,
+        coords: Coordinates,
+        country_isos: &[String],
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<Option<ElasticCity>>>>;
+
+    /// Get up to `size` candidate cities for `coords` as scored by
+    /// [LocationsRepository::get_city_by_approximate_coords()] if `scoring` is given, or sorted by
+    /// distance as by [LocationsRepository::get_closest_city()] otherwise, best first. Also return
+    /// their Elasticsearch scores, if any. Filtered and limited like the mentioned methods.
+    /// Meant for debugging.
+    fn get_city_candidates(
+        &self,
+        coords: Coordinates,
+        scoring: Option<ApproximateScoring>,
+        is_featured: Option<bool>,
+        country_iso: Option<&str>,
+        size: usize,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<(ElasticCity, Option<f64>)>>>;
+
+    /// Get up to `size` cities closest to `coords` (by centroid distance) other than the city with
+    /// `id`, closest first.
+    fn get_neighbor_cities(
+        &self,
+        id: CityId,
+        coords: Coordinates,
+        size: usize,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>>;
+
+    /// Get up to `size` cities whose centroid lies within bounding box given by its `top_left` and
+    /// `bottom_right` corners, sorted by `order` if given, in no particular order otherwise.
+    fn get_cities_in_bounding_box(
+        &self,
+        top_left: Coordinates,
+        bottom_right: Coordinates,
+        order: Option<BoundingBoxOrder>,
+        size: usize,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>>;
+
+    /// Get up to `size` cities whose centroid lies within polygon given by its `vertices` (without
+    /// repeating the first one at the end), sorted like by
+    /// [LocationsRepository::get_cities_in_bounding_box()].
+    fn get_cities_in_polygon(
+        &self,
+        vertices: &[Coordinates],
+        order: Option<BoundingBoxOrder>,
+        size: usize,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>>;
+
+    /// Batch variant of [LocationsRepository::get_city_by_coords()] without any filtering.
+    /// Returns a city for each of `points`, in the same order.
+    fn get_cities_by_coords(
+        &self,
+        points: Vec<Coordinates>,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>>;
+}
+
+/// Repository of Elastic City, Region Locations entities. Thin wrapper around app state.
+pub(crate) struct LocationsElasticRepository<'a, S: WithElastic>(pub(crate) &'a S);
+
+// Actual implementation of Locations repository on any app state that impleents [WithElasticsearch].
+impl<S: WithElastic> LocationsRepository for LocationsElasticRepository<'_, S> {
+    fn get_city(&self, id: CityId) -> LocalBoxFuture<'_, HandlerResult<ElasticCity>> {
+        let es = self.0.elasticsearch();
+        coalesced(&CITIES_IN_FLIGHT, &CITY_LOOKUPS_METRICS, id, move || {
+            get_entity(es, id, CITY_INDEX, "City").boxed_local()
+        })
+        .boxed_local()
+    }
+
+    fn get_cities(&self, ids: &[CityId]) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>> {
         let query = json!({
-            "query": match is_featured {
-                Some(is_featured) => json!({"term": {"isFeatured": is_featured}}),
-                None => json!({"match_all": {}}),
+            "query": {
+                "terms": {
+                    "id": ids,
+                }
             },
-            "sort": {
-                "_geo_distance": {
-                    "centroid": coords
+        });
+
+        self.search_city(query, ids.len() as i64).boxed_local()
+    }
+
+    fn get_region(&self, id: RegionId) -> LocalBoxFuture<'_, HandlerResult<ElasticRegion>> {
+        async move {
+            if let Some(region) = REGION_CACHE.get(&id) {
+                return Ok(region);
+            }
+
+            let es = self.0.elasticsearch();
+            let entity = coalesced(&REGIONS_IN_FLIGHT, &REGION_LOOKUPS_METRICS, id, move || {
+                get_entity(es, id, REGION_INDEX, "Region").boxed_local()
+            })
+            .await?;
+            REGION_CACHE.insert(id, entity.clone());
+            Ok(entity)
+        }
+        .boxed_local()
+    }
+
+    fn get_region_hierarchy(
+        &self,
+        id: RegionId,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticRegion>>> {
+        async move {
+            let mut regions = vec![self.get_region(id).await?];
+            while let Some(parent_id) = regions.last().and_then(|it| it.parentId) {
+                if regions.len() >= MAX_REGION_HIERARCHY_DEPTH {
+                    error!("Hierarchy of region {} is too deep, cyclic?", id);
+                    return Err(InternalServerError(format!("Region#{} hierarchy too deep.", id)));
+                }
+                regions.push(self.get_region(parent_id).await?);
+            }
+            Ok(regions)
+        }
+        .boxed_local()
+    }
+
+    fn get_featured_cities(&self) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>> {
+        let query = json!({
+            "query": {
+                "term": {
+                    "isFeatured": true,
                 }
             },
+            "sort": [
+                "countryIso",
+                { "population": "desc" },
+            ],
         });
 
-        let cities = self.search_city(query, 1).await?;
-        // Extract the single city from response. Both no and multiple cities are unexpected.
-        cities.into_iter().single().map_err(|e| InternalServerError(e.to_string()))
+        self.search_city(query, 1000).boxed_local()
     }
 
-    async fn get_intersecting_city(
+    fn get_country_main_city(
         &self,
-        coords: Coordinates,
+        country_iso: &str,
         is_featured: Option<bool>,
-    ) -> HandlerResult<Option<ElasticCity>> {
-        let geo_query = json!({"geo_shape": {"geometry": {"shape": coords.geojson()}}});
+    ) -> LocalBoxFuture<'_, HandlerResult<Option<ElasticCity>>> {
         let query = json!({
             "query": {
                 "bool": {
-                    "filter": match is_featured {
-                        Some(is_featured) => json!([geo_query, {"term": {"isFeatured": is_featured}}]),
-                        None => geo_query
-                    }
+                    "filter": city_filter(None, is_featured, Some(country_iso)),
                 }
-            }
+            },
+            "sort": [
+                { "isFeatured": "desc" },
+                { "population": "desc" },
+            ],
         });
 
-        Ok(self.search_city(query, 1).await?.into_iter().next())
+        async move { Ok(self.search_city(query, 1).await?.into_iter().next()) }.boxed_local()
     }
 
-    async fn get_entity<T: fmt::Debug + DeserializeOwned>(
+    fn count_featured_cities(
         &self,
-        id: u64,
-        index_name: &str,
-        entity_name: &str,
-    ) -> HandlerResult<T> {
-        let es = self.0.elasticsearch();
+        country_iso: Option<&str>,
+    ) -> LocalBoxFuture<'_, HandlerResult<u64>> {
+        let body = json!({
+            "query": {
+                "bool": {
+                    "filter": city_filter(None, Some(true), country_iso),
+                }
+            },
+        });
 
-        let response = es
-            .get(IndexTypeId(index_name, "_source", &id.to_string()))
-            ._source_excludes(EXCLUDED_FIELDS)
-            .send()
+        async move {
+            let es = self.0.elasticsearch();
+            let response = send_with_retry(|| {
+                let mut request = es.count(CountParts::Index(&[CITY_INDEX])).body(&body);
+                if let Some(preference) = self.0.elastic_preference() {
+                    request = request.preference(preference);
+                }
+                request.send()
+            })
             .await?;
-
-        if response.status_code() == StatusCode::NOT_FOUND {
-            return Err(NotFound(format!("{}#{} not found.", entity_name, id)));
+            let response = logged_error_for_status(Some(&body), response).await?;
+            Ok(response.json::<CountResponse>().await?.count)
         }
+        .boxed_local()
+    }
 
-        let response = self.logged_error_for_status(None, response).await?;
-        let response_body = response.json::<T>().await?;
-        debug!("Elasticsearch response body: {:?}.", response_body);
+    fn get_region_cities(
+        &self,
+        id: RegionId,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>> {
+        let query = json!({
+            "query": {
+                "term": {
+                    "regionId": id,
+                }
+            },
+        });
 
-        Ok(response_body)
+        self.search_city(query, 1000).boxed_local()
     }
 
-    async fn search_city(&self, body: JsonValue, size: i64) -> HandlerResult<Vec<ElasticCity>> {
-        let es = self.0.elasticsearch();
-
-        let response = es
-            .search(Index(&[CITY_INDEX]))
-            .body(&body)
-            ._source_excludes(EXCLUDED_FIELDS)
-            .size(size)
-            .send()
-            .await?;
-        let response = self.logged_error_for_status(Some(&body), response).await?;
-        let response_body = response.json::<SearchResponse<ElasticCity>>().await?;
-        debug!("Elasticsearch response body: {:?}.", response_body);
+    fn list_cities(
+        &self,
+        size: usize,
+        search_after: Option<SearchAfter>,
+    ) -> LocalBoxFuture<'_, HandlerResult<(Vec<ElasticCity>, Option<SearchAfter>)>> {
+        let mut query = json!({
+            "query": {"match_all": {}},
+            "sort": [{"id": "asc"}],
+        });
+        if let Some(search_after) = search_after {
+            query["search_after"] = json!(search_after);
+        }
 
-        Ok(response_body.hits.hits.into_iter().map(|hit| hit._source).collect())
+        async move {
+            let hits = self.search_hits(CITY_INDEX, query, size as i64).await?;
+            let next_search_after = match hits.last() {
+                Some(hit) if hits.len() == size => Some(SearchAfter(hit.sort.clone())),
+                _ => None,
+            };
+            Ok((hits.into_iter().map(|hit| hit._source).collect(), next_search_after))
+        }
+        .boxed_local()
     }
 
-    async fn logged_error_for_status(
+    fn get_changed_cities(
         &self,
-        body: Option<&JsonValue>,
-        response: EsResponse,
-    ) -> Result<EsResponse, EsError> {
-        // This is somewhat convoluted to satisfy Rust lifetime rules. As response.text() takes
-        // ownership of the response, we in turn also need to take its ownership. We need to use
-        // error_for_status_code_ref() (rather than the non-_ref variant) for the same reason.
-        match response.error_for_status_code_ref() {
-            Ok(_) => Ok(response),
-            Err(e) => {
-                let request = body.and_then(|val| to_string_pretty(val).ok()).unwrap_or_default();
-                let resp_text = response.text().await.unwrap_or_default();
-                error!("Elasticsearch: {}. Request:\n{}\nresponse: {}", e, request, resp_text);
-                Err(e)
-            }
+        size: usize,
+        since: Option<u64>,
+        search_after: Option<SearchAfter>,
+    ) -> LocalBoxFuture<'_, HandlerResult<(Vec<ElasticCity>, Option<SearchAfter>)>> {
+        let updated_filter = match since {
+            Some(since) => json!({"range": {"updatedAt": {"gt": since}}}),
+            None => json!({"exists": {"field": "updatedAt"}}),
+        };
+        let mut query = json!({
+            "query": {"bool": {"filter": [updated_filter]}},
+            "sort": [{"updatedAt": "asc"}, {"id": "asc"}],
+        });
+        if let Some(search_after) = search_after {
+            query["search_after"] = json!(search_after);
         }
+
+        async move {
+            let hits = self.search_hits(CITY_INDEX, query, size as i64).await?;
+            let last_search_after = hits.last().map(|hit| SearchAfter(hit.sort.clone()));
+            Ok((hits.into_iter().map(|hit| hit._source).collect(), last_search_after))
+        }
+        .boxed_local()
     }
-}
 
-/// City entity mapped from Elasticsearch.
-#[allow(non_snake_case)]
-#[derive(Debug, Deserialize)]
-pub(crate) struct ElasticCity {
-    pub(crate) id: u64,
-    pub(crate) regionId: u64,
-    pub(crate) isFeatured: bool,
-    pub(crate) countryIso: String,
-    pub(crate) timezone: String,
-    pub(crate) centroid: Coordinates,
+    fn get_country_isos(&self) -> LocalBoxFuture<'_, HandlerResult<Vec<String>>> {
+        let body = json!({
+            "aggs": {
+                "countries": {
+                    "terms": {
+                        "field": "countryIso",
+                        "size": MAX_COUNTRIES,
+                        "order": {"_key": "asc"},
+                    }
+                }
+            },
+        });
 
-    #[serde(flatten)] // captures rest of fields, see https://serde.rs/attr-flatten.html
-    pub(crate) names: HashMap<String, String>,
-}
+        async move {
+            if let Some(country_isos) = COUNTRY_ISOS_CACHE.get(&()) {
+                return Ok(country_isos);
+            }
 
-/// Region entity mapped from Elasticsearch.
-#[allow(non_snake_case)]
+            let es = self.0.elasticsearch();
+            let response = send_with_retry(|| {
+                let mut request = es.search(Index(&[CITY_INDEX])).body(&body).size(0);
+                if let Some(preference) = self.0.elastic_preference() {
+                    request = request.preference(preference);
+                }
+                request.send()
+            })
+            .await?;
+            let response = logged_error_for_status(Some(&body), response).await?;
+            let response_body = response.json::<CountriesAggregationResponse>().await?;
+            record_took(response_body.took);
+
+            let buckets = response_body.aggregations.countries.buckets;
+            let country_isos: Vec<String> = buckets.into_iter().map(|it| it.key).collect();
+            COUNTRY_ISOS_CACHE.insert((), country_isos.clone());
+            Ok(country_isos)
+        }
+        .boxed_local()
+    }
+
+    fn search(
+        &self,
+        query: &str,
+        language: Language,
+        country_iso: Option<&str>,
+        options: SearchOptions,
+    ) -> LocalBoxFuture<'_, HandlerResult<SearchResults<ElasticCity>>> {
+        let name_key = language.name_key();
+        let mut should =
+            if options.match_info { match_info_clauses(query, &name_key) } else { Vec::new() };
+        should.extend(name_prefix_clause(query, &name_key, options.prefix_boost));
+        should.extend(name_phrase_clause(query, &name_key, options.phrase_boost, options.exact));
+        should.extend(featured_clause(options.featured_boost));
+
+        let name_match = name_multi_match(query, language, options.cross_language, options.exact);
+        let template_values = [
+            ("query", json!(query)),
+            ("language", json!(language.as_str())),
+            ("nameKey", json!(name_key)),
+            ("nameMatch", name_match.clone()),
+            ("should", json!(should)),
+            ("filter", country_filter(country_iso)),
+        ];
+        let mut body = match query_templates::render(QueryKind::Search, &template_values) {
+            Some(body) => body,
+            None => json!({
+                "query": {
+                    "function_score": {
+                        "query": {
+                            "bool": {
+                                "must": [name_match],
+                                "should": should,
+                                "filter": country_filter(country_iso),
+                            }
+                        },
+                        // Boost cities with higher population.
+                        "functions": [{
+                            "field_value_factor": {
+                                "field": "population",
+                                // Take logarithm of the city's population to account for human's logarithmic perception of size.
+                                // Add 2 before taking the logarithm to make the score function strictly positive,
+                                // because it's multiplied with the MultiMatch score.
+                                "modifier": "ln2p",
+                                // For missing values assume 500 humans live there.
+                                "missing": 500,
+                            }
+                        }],
+                    }
+                },
+            }),
+        };
+
+        // Applies to templates too, so that they need no placeholder for it.
+        if options.min_score > 0.0 {
+            body["min_score"] = json!(options.min_score);
+        }
+        if body.get("_source").is_some() {
+            warn!("Query template sets _source, overriding it with localized source filter.");
+        }
+        body["_source"] = localized_source(CITY_INDEX, language);
+
+        // Fetch more cities when deduplicating so that duplicates don't shorten the result much.
+        let size = if options.dedupe { 2 * options.size } else { options.size };
+
+        async move {
+            let (hits, partial) =
+                self.limited_search_hits::<ElasticCity>(CITY_INDEX, body, size as i64).await?;
+            let mut seen_keys = HashSet::new();
+            let items = hits
+                .into_iter()
+                .map(|hit| {
+                    let matched = |name: &str| hit.matched_queries.iter().any(|q| q == name);
+                    let matched_on = if matched("name") {
+                        Some(MatchedOn::Name)
+                    } else if matched("alias") {
+                        Some(MatchedOn::Alias)
+                    } else {
+                        None
+                    };
+                    ElasticCity { matched_on, ..hit._source }
+                })
+                // Hits are sorted by relevance, so the first one of duplicates is kept.
+                .filter(|city| !options.dedupe || seen_keys.insert(city.dedupe_key(&name_key)))
+                .take(options.size)
+                .collect();
+            Ok(SearchResults { items, partial })
+        }
+        .boxed_local()
+    }
+
+    fn suggest_city_names(
+        &self,
+        query: &str,
+        language: Language,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<String>>> {
+        // Term suggester corrects each word separately, only words not present in the index.
+        let body = json!({
+            "suggest": {
+                "names": {
+                    "text": query,
+                    "term": {"field": language.name_key(), "size": MAX_SUGGESTIONS},
+                }
+            },
+        });
+        let query = query.to_string();
+
+        async move {
+            let es = self.0.elasticsearch();
+            let response = send_with_retry(|| {
+                let mut request = es.search(Index(&[CITY_INDEX])).body(&body).size(0);
+                if let Some(preference) = self.0.elastic_preference() {
+                    request = request.preference(preference);
+                }
+                request.send()
+            })
+            .await?;
+            let response = logged_error_for_status(Some(&body), response).await?;
+            let response_body = response.json::<SuggestResponse>().await?;
+            record_took(response_body.took);
+
+            Ok(combine_suggestions(&query, &response_body.suggest.names))
+        }
+        .boxed_local()
+    }
+
+    fn get_cities_by_name(
+        &self,
+        name: &str,
+        language: Language,
+        country_iso: &str,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>> {
+        // Relies on the keyword sub-field of localized names, which holds the exact name.
+        let name_keyword_key = format!("{}.keyword", language.name_key());
+        let body = json!({
+            "query": {
+                "bool": {
+                    "filter": [
+                        {"term": {name_keyword_key: name}},
+                        {"term": {"countryIso": country_iso}},
+                    ]
+                }
+            },
+        });
+
+        self.search_city(body, 2).boxed_local()
+    }
+
+    fn search_regions(
+        &self,
+        query: &str,
+        language: Language,
+        country_iso: Option<&str>,
+    ) -> LocalBoxFuture<'_, HandlerResult<SearchResults<ElasticRegion>>> {
+        let body = json!({
+            "query": {
+                "bool": {
+                    "must": [name_multi_match(query, language, false, false)],
+                    "filter": country_filter(country_iso),
+                }
+            },
+            "_source": localized_source(REGION_INDEX, language),
+        });
+
+        async move {
+            let (hits, partial) = self.limited_search_hits(REGION_INDEX, body, 10).await?;
+            Ok(SearchResults { items: hits.into_iter().map(|hit| hit._source).collect(), partial })
+        }
+        .boxed_local()
+    }
+
+    fn get_city_by_coords(
+        &self,
+        coords: Coordinates,
+        is_featured: Option<bool>,
+        country_iso: Option<&str>,
+    ) -> LocalBoxFuture<'_, HandlerResult<ElasticCity>> {
+        let country_iso = country_iso.map(str::to_string);
+
+        async move {
+            let country_iso = country_iso.as_deref();
+            match self.get_intersecting_city(coords, is_featured, country_iso).await? {
+                Some(city) => Ok(city),
+                None => self.get_closest_city(coords, is_featured, country_iso).await,
+            }
+        }
+        .boxed_local()
+    }
+
+    fn get_city_by_approximate_coords(
+        &self,
+        coords: Coordinates,
+        scoring: ApproximateScoring,
+        is_featured: Option<bool>,
+        country_iso: Option<&str>,
+    ) -> LocalBoxFuture<'_, HandlerResult<ElasticCity>> {
+        let query = approximate_city_query(coords, scoring, is_featured, country_iso);
+        let country_iso = country_iso.map(str::to_string);
+
+        async move {
+            match self.search_city(query, 1).await?.into_iter().next() {
+                Some(city) => Ok(city),
+                None => self.get_closest_city(coords, is_featured, country_iso.as_deref()).await,
+            }
+        }
+        .boxed_local()
+    }
+
+    fn get_closest_city(
+        &self,
+        coords: Coordinates,
+        is_featured: Option<bool>,
+        country_iso: Option<&str>,
+    ) -> LocalBoxFuture<'_, HandlerResult<ElasticCity>> {
+        let query = closest_city_query(coords, is_featured, country_iso);
+        let country_iso = country_iso.map(str::to_string);
+
+        async move {
+            let cities = self.search_city(query, 1).await?;
+            // Extract the single city from response. No cities are found only if there are no
+            // matching cities at all (e.g. the index is empty), multiple cities are unexpected.
+            match cities.into_iter().single() {
+                Err(SingleError::NoElements) => {
+                    Err(NotFound(no_closest_city_message(is_featured, country_iso.as_deref())))
+                }
+                result => result.map_err(|e| InternalServerError(e.to_string())),
+            }
+        }
+        .boxed_local()
+    }
+
+    fn get_closest_city_per_country(
+        &self,
+        coords: Coordinates,
+        country_isos: &[String],
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<Option<ElasticCity>>>> {
+        let bodies =
+            country_isos.iter().map(|iso_code| closest_city_query(coords, None, Some(iso_code)));
+
+        self.multi_search_city(bodies.collect()).boxed_local()
+    }
+
+    fn get_city_candidates(
+        &self,
+        coords: Coordinates,
+        scoring: Option<ApproximateScoring>,
+        is_featured: Option<bool>,
+        country_iso: Option<&str>,
+        size: usize,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<(ElasticCity, Option<f64>)>>> {
+        let query = match scoring {
+            Some(scoring) => approximate_city_query(coords, scoring, is_featured, country_iso),
+            None => closest_city_query(coords, is_featured, country_iso),
+        };
+
+        async move {
+            let hits = self.search_hits(CITY_INDEX, query, size as i64).await?;
+            Ok(hits.into_iter().map(|hit| (hit._source, hit._score)).collect())
+        }
+        .boxed_local()
+    }
+
+    fn get_neighbor_cities(
+        &self,
+        id: CityId,
+        coords: Coordinates,
+        size: usize,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>> {
+        let query = json!({
+            "query": {
+                "bool": {
+                    "must_not": [{"term": {"id": id}}],
+                }
+            },
+            "sort": {
+                "_geo_distance": {
+                    "centroid": coords
+                }
+            },
+        });
+
+        self.search_city(query, size as i64).boxed_local()
+    }
+
+    fn get_cities_in_bounding_box(
+        &self,
+        top_left: Coordinates,
+        bottom_right: Coordinates,
+        order: Option<BoundingBoxOrder>,
+        size: usize,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>> {
+        let geo_query = json!({
+            "geo_bounding_box": {
+                "centroid": {
+                    "top_left": top_left,
+                    "bottom_right": bottom_right,
+                }
+            }
+        });
+        let query = json!({
+            "query": {
+                "bool": {
+                    "filter": city_filter(Some(geo_query), None, None),
+                }
+            },
+            "sort": geo_area_sort(order),
+        });
+
+        self.search_city(query, size as i64).boxed_local()
+    }
+
+    fn get_cities_in_polygon(
+        &self,
+        vertices: &[Coordinates],
+        order: Option<BoundingBoxOrder>,
+        size: usize,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>> {
+        let geo_query = json!({
+            "geo_polygon": {
+                "centroid": {
+                    "points": vertices,
+                }
+            }
+        });
+        let query = json!({
+            "query": {
+                "bool": {
+                    "filter": city_filter(Some(geo_query), None, None),
+                }
+            },
+            "sort": geo_area_sort(order),
+        });
+
+        self.search_city(query, size as i64).boxed_local()
+    }
+
+    fn get_cities_by_coords(
+        &self,
+        points: Vec<Coordinates>,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>> {
+        async move {
+            // First try to find cities whose geometry contains the points in one multi-search,
+            // then fall back to the closest cities for points outside of all cities in another.
+            let bodies = points.iter().map(|&coords| intersecting_city_query(coords, None, None));
+            let mut cities = self.multi_search_city(bodies.collect()).await?;
+
+            let missing: Vec<usize> = (0..points.len()).filter(|&i| cities[i].is_none()).collect();
+            if !missing.is_empty() {
+                let bodies = missing.iter().map(|&i| closest_city_query(points[i], None, None));
+                let closest_cities = self.multi_search_city(bodies.collect()).await?;
+                for (i, city) in missing.into_iter().zip(closest_cities) {
+                    cities[i] = city;
+                }
+            }
+
+            cities
+                .into_iter()
+                .map(|city| {
+                    city.ok_or_else(|| {
+                        NotFound("No city found, the city index is empty.".to_string())
+                    })
+                })
+                .collect()
+        }
+        .boxed_local()
+    }
+}
+
+// Elasticsearch-specific helpers of the Locations repository.
+impl<S: WithElastic> LocationsElasticRepository<'_, S> {
+    async fn get_intersecting_city(
+        &self,
+        coords: Coordinates,
+        is_featured: Option<bool>,
+        country_iso: Option<&str>,
+    ) -> HandlerResult<Option<ElasticCity>> {
+        let query = intersecting_city_query(coords, is_featured, country_iso);
+
+        Ok(self.search_city(query, 1).await?.into_iter().next())
+    }
+
+    /// Run `bodies` as a single Elasticsearch multi-search in the city index, return the top city
+    /// of each search, in order.
+    async fn multi_search_city(
+        &self,
+        bodies: Vec<JsonValue>,
+    ) -> HandlerResult<Vec<Option<ElasticCity>>> {
+        let es = self.0.elasticsearch();
+
+        let excludes = excluded_fields(CITY_INDEX);
+        let header = match self.0.elastic_preference() {
+            Some(preference) => json!({ "preference": preference }),
+            None => json!({}),
+        };
+        let request_lines: Vec<JsonValue> = bodies
+            .into_iter()
+            .flat_map(|mut body| {
+                body["size"] = json!(1);
+                body["_source"] = json!({ "excludes": excludes });
+                vec![header.clone(), body]
+            })
+            .collect();
+
+        let response = send_with_retry(|| {
+            let request_lines: Vec<JsonBody<JsonValue>> =
+                request_lines.iter().cloned().map(Into::into).collect();
+            es.msearch(MsearchParts::Index(&[CITY_INDEX])).body(request_lines).send()
+        })
+        .await?;
+        let response = logged_error_for_status(None, response).await?;
+        let response_body = response.json::<MultiSearchResponse<ElasticCity>>().await?;
+        record_took(response_body.took);
+        debug!("Elasticsearch response body: {:?}.", response_body);
+
+        response_body
+            .responses
+            .into_iter()
+            .map(|item| match item.hits {
+                Some(hits) => Ok(hits.hits.into_iter().next().map(|hit| hit._source)),
+                None => {
+                    error!("Elasticsearch multi-search item failed: {:?}.", item.error);
+                    Err(InternalServerError("Elasticsearch multi-search failed.".to_string()))
+                }
+            })
+            .collect()
+    }
+
+    async fn search_city(&self, body: JsonValue, size: i64) -> HandlerResult<Vec<ElasticCity>> {
+        let hits = self.search_hits(CITY_INDEX, body, size).await?;
+
+        Ok(hits.into_iter().map(|hit| hit._source).collect())
+    }
+
+    async fn search_hits<T: fmt::Debug + DeserializeOwned>(
+        &self,
+        index_name: &str,
+        body: JsonValue,
+        size: i64,
+    ) -> HandlerResult<Vec<Hit<T>>> {
+        Ok(self.send_search(index_name, body, size, false).await?.hits.hits)
+    }
+
+    /// Like [Self::search_hits()], but limit work of Elasticsearch by configured `timeout` and
+    /// `terminate_after`. Also return whether Elasticsearch stopped early, so that the hits may be
+    /// partial. Meant for full-text searches, where a partial result is still useful.
+    async fn limited_search_hits<T: fmt::Debug + DeserializeOwned>(
+        &self,
+        index_name: &str,
+        body: JsonValue,
+        size: i64,
+    ) -> HandlerResult<(Vec<Hit<T>>, bool)> {
+        let response_body = self.send_search(index_name, body, size, true).await?;
+        let partial = response_body.timed_out || response_body.terminated_early;
+        if partial {
+            warn!("Elasticsearch search in {} stopped early, results are partial.", index_name);
+        }
+        Ok((response_body.hits.hits, partial))
+    }
+
+    async fn send_search<T: fmt::Debug + DeserializeOwned>(
+        &self,
+        index_name: &str,
+        body: JsonValue,
+        size: i64,
+        limited: bool,
+    ) -> HandlerResult<SearchResponse<T>> {
+        let es = self.0.elasticsearch();
+
+        let index_names = [index_name];
+        let response = send_with_retry(|| {
+            let mut request = es.search(Index(&index_names)).body(&body).size(size);
+            // Bodies may filter `_source` themselves, see localized_source().
+            if body.get("_source").is_none() {
+                request = request._source_excludes(excluded_fields(index_name));
+            }
+            if let Some(preference) = self.0.elastic_preference() {
+                request = request.preference(preference);
+            }
+            if limited {
+                if let Some(timeout) = self.0.elastic_search_timeout() {
+                    request = request.timeout(timeout);
+                }
+                if let Some(terminate_after) = self.0.elastic_search_terminate_after() {
+                    request = request.terminate_after(terminate_after as i64);
+                }
+            }
+            request.send()
+        })
+        .await?;
+        let response = logged_error_for_status(Some(&body), response).await?;
+        let response_body = response.json::<SearchResponse<T>>().await?;
+        record_took(response_body.took);
+        debug!("Elasticsearch response body: {:?}.", response_body);
+
+        Ok(response_body)
+    }
+}
+
+/// Construct zero-boost Elasticsearch `should` clauses that don't affect scoring, but tell using
+/// named queries whether `query` matched the localized name (`name`) or a name in some other
+/// language (`alias`).
+fn match_info_clauses(query: &str, name_key: &str) -> Vec<JsonValue> {
+    let clause = |fields: &[&str], name: &str| {
+        json!({
+            "multi_match": {
+                "query": query,
+                "fields": fields,
+                "type": "bool_prefix",
+                "boost": 0,
+                "_name": name,
+            }
+        })
+    };
+
+    let localized_fields = format!("{}.autocomplete*", name_key);
+    vec![clause(&[&localized_fields], "name"), clause(&["name.all.autocomplete*"], "alias")]
+}
+
+/// Construct Elasticsearch `should` clause that adds `boost` to score of featured cities. Returns
+/// [None] if `boost` is not positive.
+fn featured_clause(boost: f64) -> Option<JsonValue> {
+    if boost <= 0.0 {
+        return None;
+    }
+    Some(json!({
+        "constant_score": {
+            "filter": {"term": {"isFeatured": true}},
+            "boost": boost,
+        }
+    }))
+}
+
+/// Construct Elasticsearch `should` clause that adds `boost` to score of cities whose name
+/// localized under `name_key` starts with `query`, word by word (the last word may be incomplete).
+/// Returns [None] if `boost` is not positive or `query` has no words.
+fn name_prefix_clause(query: &str, name_key: &str, boost: f64) -> Option<JsonValue> {
+    if boost <= 0.0 {
+        return None;
+    }
+    // The analyzed field is lowercase, while span queries are not analyzed.
+    let field = format!("{}.autocomplete", name_key);
+    let words: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+    let (last_word, complete_words) = words.split_last()?;
+
+    let mut clauses: Vec<JsonValue> =
+        complete_words.iter().map(|word| json!({"span_term": {&field: word}})).collect();
+    clauses.push(json!({"span_multi": {"match": {"prefix": {&field: {"value": last_word}}}}}));
+    Some(json!({
+        "span_first": {
+            "match": {
+                "span_near": {
+                    "clauses": clauses,
+                    "slop": 0,
+                    "in_order": true,
+                }
+            },
+            "end": words.len(),
+            "boost": boost,
+        }
+    }))
+}
+
+/// Construct Elasticsearch `should` clause boosting by `boost` cities whose name localized under
+/// `name_key` contains words of `query` as a phrase (contiguous and in order), with or without
+/// diacritics (only with them if `exact`). Returns [None] if `boost` is not positive or `query`
+/// has less than two words, as a single word is a phrase of every name it matches, so boosting it
+/// would change nothing.
+fn name_phrase_clause(query: &str, name_key: &str, boost: f64, exact: bool) -> Option<JsonValue> {
+    if boost <= 0.0 || query.split_whitespace().nth(1).is_none() {
+        return None;
+    }
+    // Whole words of the plain name field rather than its n-gram subfields, so that only the full
+    // phrase is boosted. Root of the `autocomplete_ascii` subfield is analyzed into whole words
+    // too, it serves as the plain field without diacritics.
+    let mut fields = vec![name_key.to_string()];
+    if !exact {
+        fields.push(format!("{}.autocomplete_ascii", name_key));
+    }
+    Some(json!({
+        "multi_match": {
+            "query": query,
+            "fields": fields,
+            "type": "phrase",
+            "boost": boost,
+        }
+    }))
+}
+
+/// Construct Elasticsearch query matching `query` against localized names (and their prefixes),
+/// preferring those in `language`. If `cross_language` is set, names in other supported languages
+/// are boosted so that they are preferred over the generic `name.all` fields. Names are matched
+/// also without diacritics (using their folded ASCII versions) unless `exact` is set.
+fn name_multi_match(
+    query: &str,
+    language: Language,
+    cross_language: bool,
+    exact: bool,
+) -> JsonValue {
+    let name_key = language.name_key();
+    let mut fields = vec![
+        // Match against the specified language with diacritics.
+        // Use the highest boost (8) because these three fields are most specific.
+        format!("{}.autocomplete^8.0", name_key),
+        format!("{}.autocomplete._2gram^8.0", name_key),
+        format!("{}.autocomplete._3gram^8.0", name_key),
+        // Match against ascii versions of the name to match queries without diacritics.
+        // Lower boost by factor of two, to prefer entities that matched with diacritics.
+        format!("{}.autocomplete_ascii^4.0", name_key),
+        format!("{}.autocomplete_ascii._2gram^4.0", name_key),
+        format!("{}.autocomplete_ascii._3gram^4.0", name_key),
+        // Match against all language mutations with diacritics.
+        // Lower the boost by factor of 4 to prefer matches in specified language.
+        "name.all.autocomplete^2.0".to_string(),
+        "name.all.autocomplete._2gram^2.0".to_string(),
+        "name.all.autocomplete._3gram^2.0".to_string(),
+        // Match against ascii version of all language mutations.
+        // Lower the boost by factor of 8 because this is the least specific field.
+        "name.all.autocomplete_ascii^1.0".to_string(),
+        "name.all.autocomplete_ascii._2gram^1.0".to_string(),
+        "name.all.autocomplete_ascii._3gram^1.0".to_string(),
+    ];
+    if cross_language {
+        // Match against other languages with boosts halved compared to the specified language,
+        // so that e.g. a German user typing "Praga" gets Prague ahead of cities whose German name
+        // merely starts similarly.
+        for other_key in Language::ALL.iter().filter(|&&it| it != language).map(|it| it.name_key())
+        {
+            fields.extend(vec![
+                format!("{}.autocomplete^4.0", other_key),
+                format!("{}.autocomplete._2gram^4.0", other_key),
+                format!("{}.autocomplete._3gram^4.0", other_key),
+                format!("{}.autocomplete_ascii^2.0", other_key),
+                format!("{}.autocomplete_ascii._2gram^2.0", other_key),
+                format!("{}.autocomplete_ascii._3gram^2.0", other_key),
+            ]);
+        }
+    }
+    if exact {
+        fields.retain(|field| !field.contains(".autocomplete_ascii"));
+    }
+
+    json!({
+        "multi_match": {
+            "query": query,
+            "fields": fields,
+            "type": "bool_prefix",
+        }
+    })
+}
+
+/// Fields of documents in index `index_name` that the service doesn't need, to be excluded from
+/// `_source` of Elasticsearch responses.
+fn excluded_fields(index_name: &str) -> &'static [&'static str] {
+    match index_name {
+        CITY_INDEX => &["geometry"],
+        _ => &["geometry", "population"],
+    }
+}
+
+/// Fields of documents in index `index_name` that the service needs regardless of language. Must
+/// list all fields of [ElasticCity] and [ElasticRegion] respectively, except for localized names,
+/// otherwise their optional fields silently become `None` in search results (checked by tests).
+fn source_fields(index_name: &str) -> &'static [&'static str] {
+    match index_name {
+        CITY_INDEX => &[
+            "id",
+            "regionId",
+            "isFeatured",
+            "countryIso",
+            "timezone",
+            "centroid",
+            "updatedAt",
+            "population",
+            "canonicalId",
+        ],
+        _ => &["id", "countryIso", "centroid", "parentId"],
+    }
+}
+
+/// Elasticsearch `_source` filter of documents in index `index_name` to the fields needed to
+/// respond in `language`, leaving out names in other languages (and fields never needed). Meant
+/// for searches that return many documents, whose entities must then not be cached or reused for
+/// other languages.
+fn localized_source(index_name: &str, language: Language) -> JsonValue {
+    let mut includes: Vec<String> =
+        source_fields(index_name).iter().map(ToString::to_string).collect();
+    includes.extend(vec![language.name_key(), language.official_name_key()]);
+    json!({ "includes": includes, "excludes": excluded_fields(index_name) })
+}
+
+/// Construct Elasticsearch search body to find a city whose geometry contains `coords`, optionally
+/// filtered by `is_featured` and country given by its ISO code.
+fn intersecting_city_query(
+    coords: Coordinates,
+    is_featured: Option<bool>,
+    country_iso: Option<&str>,
+) -> JsonValue {
+    let geo_query = json!({"geo_shape": {"geometry": {"shape": coords.geojson()}}});
+    json!({
+        "query": {
+            "bool": {
+                "filter": city_filter(Some(geo_query), is_featured, country_iso),
+            }
+        }
+    })
+}
+
+/// Construct Elasticsearch search body to find cities near approximate `coords` sorted by score
+/// given by `scoring`, optionally filtered by `is_featured` and country given by its ISO code.
+fn approximate_city_query(
+    coords: Coordinates,
+    scoring: ApproximateScoring,
+    is_featured: Option<bool>,
+    country_iso: Option<&str>,
+) -> JsonValue {
+    let distance_filter = json!({
+        "geo_distance": {
+            "distance": format!("{}km", 4.0 * scoring.distance_scale_km),
+            "centroid": coords,
+        }
+    });
+    json!({
+        "query": {
+            "function_score": {
+                "query": {
+                    "bool": {
+                        "filter": city_filter(Some(distance_filter), is_featured, country_iso),
+                    }
+                },
+                "functions": [
+                    // Score decreases with distance, it is 0.5 at distance_scale_km.
+                    {
+                        "gauss": {
+                            "centroid": {
+                                "origin": coords,
+                                "scale": format!("{}km", scoring.distance_scale_km),
+                            }
+                        }
+                    },
+                    // Boost featured cities.
+                    {
+                        "filter": {"term": {"isFeatured": true}},
+                        "weight": scoring.featured_boost,
+                    },
+                    // Boost bigger cities, logarithmically as in search.
+                    {
+                        "field_value_factor": {
+                            "field": "population",
+                            "factor": scoring.population_factor,
+                            "modifier": "ln2p",
+                            "missing": 500,
+                        }
+                    },
+                ],
+                "score_mode": "multiply",
+                "boost_mode": "replace",
+            }
+        },
+    })
+}
+
+/// Message of Not Found error of [LocationsRepository::get_closest_city()] when no city matches
+/// its filters. Only without any filters it means that the city index is empty.
+pub(crate) fn no_closest_city_message(
+    is_featured: Option<bool>,
+    country_iso: Option<&str>,
+) -> String {
+    let cities = match is_featured {
+        Some(true) => "featured city",
+        Some(false) => "non-featured city",
+        None => "city",
+    };
+    match (is_featured, country_iso) {
+        (_, Some(iso_code)) => format!("No {} in country {} found.", cities, iso_code),
+        (Some(_), None) => format!("No {} found.", cities),
+        (None, None) => "No city found, the city index is empty.".to_string(),
+    }
+}
+
+/// Construct Elasticsearch search body to find cities sorted by distance of their centroid from
+/// `coords`, optionally filtered by `is_featured` and country given by its ISO code.
+fn closest_city_query(
+    coords: Coordinates,
+    is_featured: Option<bool>,
+    country_iso: Option<&str>,
+) -> JsonValue {
+    let filter = city_filter(None, is_featured, country_iso);
+    let template_values = [("coords", json!(coords)), ("filter", filter.clone())];
+    query_templates::render(QueryKind::Closest, &template_values).unwrap_or_else(|| {
+        json!({
+            "query": {
+                "bool": {
+                    "filter": filter,
+                }
+            },
+            "sort": {
+                "_geo_distance": {
+                    "centroid": coords
+                }
+            },
+        })
+    })
+}
+
+/// Construct Elasticsearch sort of cities within a geographic area by `order`.
+fn geo_area_sort(order: Option<BoundingBoxOrder>) -> JsonValue {
+    match order {
+        Some(BoundingBoxOrder::Featured) => json!([{"isFeatured": "desc"}, {"population": "desc"}]),
+        Some(BoundingBoxOrder::Population) => json!([{"population": "desc"}]),
+        None => json!(["_doc"]), // The most efficient order.
+    }
+}
+
+/// Construct Elasticsearch filter clauses of cities: `geo_query` if given, optionally `is_featured`
+/// and country given by its ISO code. No clauses match all cities.
+fn city_filter(
+    geo_query: Option<JsonValue>,
+    is_featured: Option<bool>,
+    country_iso: Option<&str>,
+) -> JsonValue {
+    let mut clauses: Vec<JsonValue> = geo_query.into_iter().collect();
+    if let Some(is_featured) = is_featured {
+        clauses.push(json!({"term": {"isFeatured": is_featured}}));
+    }
+    if let Some(iso_code) = country_iso {
+        clauses.push(json!({"term": {"countryIso": iso_code}}));
+    }
+    json!(clauses)
+}
+
+/// Whole-query suggestions out of per-word `entries` of a term suggester: the n-th suggestion has
+/// each misspelled word of `query` replaced by its n-th (or last) correction. Entries that start
+/// before the end of the previous replaced word (e.g. out of order or overlapping, as analyzers
+/// emitting synonyms produce) are skipped.
+fn combine_suggestions(query: &str, entries: &[SuggestEntry]) -> Vec<String> {
+    let count = entries.iter().map(|it| it.options.len()).max().unwrap_or(0);
+    let mut suggestions: Vec<String> = Vec::with_capacity(count);
+    for n in 0..count {
+        let (mut suggestion, mut rest_start) = (String::new(), 0);
+        for entry in entries {
+            if let Some(option) = entry.options.get(n).or_else(|| entry.options.last()) {
+                let start = byte_index(query, entry.offset);
+                if start < rest_start {
+                    continue;
+                }
+                suggestion.push_str(&query[rest_start..start]);
+                suggestion.push_str(&option.text);
+                rest_start = byte_index(query, entry.offset + entry.length);
+            }
+        }
+        suggestion.push_str(&query[rest_start..]);
+        if !suggestions.contains(&suggestion) {
+            suggestions.push(suggestion);
+        }
+    }
+    suggestions
+}
+
+/// Byte index in `text` of UTF-16 code unit `offset` (as Elasticsearch counts), clamped to length.
+fn byte_index(text: &str, offset: usize) -> usize {
+    let mut utf16_index = 0;
+    for (byte_index, c) in text.char_indices() {
+        if utf16_index >= offset {
+            return byte_index;
+        }
+        utf16_index += c.len_utf16();
+    }
+    text.len()
+}
+
+/// Construct Elasticsearch filter clauses limiting results to country given by its ISO code.
+fn country_filter(country_iso: Option<&str>) -> JsonValue {
+    match country_iso {
+        Some(iso_code) => json!([{"term": {"countryIso": iso_code}}]),
+        None => json!([]),
+    }
+}
+
+/// Get entity of type `T` from Elasticsearch index `index_name` given its `id`. Owns `es` so that
+/// the returned future is `'static` and can be shared by [coalesced()].
+async fn get_entity<T: fmt::Debug + DeserializeOwned>(
+    es: Rc<Elasticsearch>,
+    id: impl fmt::Display,
+    index_name: &str,
+    entity_name: &str,
+) -> HandlerResult<T> {
+    let id_string = id.to_string();
+    let response = send_with_retry(|| {
+        es.get(IndexTypeId(index_name, "_source", &id_string))
+            ._source_excludes(excluded_fields(index_name))
+            .send()
+    })
+    .await?;
+
+    if response.status_code() == StatusCode::NOT_FOUND {
+        return Err(NotFound(format!("{}#{} not found.", entity_name, id)));
+    }
+
+    let response = logged_error_for_status(None, response).await?;
+    let response_body = response.json::<T>().await?;
+    debug!("Elasticsearch response body: {:?}.", response_body);
+
+    Ok(response_body)
+}
+
+/// Number of Elasticsearch searches (a multi-search counts as one) since start of the service.
+static ES_SEARCHES: AtomicU64 = AtomicU64::new(0);
+/// Total time Elasticsearch reported spending on [ES_SEARCHES] in milliseconds.
+static ES_TOOK_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Record `took_ms` time Elasticsearch spent on a search, for metrics and the current request.
+fn record_took(took_ms: u64) {
+    ES_SEARCHES.fetch_add(1, Relaxed);
+    ES_TOOK_MS.fetch_add(took_ms, Relaxed);
+    es_took::record(took_ms);
+}
+
+/// Metrics of Elasticsearch searches made by the service.
+#[allow(non_snake_case)]
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct ElasticMetrics {
+    /// Number of searches since start of the service (a multi-search counts as one).
+    searches: u64,
+    /// Total time Elasticsearch reported (as `took`) spending on the searches in milliseconds.
+    tookMs: u64,
+}
+
+/// Get metrics of Elasticsearch searches made by the service.
+pub(crate) fn elastic_metrics() -> ElasticMetrics {
+    ElasticMetrics { searches: ES_SEARCHES.load(Relaxed), tookMs: ES_TOOK_MS.load(Relaxed) }
+}
+
+/// Number of retries of an Elasticsearch request rejected with HTTP 429 Too Many Requests.
+const TOO_MANY_REQUESTS_RETRIES: u32 = 2;
+/// Upper bound of delay before the first retry of a rejected request, doubled for each next one.
+const TOO_MANY_REQUESTS_BACKOFF: Duration = Duration::from_millis(50);
+/// `Retry-After` in seconds suggested to clients when Elasticsearch keeps rejecting requests.
+const TOO_MANY_REQUESTS_RETRY_AFTER_SECS: u64 = 1;
+
+/// Response to a request sent by [send_with_retry()]. Abstracted out so that retries can be tested
+/// with canned responses.
+trait WithStatusCode {
+    fn status_code(&self) -> StatusCode;
+}
+
+impl WithStatusCode for EsResponse {
+    fn status_code(&self) -> StatusCode {
+        EsResponse::status_code(self)
+    }
+}
+
+/// Send Elasticsearch request using `send` (called again for each attempt). While Elasticsearch
+/// rejects it with HTTP 429 Too Many Requests because it is overloaded, retry it a few times after
+/// a short randomized backoff. Error with HTTP 503 Service Unavailable if it keeps rejecting it.
+async fn send_with_retry<F, Fut, R>(mut send: F) -> HandlerResult<R>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<R, EsError>>,
+    R: WithStatusCode,
+{
+    let (mut backoff, mut retries_left) = (TOO_MANY_REQUESTS_BACKOFF, TOO_MANY_REQUESTS_RETRIES);
+    loop {
+        let response = send().await?;
+        if response.status_code() != StatusCode::TOO_MANY_REQUESTS {
+            return Ok(response);
+        }
+        if retries_left == 0 {
+            error!("Elasticsearch responded 429 Too Many Requests even after retries.");
+            return Err(Overloaded(
+                "Elasticsearch is overloaded.".to_string(),
+                TOO_MANY_REQUESTS_RETRY_AFTER_SECS,
+            ));
+        }
+        retries_left -= 1;
+
+        // Full jitter, so that requests rejected at once don't all retry at once again.
+        let delay = backoff.mul_f64(rand::random());
+        warn!("Elasticsearch responded 429 Too Many Requests, retrying in {:?}.", delay);
+        delay_for(delay).await;
+        backoff *= 2;
+    }
+}
+
+async fn logged_error_for_status(
+    body: Option<&JsonValue>,
+    response: EsResponse,
+) -> Result<EsResponse, EsError> {
+    // This is somewhat convoluted to satisfy Rust lifetime rules. As response.text() takes
+    // ownership of the response, we in turn also need to take its ownership. We need to use
+    // error_for_status_code_ref() (rather than the non-_ref variant) for the same reason.
+    match response.error_for_status_code_ref() {
+        Ok(_) => Ok(response),
+        Err(e) => {
+            let request = body.and_then(|val| to_string_pretty(val).ok()).unwrap_or_default();
+            let resp_text = response.text().await.unwrap_or_default();
+            error!("Elasticsearch: {}. Request:\n{}\nresponse: {}", e, request, resp_text);
+            Err(e)
+        }
+    }
+}
+
+/// Check that the city and region indices exist, either as concrete indices or as aliases (used
+/// for zero-downtime reindexing), and log which concrete indices they resolve to.
+///
+/// # Panics
+///
+/// Panics if some of the indices cannot be resolved.
+pub(crate) async fn check_indices(es: &Elasticsearch) {
+    for &index_name in &[CITY_INDEX, REGION_INDEX] {
+        let concrete_indices = resolve_index(es, index_name)
+            .await
+            .unwrap_or_else(|e| panic!("Cannot resolve index {}: {}.", index_name, e));
+        info!("Index {} resolves to {:?}.", index_name, concrete_indices);
+    }
+}
+
+/// Check that Elasticsearch is reachable and both the city and region indices resolve, so that
+/// the service can serve traffic. Like [check_indices()], but errs rather than panics.
+pub(crate) async fn check_ready(es: &Elasticsearch) -> HandlerResult<()> {
+    for &index_name in &[CITY_INDEX, REGION_INDEX] {
+        match resolve_index(es, index_name).await {
+            Ok(concrete_indices) if !concrete_indices.is_empty() => {}
+            Ok(_) => return Err(ServiceUnavailable(format!("Index {} not found.", index_name))),
+            Err(e) => {
+                warn!("Cannot resolve index {}: {}.", index_name, e);
+                return Err(ServiceUnavailable(format!("Cannot resolve index {}.", index_name)));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fields of the city index the service relies on and their allowed mapping types, see
+/// [check_mappings()]. Localized names are checked separately.
+const CITY_MAPPING_FIELDS: &[(&str, &[&str])] = &[
+    ("id", &["long", "integer"]),
+    ("centroid", &["geo_point"]),
+    ("regionId", &["long", "integer"]),
+    ("countryIso", &["keyword"]),
+    ("isFeatured", &["boolean"]),
+];
+
+/// Fields of the region index the service relies on and their allowed mapping types, see
+/// [check_mappings()]. Localized names are checked separately.
+const REGION_MAPPING_FIELDS: &[(&str, &[&str])] =
+    &[("id", &["long", "integer"]), ("centroid", &["geo_point"]), ("countryIso", &["keyword"])];
+
+/// Check that mappings of the city and region indices have the fields the service relies on with
+/// expected types, including localized names with the `autocomplete` sub-field for each
+/// supported language, logging each mismatch.
+///
+/// # Panics
+///
+/// Panics if some of the fields are missing or have unexpected types, or if the mappings cannot
+/// be fetched.
+pub(crate) async fn check_mappings(es: &Elasticsearch) {
+    let mut mismatches = Vec::new();
+    for &(index_name, fields) in
+        &[(CITY_INDEX, CITY_MAPPING_FIELDS), (REGION_INDEX, REGION_MAPPING_FIELDS)]
+    {
+        let response = es
+            .indices()
+            .get_mapping(IndicesGetMappingParts::Index(&[index_name]))
+            .send()
+            .await
+            .and_then(|it| it.error_for_status_code())
+            .unwrap_or_else(|e| panic!("Cannot fetch mapping of index {}: {}.", index_name, e));
+        // Response is keyed by names of concrete indices, which may be several for an alias.
+        let mappings = response
+            .json::<HashMap<String, JsonValue>>()
+            .await
+            .unwrap_or_else(|e| panic!("Cannot parse mapping of index {}: {}.", index_name, e));
+
+        for (concrete_index, mapping) in &mappings {
+            let properties = &mapping["mappings"]["properties"];
+            let name_fields = Language::ALL.iter().map(|it| {
+                (format!("{}.fields.autocomplete", it.name_key()), &["search_as_you_type"][..])
+            });
+            let fields = fields.iter().map(|&(field, types)| (field.to_string(), types));
+            for (field, types) in fields.chain(name_fields) {
+                let actual_type = mapping_type(properties, &field);
+                if !actual_type.map_or(false, |it| types.contains(&it)) {
+                    error!(
+                        "Index {} field {} has mapping type {:?}, expected one of {:?}.",
+                        concrete_index, field, actual_type, types
+                    );
+                    mismatches.push(format!("{}/{}", concrete_index, field));
+                }
+            }
+        }
+    }
+    if !mismatches.is_empty() {
+        panic!("Index mappings don't match expectations of the service: {:?}.", mismatches);
+    }
+    info!("Index mappings have all fields the service relies on.");
+}
+
+/// Mapping type of field at dot-separated `path` in mapping `properties`, [None] if the field is
+/// not mapped. Path segments of objects are looked up in their `properties`, other segments in
+/// `fields` (multi-fields) if the previous segment is `fields`.
+fn mapping_type<'a>(properties: &'a JsonValue, path: &str) -> Option<&'a str> {
+    let mut segments = path.split('.');
+    let mut field = properties.get(segments.next()?)?;
+    while let Some(segment) = segments.next() {
+        field = match segment {
+            "fields" => field.get("fields")?.get(segments.next()?)?,
+            segment => field.get("properties")?.get(segment)?,
+        };
+    }
+    // Object fields don't have explicit type in the mapping.
+    Some(field.get("type").and_then(JsonValue::as_str).unwrap_or("object"))
+}
+
+/// Check that all cities given by their `ids` exist, logging each missing one.
+///
+/// # Panics
+///
+/// Panics if some of the cities do not exist or cannot be fetched.
+pub(crate) async fn check_cities_exist(es: Rc<Elasticsearch>, ids: &[CityId]) {
+    let mut missing_ids = Vec::new();
+    for &id in ids {
+        match get_entity::<ElasticCity>(Rc::clone(&es), id, CITY_INDEX, "City").await {
+            Ok(_) => {}
+            Err(NotFound(_)) => {
+                error!("City {} does not exist.", id);
+                missing_ids.push(id);
+            }
+            Err(e) => panic!("Cannot fetch city {}: {}.", id, e),
+        }
+    }
+    if !missing_ids.is_empty() {
+        panic!("Cities {:?} do not exist.", missing_ids);
+    }
+    info!("All {} checked cities exist.", ids.len());
+}
+
+/// Get names of concrete indices that index or alias `index_name` resolves to.
+async fn resolve_index(es: &Elasticsearch, index_name: &str) -> Result<Vec<String>, EsError> {
+    let response = es
+        .indices()
+        .get(IndicesGetParts::Index(&[index_name]))
+        .filter_path(&["*.settings.index.provided_name"]) // keep the response small
+        .send()
+        .await?;
+    let response = logged_error_for_status(None, response).await?;
+    // Response is keyed by names of concrete indices.
+    let indices = response.json::<HashMap<String, JsonValue>>().await?;
+
+    Ok(indices.into_iter().map(|(name, _)| name).collect())
+}
+
+/// Time-to-live of entries of [REGION_CACHE], set by [init_caches()].
+static REGION_CACHE_TTL: OnceCell<Option<Duration>> = OnceCell::new();
+
+/// Cache of regions, which change rarely, shared by all threads. Entries expire after
+/// [REGION_CACHE_TTL] if it is set.
+static REGION_CACHE: Lazy<MapCache<RegionId, ElasticRegion>> =
+    Lazy::new(|| MapCache::new("region", REGION_CACHE_TTL.get().copied().flatten(), SystemClock));
+
+/// Cache of ISO codes of countries that have some cities, under the single `()` key.
+static COUNTRY_ISOS_CACHE: Lazy<MapCache<(), Vec<String>>> =
+    Lazy::new(|| MapCache::new("countries", Some(COUNTRY_ISOS_CACHE_TTL), SystemClock));
+
+/// Set up caches of the repository now rather than on first use, with entries of the region cache
+/// expiring after `region_cache_ttl` if given.
+///
+/// # Panics
+///
+/// Panics if the caches have already been set up.
+pub(crate) fn init_caches(region_cache_ttl: Option<Duration>) {
+    REGION_CACHE_TTL.set(region_cache_ttl).expect("caches are set up only once");
+    Lazy::force(&REGION_CACHE);
+}
+
+/// Set number of decimal places of coordinates and distances in kilometres in API responses.
+///
+/// # Panics
+///
+/// Panics if the precision has already been set.
+pub(crate) fn init_response_precision(coordinates_decimals: i32, distance_km_decimals: i32) {
+    COORDINATES_DECIMALS.set(coordinates_decimals).expect("precision is set only once");
+    DISTANCE_KM_DECIMALS.set(distance_km_decimals).expect("precision is set only once");
+}
+
+/// Clear cache of regions, e.g. after an update of the region index. Returns number of cleared
+/// entries.
+pub(crate) fn clear_region_cache() -> usize {
+    REGION_CACHE.clear()
+}
+
+/// Clear cache of ISO codes of countries that have some cities, e.g. after a city of a new country
+/// was indexed. Returns number of cleared entries.
+pub(crate) fn clear_country_isos_cache() -> usize {
+    COUNTRY_ISOS_CACHE.clear()
+}
+
+/// Get metrics of all caches of the repository, including coalescing of in-flight lookups (where
+/// a lookup joining one already in flight is a hit).
+pub(crate) fn cache_metrics() -> Vec<CacheMetrics> {
+    let caches: [&dyn InstrumentedCache; 4] =
+        [&*REGION_CACHE, &*COUNTRY_ISOS_CACHE, &CITY_LOOKUPS_METRICS, &REGION_LOOKUPS_METRICS];
+    caches.iter().map(|it| it.metrics()).collect()
+}
+
+/// Map of in-flight entity lookups keyed by entity id.
+type InFlight<K, T> = RefCell<HashMap<K, Shared<LocalBoxFuture<'static, HandlerResult<T>>>>>;
+
+thread_local! {
+    // Thread-local because Elasticsearch client and its futures are bound to the current thread.
+    static CITIES_IN_FLIGHT: InFlight<CityId, ElasticCity> = RefCell::new(HashMap::new());
+    static REGIONS_IN_FLIGHT: InFlight<RegionId, ElasticRegion> = RefCell::new(HashMap::new());
+}
+
+/// Instrumentation of [InFlight] maps, aggregated across all threads.
+#[derive(Debug)]
+struct InFlightMetrics {
+    name: &'static str,
+    counters: CacheCounters,
+    /// Number of lookups in flight.
+    size: AtomicUsize,
+}
+
+impl InFlightMetrics {
+    const fn new(name: &'static str) -> Self {
+        Self { name, counters: CacheCounters::new(), size: AtomicUsize::new(0) }
+    }
+}
+
+impl InstrumentedCache for InFlightMetrics {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn counters(&self) -> &CacheCounters {
+        &self.counters
+    }
+
+    fn size(&self) -> usize {
+        self.size.load(Relaxed)
+    }
+}
+
+static CITY_LOOKUPS_METRICS: InFlightMetrics = InFlightMetrics::new("cityInFlight");
+static REGION_LOOKUPS_METRICS: InFlightMetrics = InFlightMetrics::new("regionInFlight");
+
+/// Coalesce concurrent lookups of the same `key`: if a lookup of `key` is already in flight, await
+/// its result instead of calling `lookup`. Otherwise start `lookup()` and share it with lookups of
+/// `key` that come while it is in flight. Record the events to `metrics`.
+async fn coalesced<K: Copy + Eq + Hash + 'static, T: Clone + 'static>(
+    in_flight: &'static LocalKey<InFlight<K, T>>,
+    metrics: &'static InFlightMetrics,
+    key: K,
+    lookup: impl FnOnce() -> LocalBoxFuture<'static, HandlerResult<T>>,
+) -> HandlerResult<T> {
+    let shared = in_flight.with(|map| {
+        let mut map = map.borrow_mut();
+        if let Some(shared) = map.get(&key) {
+            metrics.counters.hit();
+            return shared.clone();
+        }
+        metrics.counters.miss();
+        metrics.size.fetch_add(1, Relaxed);
+        let shared = lookup().shared();
+        map.insert(key, shared.clone());
+        shared
+    });
+    let result = shared.await;
+    // All sharers try to remove the entry, the first one succeeds. At worst this removes a lookup
+    // started after this one completed, which then just won't be coalesced with new lookups.
+    if in_flight.with(|map| map.borrow_mut().remove(&key)).is_some() {
+        metrics.size.fetch_sub(1, Relaxed);
+    }
+    result
+}
+
+/// City entity mapped from Elasticsearch.
+#[allow(non_snake_case)]
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ElasticCity {
+    pub(crate) id: CityId,
+    pub(crate) regionId: RegionId,
+    pub(crate) isFeatured: bool,
+    pub(crate) countryIso: String,
+    /// IANA timezone name, e.g. `Europe/Prague`. Not all index documents carry it.
+    pub(crate) timezone: Option<String>,
+    pub(crate) centroid: Coordinates,
+    /// Time of last update of the document in milliseconds since Unix epoch. Not all index
+    /// documents carry it.
+    pub(crate) updatedAt: Option<u64>,
+    /// Number of inhabitants. Not all index documents carry it.
+    pub(crate) population: Option<u64>,
+    /// Id shared by duplicate documents of the same real city, e.g. from different imports. Not
+    /// all index documents carry it.
+    pub(crate) canonicalId: Option<CityId>,
+    /// Which part of the city matched the query, filled only by [LocationsRepository::search()]
+    /// when match info is requested.
+    #[serde(skip)]
+    pub(crate) matched_on: Option<MatchedOn>,
+
+    #[serde(flatten)] // captures rest of fields, see https://serde.rs/attr-flatten.html
+    pub(crate) names: HashMap<String, String>,
+}
+
+impl ElasticCity {
+    /// Key under which duplicate documents of the same real city collide:
+    /// [ElasticCity::canonicalId] if set, lowercase name under `name_key` and country otherwise.
+    fn dedupe_key(&self, name_key: &str) -> String {
+        match self.canonicalId {
+            Some(canonical_id) => canonical_id.to_string(),
+            None => {
+                let name = self.names.get(name_key).map(|it| it.to_lowercase()).unwrap_or_default();
+                format!("{}/{}", self.countryIso, name)
+            }
+        }
+    }
+}
+
+/// Region entity mapped from Elasticsearch.
+#[allow(non_snake_case)]
 #[derive(Clone, Debug, Deserialize)]
 pub(crate) struct ElasticRegion {
-    pub(crate) id: u64,
+    pub(crate) id: RegionId,
     pub(crate) countryIso: String,
     pub(crate) centroid: Coordinates,
+    /// Id of the parent region in hierarchy of administrative regions, if any. Not all index
+    /// documents carry it.
+    pub(crate) parentId: Option<RegionId>,
 
     #[serde(flatten)] // captures rest of fields, see https://serde.rs/attr-flatten.html
     pub(crate) names: HashMap<String, String>,
@@ -321,7 +2131,81 @@ pub(crate) struct ElasticRegion {
 
 #[derive(Debug, Deserialize)]
 struct SearchResponse<T> {
+    /// Time Elasticsearch spent on the search in milliseconds.
+    took: u64,
     hits: HitsResponse<T>,
+    /// Whether the search hit its `timeout`.
+    #[serde(default)]
+    timed_out: bool,
+    /// Whether the search hit its `terminate_after`, absent if it was not set.
+    #[serde(default)]
+    terminated_early: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct CountriesAggregationResponse {
+    took: u64,
+    aggregations: CountriesAggregations,
+}
+
+#[derive(Debug, Deserialize)]
+struct CountriesAggregations {
+    countries: TermsAggregation,
+}
+
+#[derive(Debug, Deserialize)]
+struct TermsAggregation {
+    buckets: Vec<TermsBucket>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TermsBucket {
+    key: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SuggestResponse {
+    took: u64,
+    suggest: Suggestions,
+}
+
+#[derive(Debug, Deserialize)]
+struct Suggestions {
+    names: Vec<SuggestEntry>,
+}
+
+/// Corrections of a single word of the suggested text.
+#[derive(Debug, Deserialize)]
+struct SuggestEntry {
+    /// Offset of the word in UTF-16 code units.
+    offset: usize,
+    /// Length of the word in UTF-16 code units.
+    length: usize,
+    options: Vec<SuggestOption>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SuggestOption {
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CountResponse {
+    count: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct MultiSearchResponse<T> {
+    /// Time Elasticsearch spent on the multi-search in milliseconds.
+    took: u64,
+    responses: Vec<MultiSearchItem<T>>,
+}
+
+/// Response of a single search of a multi-search. Has either `hits` or `error`.
+#[derive(Debug, Deserialize)]
+struct MultiSearchItem<T> {
+    hits: Option<HitsResponse<T>>,
+    error: Option<JsonValue>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -332,4 +2216,418 @@ struct HitsResponse<T> {
 #[derive(Debug, Deserialize)]
 struct Hit<T> {
     _source: T,
+    /// Relevance score of this hit, absent (null) if the search request sorted by other criteria.
+    #[serde(default)]
+    _score: Option<f64>,
+    /// Sort values of this hit, present only if the search request specified sorting.
+    #[serde(default)]
+    sort: Vec<JsonValue>,
+    /// Names of named queries that matched this hit.
+    #[serde(default)]
+    matched_queries: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::mock_locations_repo::block_on;
+    use futures::future::join;
+    use std::cell::Cell;
+
+    thread_local! {
+        static TEST_IN_FLIGHT: InFlight<u64, u64> = RefCell::new(HashMap::new());
+    }
+    static TEST_LOOKUPS_METRICS: InFlightMetrics = InFlightMetrics::new("testInFlight");
+
+    /// Lookup of `result` that takes a while, counting its calls in `fetches`.
+    fn counted_lookup(
+        fetches: &Rc<Cell<usize>>,
+        result: HandlerResult<u64>,
+    ) -> impl FnOnce() -> LocalBoxFuture<'static, HandlerResult<u64>> {
+        let fetches = Rc::clone(fetches);
+        move || {
+            fetches.set(fetches.get() + 1);
+            async move {
+                delay_for(Duration::from_millis(10)).await;
+                result
+            }
+            .boxed_local()
+        }
+    }
+
+    #[test]
+    fn language_codes_round_trip() {
+        for &language in Language::ALL.iter() {
+            assert_eq!(language.as_str().parse(), Ok(language));
+            assert_eq!(language.as_str().to_uppercase().parse(), Ok(language));
+            let form_value = RawStr::from_str(language.as_str());
+            assert_eq!(Language::from_form_value(form_value), Ok(language));
+        }
+        assert!("xx".parse::<Language>().is_err());
+    }
+
+    #[test]
+    fn no_closest_city_message_matches_filters() {
+        let message = no_closest_city_message;
+        assert_eq!(message(None, None), "No city found, the city index is empty.");
+        assert_eq!(message(Some(true), None), "No featured city found.");
+        assert_eq!(message(Some(false), None), "No non-featured city found.");
+        assert_eq!(message(None, Some("CZ")), "No city in country CZ found.");
+        assert_eq!(message(Some(true), Some("CZ")), "No featured city in country CZ found.");
+        assert_eq!(message(Some(false), Some("SK")), "No non-featured city in country SK found.");
+    }
+
+    #[test]
+    fn name_prefix_clause_matches_words_from_name_start() {
+        let clause = name_prefix_clause("Nové  Měs", "name.cs", 4.0).unwrap();
+        let field = "name.cs.autocomplete";
+        assert_eq!(
+            clause,
+            json!({
+                "span_first": {
+                    "match": {
+                        "span_near": {
+                            "clauses": [
+                                {"span_term": {field: "nové"}},
+                                {"span_multi": {"match": {"prefix": {field: {"value": "měs"}}}}},
+                            ],
+                            "slop": 0,
+                            "in_order": true,
+                        }
+                    },
+                    "end": 2,
+                    "boost": 4.0,
+                }
+            })
+        );
+
+        let single_word = name_prefix_clause("Pl", "name.cs", 1.0).unwrap();
+        let clauses = &single_word["span_first"]["match"]["span_near"]["clauses"];
+        assert_eq!(
+            clauses,
+            &json!([{"span_multi": {"match": {"prefix": {field: {"value": "pl"}}}}}])
+        );
+        assert_eq!(single_word["span_first"]["end"], json!(1));
+    }
+
+    #[test]
+    fn name_prefix_clause_is_skipped_without_boost_or_words() {
+        assert_eq!(name_prefix_clause("Plzeň", "name.cs", 0.0), None);
+        assert_eq!(name_prefix_clause("Plzeň", "name.cs", -1.0), None);
+        assert_eq!(name_prefix_clause("  ", "name.cs", 4.0), None);
+    }
+
+    #[test]
+    fn distances_and_coordinates_are_rounded_at_serialization() {
+        use serde_json::value::Serializer as JsonSerializer;
+
+        assert_eq!(serialize_distance_km(&12.345_678, JsonSerializer).unwrap(), json!(12.346));
+        assert_eq!(serialize_opt_distance_km(&Some(0.000_4), JsonSerializer).unwrap(), json!(0.0));
+        assert_eq!(serialize_opt_distance_km(&None, JsonSerializer).unwrap(), JsonValue::Null);
+
+        let coords = Coordinates { lat: 50.087_654_321, lon: 14.421_234_567 };
+        assert_eq!(
+            serialize_opt_coordinates(&Some(coords), JsonSerializer).unwrap(),
+            json!({"lat": 50.087_654, "lon": 14.421_235})
+        );
+    }
+
+    #[test]
+    fn exact_name_multi_match_skips_fields_without_diacritics() {
+        let fields = |exact| {
+            let clause = name_multi_match("Plzen", Language::CS, true, exact);
+            let fields: Vec<String> =
+                serde_json::from_value(clause["multi_match"]["fields"].clone()).unwrap();
+            fields
+        };
+
+        let folded = fields(false);
+        assert!(folded.contains(&"name.cs.autocomplete_ascii^4.0".to_string()));
+        assert!(folded.contains(&"name.all.autocomplete_ascii^1.0".to_string()));
+        assert!(folded.contains(&"name.de.autocomplete_ascii^2.0".to_string()));
+
+        let exact = fields(true);
+        assert!(exact.contains(&"name.cs.autocomplete^8.0".to_string()));
+        assert!(exact.iter().all(|field| !field.contains("ascii")), "{:?}", exact);
+    }
+
+    fn suggest_entries(entries: JsonValue) -> Vec<SuggestEntry> {
+        serde_json::from_value(entries).expect("valid suggest entries")
+    }
+
+    #[test]
+    fn suggestions_replace_misspelled_words() {
+        let entries = suggest_entries(json!([
+            {"offset": 0, "length": 4, "options": [{"text": "nové"}, {"text": "nova"}]},
+            {"offset": 5, "length": 3, "options": []},
+            {"offset": 9, "length": 4, "options": [{"text": "mesto"}]},
+        ]));
+        assert_eq!(
+            combine_suggestions("nove nad msto", &entries),
+            vec!["nové nad mesto", "nova nad mesto"]
+        );
+        assert_eq!(combine_suggestions("prha", &[]), Vec::<String>::new());
+        let no_options = suggest_entries(json!([{"offset": 0, "length": 4, "options": []}]));
+        assert_eq!(combine_suggestions("prha", &no_options), Vec::<String>::new());
+    }
+
+    #[test]
+    fn suggestion_offsets_are_utf16() {
+        let entries =
+            suggest_entries(json!([{"offset": 9, "length": 6, "options": [{"text": "Sázavou"}]}]));
+        assert_eq!(combine_suggestions("Žďár nad Sazvou", &entries), vec!["Žďár nad Sázavou"]);
+
+        // The emoji takes two UTF-16 code units.
+        let entries =
+            suggest_entries(json!([{"offset": 3, "length": 4, "options": [{"text": "praha"}]}]));
+        assert_eq!(combine_suggestions("😀 prha", &entries), vec!["😀 praha"]);
+    }
+
+    #[test]
+    fn overlapping_suggestions_are_skipped() {
+        let entries = suggest_entries(json!([
+            {"offset": 0, "length": 8, "options": [{"text": "newyork"}]},
+            {"offset": 4, "length": 4, "options": [{"text": "yorkshire"}]},
+        ]));
+        assert_eq!(combine_suggestions("new york", &entries), vec!["newyork"]);
+
+        let entries = suggest_entries(json!([
+            {"offset": 4, "length": 4, "options": [{"text": "yorks"}]},
+            {"offset": 0, "length": 3, "options": [{"text": "now"}]},
+        ]));
+        assert_eq!(combine_suggestions("new york", &entries), vec!["new yorks"]);
+    }
+
+    #[test]
+    fn featured_clause_adds_boost_to_featured_cities() {
+        assert_eq!(
+            featured_clause(1.5),
+            Some(json!({
+                "constant_score": {
+                    "filter": {"term": {"isFeatured": true}},
+                    "boost": 1.5,
+                }
+            }))
+        );
+        assert_eq!(featured_clause(0.0), None);
+        assert_eq!(featured_clause(-1.0), None);
+    }
+
+    #[test]
+    fn name_phrase_clause_boosts_multi_word_phrases() {
+        assert_eq!(
+            name_phrase_clause("Nové Město", "name.cs", 2.0, false),
+            Some(json!({
+                "multi_match": {
+                    "query": "Nové Město",
+                    "fields": ["name.cs", "name.cs.autocomplete_ascii"],
+                    "type": "phrase",
+                    "boost": 2.0,
+                }
+            }))
+        );
+        let exact = name_phrase_clause("Nové Město", "name.cs", 2.0, true).unwrap();
+        assert_eq!(exact["multi_match"]["fields"], json!(["name.cs"]));
+    }
+
+    #[test]
+    fn name_phrase_clause_is_skipped_for_single_words_or_without_boost() {
+        assert_eq!(name_phrase_clause("Praha", "name.cs", 2.0, false), None);
+        assert_eq!(name_phrase_clause(" Praha  ", "name.cs", 2.0, false), None);
+        assert_eq!(name_phrase_clause("Nové Město", "name.cs", 0.0, false), None);
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let search_after = SearchAfter(vec![json!(1_600_000_000_000_u64), json!(42)]);
+        let cursor = search_after.to_cursor(CursorKind::Changes);
+        let decoded = SearchAfter::from_cursor(&cursor, CursorKind::Changes).unwrap();
+        assert_eq!(decoded.0, search_after.0);
+    }
+
+    #[test]
+    fn cursor_of_other_endpoint_is_rejected() {
+        let list_cursor = SearchAfter(vec![json!(42)]).to_cursor(CursorKind::List);
+        let result = SearchAfter::from_cursor(&list_cursor, CursorKind::Changes);
+        assert!(matches!(result, Err(BadRequest(message)) if message.contains("another endpoint")));
+
+        let changes_cursor = SearchAfter(vec![json!(1), json!(42)]).to_cursor(CursorKind::Changes);
+        let result = SearchAfter::from_cursor(&changes_cursor, CursorKind::List);
+        assert!(matches!(result, Err(BadRequest(message)) if message.contains("another endpoint")));
+    }
+
+    #[test]
+    fn malformed_cursors_are_rejected() {
+        let cursors = [
+            "",
+            "l",
+            "x5b34325d",   // Unknown tag.
+            "l5b34325",    // Odd number of hex digits.
+            "l5b34zz5d",   // Not hex.
+            "l7b7d",       // `{}`, not an array.
+            "l5b2261225d", // `["a"]`, not integers.
+            "l5b312c325d", // `[1,2]`, too many sort values.
+        ];
+        for cursor in cursors.iter() {
+            let result = SearchAfter::from_cursor(cursor, CursorKind::List);
+            assert!(matches!(result, Err(BadRequest(_))), "cursor {:?}", cursor);
+        }
+    }
+
+    struct CannedResponse(StatusCode);
+
+    impl WithStatusCode for CannedResponse {
+        fn status_code(&self) -> StatusCode {
+            self.0
+        }
+    }
+
+    /// Send using [send_with_retry()] to a server that responds with `statuses` in turn, return
+    /// the result and the number of requests sent.
+    fn send_to(statuses: &[StatusCode]) -> (HandlerResult<StatusCode>, usize) {
+        let mut sent = 0;
+        let result = block_on(send_with_retry(|| {
+            let status = statuses[sent];
+            sent += 1;
+            futures::future::ready(Ok(CannedResponse(status)))
+        }));
+        (result.map(|response| response.0), sent)
+    }
+
+    #[test]
+    fn too_many_requests_are_retried() {
+        let (result, sent) = send_to(&[StatusCode::TOO_MANY_REQUESTS, StatusCode::OK]);
+        assert_eq!((result.unwrap(), sent), (StatusCode::OK, 2));
+
+        // Other errors are left to the caller.
+        let (result, sent) = send_to(&[StatusCode::NOT_FOUND]);
+        assert_eq!((result.unwrap(), sent), (StatusCode::NOT_FOUND, 1));
+    }
+
+    #[test]
+    fn persistent_too_many_requests_overload() {
+        let (result, sent) = send_to(&[StatusCode::TOO_MANY_REQUESTS; 3]);
+        assert_eq!(sent, 3);
+        assert!(matches!(result, Err(Overloaded(_, TOO_MANY_REQUESTS_RETRY_AFTER_SECS))));
+    }
+
+    #[test]
+    fn concurrent_lookups_are_coalesced() {
+        let fetches = Rc::new(Cell::new(0));
+        let (first, second) = block_on(join(
+            coalesced(&TEST_IN_FLIGHT, &TEST_LOOKUPS_METRICS, 1, counted_lookup(&fetches, Ok(42))),
+            coalesced(&TEST_IN_FLIGHT, &TEST_LOOKUPS_METRICS, 1, counted_lookup(&fetches, Ok(43))),
+        ));
+
+        assert_eq!(fetches.get(), 1);
+        assert_eq!((first.unwrap(), second.unwrap()), (42, 42));
+        assert!(TEST_IN_FLIGHT.with(|map| map.borrow().is_empty()));
+    }
+
+    #[test]
+    fn lookup_error_reaches_all_waiters() {
+        let fetches = Rc::new(Cell::new(0));
+        let error = || Err(InternalServerError("Lookup failed.".to_string()));
+        let (first, second) = block_on(join(
+            coalesced(&TEST_IN_FLIGHT, &TEST_LOOKUPS_METRICS, 2, counted_lookup(&fetches, error())),
+            coalesced(&TEST_IN_FLIGHT, &TEST_LOOKUPS_METRICS, 2, counted_lookup(&fetches, error())),
+        ));
+
+        assert_eq!(fetches.get(), 1);
+        assert!(matches!(first, Err(InternalServerError(_))));
+        assert!(matches!(second, Err(InternalServerError(_))));
+
+        // Once finished, the failed lookup is not shared with new lookups.
+        let third = block_on(coalesced(
+            &TEST_IN_FLIGHT,
+            &TEST_LOOKUPS_METRICS,
+            2,
+            counted_lookup(&fetches, Ok(42)),
+        ));
+        assert_eq!((fetches.get(), third.unwrap()), (2, 42));
+    }
+
+    /// Filter top-level fields of `document` by `source` the way Elasticsearch would, missing
+    /// `includes` include all fields.
+    fn filter_source(document: &JsonValue, source: &JsonValue) -> JsonValue {
+        let contains = |key: &str, field: &str| match source[key].as_array() {
+            Some(fields) => fields.iter().any(|it| it.as_str() == Some(field)),
+            None => key == "includes",
+        };
+        let fields = document
+            .as_object()
+            .unwrap()
+            .iter()
+            .filter(|(field, _)| contains("includes", field) && !contains("excludes", field));
+        JsonValue::Object(fields.map(|(field, value)| (field.clone(), value.clone())).collect())
+    }
+
+    #[test]
+    fn localized_source_keeps_all_city_fields() {
+        let document = json!({
+            "id": 1,
+            "regionId": 2,
+            "isFeatured": true,
+            "countryIso": "CZ",
+            "timezone": "Europe/Prague",
+            "centroid": {"lat": 50.08, "lon": 14.42},
+            "updatedAt": 1_600_000_000_000u64,
+            "population": 1_300_000,
+            "canonicalId": 3,
+            "geometry": {"type": "point", "coordinates": [14.42, 50.08]},
+            "name.cs": "Praha",
+            "name.en": "Prague",
+            "officialName.cs": "Hlavní město Praha",
+            "officialName.en": "Capital City of Prague",
+        });
+        let filtered = filter_source(&document, &localized_source(CITY_INDEX, Language::CS));
+        // What get() fetches, see get_entity().
+        let unfiltered =
+            filter_source(&document, &json!({"excludes": excluded_fields(CITY_INDEX)}));
+        let full: ElasticCity = serde_json::from_value(unfiltered).unwrap();
+        let city: ElasticCity = serde_json::from_value(filtered).unwrap();
+
+        assert_eq!(
+            (city.id, city.regionId, city.isFeatured, city.countryIso.as_str()),
+            (full.id, full.regionId, full.isFeatured, full.countryIso.as_str())
+        );
+        assert_eq!(
+            (city.timezone.as_deref(), city.centroid),
+            (Some("Europe/Prague"), full.centroid)
+        );
+        assert_eq!(
+            (city.updatedAt, city.population, city.canonicalId),
+            (full.updatedAt, full.population, full.canonicalId)
+        );
+        let mut names: Vec<_> = city.names.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        assert_eq!(names, vec!["name.cs", "officialName.cs"]);
+    }
+
+    #[test]
+    fn localized_source_keeps_all_region_fields() {
+        let document = json!({
+            "id": 2,
+            "countryIso": "CZ",
+            "centroid": {"lat": 49.8, "lon": 15.5},
+            "parentId": 4,
+            "population": 10_700_000,
+            "geometry": {"type": "point", "coordinates": [15.5, 49.8]},
+            "name.cs": "Česko",
+            "name.en": "Czechia",
+        });
+        let filtered = filter_source(&document, &localized_source(REGION_INDEX, Language::EN));
+        // What get() fetches, see get_entity().
+        let unfiltered =
+            filter_source(&document, &json!({"excludes": excluded_fields(REGION_INDEX)}));
+        let full: ElasticRegion = serde_json::from_value(unfiltered).unwrap();
+        let region: ElasticRegion = serde_json::from_value(filtered).unwrap();
+
+        assert_eq!(
+            (region.id, region.countryIso.as_str(), region.centroid, region.parentId),
+            (full.id, full.countryIso.as_str(), full.centroid, full.parentId)
+        );
+        assert!(region.parentId.is_some());
+        assert_eq!(region.names.keys().collect::<Vec<_>>(), vec!["name.en"]);
+    }
 }
@@ -0,0 +1,446 @@
+//! In-memory implementation of [LocationsRepository] to test handler logic without Elasticsearch.
+
+use crate::{
+    response::{
+        ErrorResponse::{InternalServerError, NotFound},
+        HandlerResult,
+    },
+    services::locations_repo::{
+        no_closest_city_message, ApproximateScoring, BoundingBoxOrder, CityId, Coordinates,
+        ElasticCity, ElasticRegion, Language, LocationsRepository, RegionId, SearchAfter,
+        SearchOptions, SearchResults,
+    },
+};
+use futures::future::{ready, Future, FutureExt, LocalBoxFuture};
+use serde_json::json;
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BTreeSet, HashMap},
+};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Repository of given cities and regions, kept in the order given. Unlike Elasticsearch, search
+/// matches case-insensitive (and unless exact, diacritics-insensitive) substrings of localized
+/// names and scores all matches 1.
+#[derive(Default)]
+pub(crate) struct MockLocationsRepository {
+    pub(crate) cities: Vec<ElasticCity>,
+    pub(crate) regions: Vec<ElasticRegion>,
+    /// Whether fetching any region fails with Internal Server Error, e.g. as if the region index
+    /// was unavailable.
+    pub(crate) failing_regions: bool,
+}
+
+/// Build a non-featured Czech city in region 1 at `0,0`, named `name` in all languages.
+pub(crate) fn city(id: u64, name: &str) -> ElasticCity {
+    ElasticCity {
+        id: CityId(id),
+        regionId: RegionId(1),
+        isFeatured: false,
+        countryIso: "CZ".to_string(),
+        timezone: None,
+        centroid: Coordinates { lat: 0.0, lon: 0.0 },
+        updatedAt: None,
+        population: None,
+        canonicalId: None,
+        matched_on: None,
+        names: names(name),
+    }
+}
+
+/// Build a top-level Czech region at `0,0`, named `name` in all languages.
+pub(crate) fn region(id: u64, name: &str) -> ElasticRegion {
+    ElasticRegion {
+        id: RegionId(id),
+        countryIso: "CZ".to_string(),
+        centroid: Coordinates { lat: 0.0, lon: 0.0 },
+        parentId: None,
+        names: names(name),
+    }
+}
+
+/// Run `future` of handler logic to completion on a fresh async runtime.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    crate::create_async_rt().block_on(future)
+}
+
+fn names(name: &str) -> HashMap<String, String> {
+    Language::ALL.iter().map(|language| (language.name_key(), name.to_string())).collect()
+}
+
+impl MockLocationsRepository {
+    fn find_city(&self, id: CityId) -> HandlerResult<ElasticCity> {
+        let city = self.cities.iter().find(|c| c.id == id);
+        city.cloned().ok_or_else(|| NotFound(format!("City#{} not found.", id)))
+    }
+
+    fn find_region(&self, id: RegionId) -> HandlerResult<ElasticRegion> {
+        if self.failing_regions {
+            return Err(InternalServerError(format!("Region#{} cannot be fetched.", id)));
+        }
+        let region = self.regions.iter().find(|r| r.id == id);
+        region.cloned().ok_or_else(|| NotFound(format!("Region#{} not found.", id)))
+    }
+
+    fn find_region_hierarchy(&self, id: RegionId) -> HandlerResult<Vec<ElasticRegion>> {
+        let mut regions = vec![self.find_region(id)?];
+        while let Some(parent_id) = regions.last().and_then(|it| it.parentId) {
+            regions.push(self.find_region(parent_id)?);
+        }
+        Ok(regions)
+    }
+
+    /// Cities matching given filters, closest to `coords` first.
+    fn closest_cities(
+        &self,
+        coords: Coordinates,
+        is_featured: Option<bool>,
+        country_iso: Option<&str>,
+    ) -> Vec<ElasticCity> {
+        let mut cities: Vec<_> = self
+            .cities
+            .iter()
+            .filter(|c| is_featured.map_or(true, |it| c.isFeatured == it))
+            .filter(|c| country_iso.map_or(true, |it| c.countryIso == it))
+            .cloned()
+            .collect();
+        cities.sort_by(|a, b| {
+            let (a, b) = (a.centroid.distance_km(coords), b.centroid.distance_km(coords));
+            a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+        });
+        cities
+    }
+
+    fn closest_city(
+        &self,
+        coords: Coordinates,
+        is_featured: Option<bool>,
+        country_iso: Option<&str>,
+    ) -> HandlerResult<ElasticCity> {
+        let closest = self.closest_cities(coords, is_featured, country_iso).into_iter().next();
+        closest.ok_or_else(|| NotFound(no_closest_city_message(is_featured, country_iso)))
+    }
+}
+
+/// Sort `cities` by `order` the way Elasticsearch does, keep the first `size` of them.
+fn sorted_cities(
+    mut cities: Vec<ElasticCity>,
+    order: Option<BoundingBoxOrder>,
+    size: usize,
+) -> Vec<ElasticCity> {
+    match order {
+        Some(BoundingBoxOrder::Featured) => {
+            cities.sort_by_key(|c| (Reverse(c.isFeatured), Reverse(c.population)))
+        }
+        Some(BoundingBoxOrder::Population) => cities.sort_by_key(|c| Reverse(c.population)),
+        None => {}
+    }
+    cities.truncate(size);
+    cities
+}
+
+/// Whether `point` lies within polygon given by its `vertices`, using ray casting.
+fn in_polygon(point: Coordinates, vertices: &[Coordinates]) -> bool {
+    let mut inside = false;
+    let mut previous = match vertices.last() {
+        Some(&last) => last,
+        None => return false,
+    };
+    for &vertex in vertices {
+        if (vertex.lat > point.lat) != (previous.lat > point.lat)
+            && point.lon
+                < (previous.lon - vertex.lon) * (point.lat - vertex.lat)
+                    / (previous.lat - vertex.lat)
+                    + vertex.lon
+        {
+            inside = !inside;
+        }
+        previous = vertex;
+    }
+    inside
+}
+
+impl LocationsRepository for MockLocationsRepository {
+    fn get_city(&self, id: CityId) -> LocalBoxFuture<'_, HandlerResult<ElasticCity>> {
+        ready(self.find_city(id)).boxed_local()
+    }
+
+    fn get_cities(&self, ids: &[CityId]) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>> {
+        let cities = self.cities.iter().filter(|c| ids.contains(&c.id)).cloned().collect();
+        ready(Ok(cities)).boxed_local()
+    }
+
+    fn get_region(&self, id: RegionId) -> LocalBoxFuture<'_, HandlerResult<ElasticRegion>> {
+        ready(self.find_region(id)).boxed_local()
+    }
+
+    fn get_region_hierarchy(
+        &self,
+        id: RegionId,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticRegion>>> {
+        ready(self.find_region_hierarchy(id)).boxed_local()
+    }
+
+    fn get_featured_cities(&self) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>> {
+        let cities = self.cities.iter().filter(|c| c.isFeatured).cloned().collect();
+        ready(Ok(cities)).boxed_local()
+    }
+
+    fn get_country_main_city(
+        &self,
+        country_iso: &str,
+        is_featured: Option<bool>,
+    ) -> LocalBoxFuture<'_, HandlerResult<Option<ElasticCity>>> {
+        let city = self
+            .cities
+            .iter()
+            .filter(|c| c.countryIso == country_iso)
+            .filter(|c| is_featured.map_or(true, |it| c.isFeatured == it))
+            .min_by_key(|c| (Reverse(c.isFeatured), Reverse(c.population)))
+            .cloned();
+        ready(Ok(city)).boxed_local()
+    }
+
+    fn count_featured_cities(
+        &self,
+        country_iso: Option<&str>,
+    ) -> LocalBoxFuture<'_, HandlerResult<u64>> {
+        let count = self
+            .cities
+            .iter()
+            .filter(|c| c.isFeatured && country_iso.map_or(true, |it| c.countryIso == it))
+            .count();
+        ready(Ok(count as u64)).boxed_local()
+    }
+
+    fn get_region_cities(
+        &self,
+        id: RegionId,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>> {
+        let cities = self.cities.iter().filter(|c| c.regionId == id).cloned().collect();
+        ready(Ok(cities)).boxed_local()
+    }
+
+    fn list_cities(
+        &self,
+        size: usize,
+        search_after: Option<SearchAfter>,
+    ) -> LocalBoxFuture<'_, HandlerResult<(Vec<ElasticCity>, Option<SearchAfter>)>> {
+        let after_id = search_after.and_then(|it| it.0.first().and_then(|id| id.as_u64()));
+        let mut cities: Vec<_> =
+            self.cities.iter().filter(|c| Some(c.id.0) > after_id).cloned().collect();
+        cities.sort_by_key(|c| c.id.0);
+        let next = cities.get(size).map(|_| SearchAfter(vec![json!(cities[size - 1].id)]));
+        cities.truncate(size);
+        ready(Ok((cities, next))).boxed_local()
+    }
+
+    fn get_changed_cities(
+        &self,
+        size: usize,
+        since: Option<u64>,
+        search_after: Option<SearchAfter>,
+    ) -> LocalBoxFuture<'_, HandlerResult<(Vec<ElasticCity>, Option<SearchAfter>)>> {
+        let after = search_after.map(|it| {
+            let value = |i: usize| it.0.get(i).and_then(|v| v.as_u64()).unwrap_or_default();
+            (value(0), value(1))
+        });
+        let mut cities: Vec<_> = self
+            .cities
+            .iter()
+            .filter(|c| c.updatedAt.map_or(false, |at| since.map_or(true, |since| at > since)))
+            .filter(|c| after.map_or(true, |after| (c.updatedAt.unwrap(), c.id.0) > after))
+            .cloned()
+            .collect();
+        cities.sort_by_key(|c| (c.updatedAt, c.id.0));
+        cities.truncate(size);
+        let last = cities.last().map(|c| SearchAfter(vec![json!(c.updatedAt), json!(c.id)]));
+        ready(Ok((cities, last))).boxed_local()
+    }
+
+    fn get_country_isos(&self) -> LocalBoxFuture<'_, HandlerResult<Vec<String>>> {
+        let isos: BTreeSet<_> = self.cities.iter().map(|c| c.countryIso.clone()).collect();
+        ready(Ok(isos.into_iter().collect())).boxed_local()
+    }
+
+    fn search(
+        &self,
+        query: &str,
+        language: Language,
+        country_iso: Option<&str>,
+        options: SearchOptions,
+    ) -> LocalBoxFuture<'_, HandlerResult<SearchResults<ElasticCity>>> {
+        // Like the `autocomplete_ascii` fields, ignore diacritics unless `exact` is requested.
+        let fold = |text: &str| -> String {
+            let lowercase = text.to_lowercase();
+            if options.exact {
+                lowercase
+            } else {
+                lowercase.nfd().filter(|&c| !is_combining_mark(c)).collect()
+            }
+        };
+        let (query, name_key) = (fold(query), language.name_key());
+        let items = self
+            .cities
+            .iter()
+            .filter(|c| country_iso.map_or(true, |it| c.countryIso == it))
+            .filter(|c| c.names.get(&name_key).map_or(false, |n| fold(n).contains(&query)))
+            .filter(|_| options.min_score <= 1.0)
+            .take(options.size)
+            .cloned()
+            .collect();
+        ready(Ok(SearchResults { items, partial: false })).boxed_local()
+    }
+
+    fn suggest_city_names(
+        &self,
+        _query: &str,
+        _language: Language,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<String>>> {
+        ready(Ok(Vec::new())).boxed_local()
+    }
+
+    fn get_cities_by_name(
+        &self,
+        name: &str,
+        language: Language,
+        country_iso: &str,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>> {
+        let name_key = language.name_key();
+        let cities = self
+            .cities
+            .iter()
+            .filter(|c| c.countryIso == country_iso)
+            .filter(|c| c.names.get(&name_key).map(String::as_str) == Some(name))
+            .take(2)
+            .cloned()
+            .collect();
+        ready(Ok(cities)).boxed_local()
+    }
+
+    fn search_regions(
+        &self,
+        query: &str,
+        language: Language,
+        country_iso: Option<&str>,
+    ) -> LocalBoxFuture<'_, HandlerResult<SearchResults<ElasticRegion>>> {
+        let (query, name_key) = (query.to_lowercase(), language.name_key());
+        let items = self
+            .regions
+            .iter()
+            .filter(|r| country_iso.map_or(true, |it| r.countryIso == it))
+            .filter(|r| r.names.get(&name_key).map_or(false, |n| n.to_lowercase().contains(&query)))
+            .cloned()
+            .collect();
+        ready(Ok(SearchResults { items, partial: false })).boxed_local()
+    }
+
+    fn get_city_by_coords(
+        &self,
+        coords: Coordinates,
+        is_featured: Option<bool>,
+        country_iso: Option<&str>,
+    ) -> LocalBoxFuture<'_, HandlerResult<ElasticCity>> {
+        ready(self.closest_city(coords, is_featured, country_iso)).boxed_local()
+    }
+
+    fn get_city_by_approximate_coords(
+        &self,
+        coords: Coordinates,
+        _scoring: ApproximateScoring,
+        is_featured: Option<bool>,
+        country_iso: Option<&str>,
+    ) -> LocalBoxFuture<'_, HandlerResult<ElasticCity>> {
+        ready(self.closest_city(coords, is_featured, country_iso)).boxed_local()
+    }
+
+    fn get_closest_city(
+        &self,
+        coords: Coordinates,
+        is_featured: Option<bool>,
+        country_iso: Option<&str>,
+    ) -> LocalBoxFuture<'_, HandlerResult<ElasticCity>> {
+        ready(self.closest_city(coords, is_featured, country_iso)).boxed_local()
+    }
+
+    fn get_closest_city_per_country(
+        &self,
+        coords: Coordinates,
+        country_isos: &[String],
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<Option<ElasticCity>>>> {
+        let cities = country_isos
+            .iter()
+            .map(|iso| self.closest_cities(coords, None, Some(iso)).into_iter().next())
+            .collect();
+        ready(Ok(cities)).boxed_local()
+    }
+
+    fn get_city_candidates(
+        &self,
+        coords: Coordinates,
+        _scoring: Option<ApproximateScoring>,
+        is_featured: Option<bool>,
+        country_iso: Option<&str>,
+        size: usize,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<(ElasticCity, Option<f64>)>>> {
+        let candidates = self
+            .closest_cities(coords, is_featured, country_iso)
+            .into_iter()
+            .take(size)
+            .map(|c| (c, None))
+            .collect();
+        ready(Ok(candidates)).boxed_local()
+    }
+
+    fn get_neighbor_cities(
+        &self,
+        id: CityId,
+        coords: Coordinates,
+        size: usize,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>> {
+        let cities = self
+            .closest_cities(coords, None, None)
+            .into_iter()
+            .filter(|c| c.id != id)
+            .take(size)
+            .collect();
+        ready(Ok(cities)).boxed_local()
+    }
+
+    fn get_cities_in_bounding_box(
+        &self,
+        top_left: Coordinates,
+        bottom_right: Coordinates,
+        order: Option<BoundingBoxOrder>,
+        size: usize,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>> {
+        let cities = self
+            .cities
+            .iter()
+            .filter(|c| (bottom_right.lat..=top_left.lat).contains(&c.centroid.lat))
+            .filter(|c| (top_left.lon..=bottom_right.lon).contains(&c.centroid.lon))
+            .cloned()
+            .collect();
+        ready(Ok(sorted_cities(cities, order, size))).boxed_local()
+    }
+
+    fn get_cities_in_polygon(
+        &self,
+        vertices: &[Coordinates],
+        order: Option<BoundingBoxOrder>,
+        size: usize,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>> {
+        let cities =
+            self.cities.iter().filter(|c| in_polygon(c.centroid, vertices)).cloned().collect();
+        ready(Ok(sorted_cities(cities, order, size))).boxed_local()
+    }
+
+    fn get_cities_by_coords(
+        &self,
+        points: Vec<Coordinates>,
+    ) -> LocalBoxFuture<'_, HandlerResult<Vec<ElasticCity>>> {
+        let cities =
+            points.into_iter().map(|coords| self.closest_city(coords, None, None)).collect();
+        ready(cities).boxed_local()
+    }
+}
@@ -0,0 +1,177 @@
+//! Elasticsearch query bodies loadable from JSON template files, so that relevance can be tuned
+//! without code changes. Built-in query bodies are used for queries without a template.
+//!
+//! A template is a JSON search body where JSON strings of the form `"{{name}}"` are placeholders,
+//! replaced by JSON values (not necessarily strings) when the query is run. Object keys can be
+//! placeholders too, e.g. `{"term": {"{{nameKey}}": "{{query}}"}}`; they are replaced by the string
+//! value, or by the JSON text of other values.
+
+use log::info;
+use once_cell::sync::OnceCell;
+use serde_json::{Map, Value as JsonValue};
+use std::{collections::HashMap, fs};
+
+/// Kinds of Elasticsearch queries whose body can be given by a template.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum QueryKind {
+    /// City search, placeholders:
+    /// - `query`: the search query string,
+    /// - `language`: code of the requested language, e.g. `"cs"`,
+    /// - `nameKey`: field of names in the requested language, e.g. `"name.cs"`,
+    /// - `nameMatch`: the built-in query clause matching names,
+    /// - `should`: array of optional clauses (match info, name prefix, phrase and featured
+    ///   boosts),
+    /// - `filter`: array of filter clauses (country).
+    ///
+    /// The service sets `min_score` and `_source` of the rendered body itself.
+    Search,
+    /// Cities sorted by distance from a point, placeholders:
+    /// - `coords`: the point as an object with `lat` and `lon`,
+    /// - `filter`: array of filter clauses (featured, country).
+    Closest,
+}
+
+impl QueryKind {
+    /// Names of placeholders the template may use.
+    fn placeholders(self) -> &'static [&'static str] {
+        match self {
+            QueryKind::Search => &["query", "language", "nameKey", "nameMatch", "should", "filter"],
+            QueryKind::Closest => &["coords", "filter"],
+        }
+    }
+}
+
+/// Templates loaded by [init()], none until it is called.
+static TEMPLATES: OnceCell<HashMap<QueryKind, JsonValue>> = OnceCell::new();
+
+/// Load and validate templates from files given by `paths` of query kinds, so that the service
+/// fails fast on bad ones.
+///
+/// # Panics
+///
+/// Panics if a template cannot be read, is not a JSON object or uses an unknown placeholder, or if
+/// templates have already been loaded.
+pub(crate) fn init(paths: &[(QueryKind, String)]) {
+    let templates: HashMap<_, _> = paths
+        .iter()
+        .map(|(kind, path)| {
+            let template = load_template(*kind, path)
+                .unwrap_or_else(|e| panic!("{:?} query template {}: {}", kind, path, e));
+            info!("Loaded {:?} query template from {}.", kind, path);
+            (*kind, template)
+        })
+        .collect();
+    info!("Elasticsearch queries given by templates: {:?}.", templates.keys().collect::<Vec<_>>());
+    TEMPLATES.set(templates).expect("query templates are loaded only once");
+}
+
+/// Render template of `kind` with placeholders replaced by `values`, [None] if there is no
+/// template for `kind` and the built-in query body should be used.
+pub(crate) fn render(kind: QueryKind, values: &[(&str, JsonValue)]) -> Option<JsonValue> {
+    TEMPLATES.get()?.get(&kind).map(|template| substitute(template, values))
+}
+
+fn load_template(kind: QueryKind, path: &str) -> Result<JsonValue, String> {
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let template: JsonValue = serde_json::from_str(&content).map_err(|e| e.to_string())?;
+    if !template.is_object() {
+        return Err("template must be a JSON object".to_string());
+    }
+
+    let mut used = Vec::new();
+    collect_placeholders(&template, &mut used);
+    match used.iter().find(|name| !kind.placeholders().contains(name)) {
+        Some(unknown) => Err(format!(
+            "unknown placeholder `{}`, expected some of {:?}",
+            unknown,
+            kind.placeholders()
+        )),
+        None => Ok(template),
+    }
+}
+
+/// Name of placeholder `value` if it is one.
+fn placeholder_name(value: &JsonValue) -> Option<&str> {
+    placeholder_key_name(value.as_str()?)
+}
+
+/// Name of placeholder object `key` if it is one.
+fn placeholder_key_name(key: &str) -> Option<&str> {
+    key.strip_prefix("{{")?.strip_suffix("}}")
+}
+
+fn collect_placeholders<'a>(template: &'a JsonValue, names: &mut Vec<&'a str>) {
+    match template {
+        JsonValue::Array(items) => items.iter().for_each(|it| collect_placeholders(it, names)),
+        JsonValue::Object(map) => map.iter().for_each(|(key, value)| {
+            names.extend(placeholder_key_name(key));
+            collect_placeholders(value, names);
+        }),
+        value => names.extend(placeholder_name(value)),
+    }
+}
+
+fn substitute(template: &JsonValue, values: &[(&str, JsonValue)]) -> JsonValue {
+    match template {
+        JsonValue::Array(items) => items.iter().map(|it| substitute(it, values)).collect(),
+        JsonValue::Object(map) => JsonValue::Object(
+            map.iter()
+                .map(|(key, value)| (substitute_key(key, values), substitute(value, values)))
+                .collect::<Map<_, _>>(),
+        ),
+        value => placeholder_name(value)
+            .and_then(|name| values.iter().find(|(it, _)| *it == name))
+            .map_or_else(|| value.clone(), |(_, replacement)| replacement.clone()),
+    }
+}
+
+fn substitute_key(key: &str, values: &[(&str, JsonValue)]) -> String {
+    let replacement =
+        placeholder_key_name(key).and_then(|name| values.iter().find(|(it, _)| *it == name));
+    match replacement {
+        Some((_, JsonValue::String(string))) => string.clone(),
+        Some((_, value)) => value.to_string(),
+        None => key.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn placeholders_are_substituted_in_values_and_keys() {
+        let template = json!({
+            "query": {"term": {"{{nameKey}}": "{{query}}"}},
+            "filter": "{{filter}}",
+            "{{unknown}}": "{{unknown}}",
+            "{{language}}x": "x{{language}}",
+        });
+        let values = [
+            ("nameKey", json!("name.cs")),
+            ("query", json!("Plzeň")),
+            ("filter", json!([{"term": {"countryIso": "CZ"}}])),
+            ("language", json!("cs")),
+        ];
+        assert_eq!(
+            substitute(&template, &values),
+            json!({
+                "query": {"term": {"name.cs": "Plzeň"}},
+                "filter": [{"term": {"countryIso": "CZ"}}],
+                "{{unknown}}": "{{unknown}}",
+                "{{language}}x": "x{{language}}",
+            })
+        );
+        assert_eq!(substitute(&json!({"{{size}}": 1}), &[("size", json!(10))]), json!({"10": 1}));
+    }
+
+    #[test]
+    fn placeholders_are_collected_from_values_and_keys() {
+        let template = json!({"{{nameKey}}": ["{{query}}", {"a": "{{filter}}"}], "b": "{{c"});
+        let mut names = Vec::new();
+        collect_placeholders(&template, &mut names);
+        names.sort();
+        assert_eq!(names, vec!["filter", "nameKey", "query"]);
+    }
+}
@@ -1,35 +1,60 @@
 //! Elasticsearch client with a connection pool.
 
-use elasticsearch::{http::transport::Transport, Elasticsearch};
+use crate::config::{ElasticConfig, ElasticLocation};
+use elasticsearch::{
+    auth::Credentials,
+    http::transport::{CloudConnectionPool, SingleNodeConnectionPool, TransportBuilder},
+    Elasticsearch,
+};
 use log::info;
-use std::{env, rc::Rc, thread};
+use std::{rc::Rc, thread};
 
 /// Trait to be implemented by application states that contain stateful Elasticsearch client.
 pub(crate) trait WithElastic {
     /// Get reference to stateful Elasticsearch client.
     fn elasticsearch(&self) -> Rc<Elasticsearch>;
+
+    /// Get Elasticsearch `preference` to send with search requests, if configured.
+    fn elastic_preference(&self) -> Option<&str>;
+
+    /// Get Elasticsearch `timeout` (e.g. `500ms`) to send with full-text search requests, if
+    /// configured.
+    fn elastic_search_timeout(&self) -> Option<&str>;
+
+    /// Get Elasticsearch `terminate_after` (maximum number of documents to collect per shard) to
+    /// send with full-text search requests, if configured.
+    fn elastic_search_terminate_after(&self) -> Option<u64>;
 }
 
-pub(crate) fn new_pingless() -> Elasticsearch {
+/// Construct Elasticsearch client given by `config` without checking that it is reachable.
+pub(crate) fn new_pingless(config: &ElasticConfig) -> Elasticsearch {
     info!("Creating Elasticsearch client from {:?}", thread::current());
-    let es_url = format!(
-        "http://{}:{}/",
-        env::var("GOOUT_ELASTIC_HOST").expect("GOOUT_ELASTIC_HOST env variable"),
-        env::var("GOOUT_ELASTIC_PORT").expect("GOOUT_ELASTIC_PORT env variable")
-    );
-    let es_transport = Transport::single_node(&es_url).unwrap();
+    let mut transport_builder = match &config.location {
+        ElasticLocation::Cloud(cloud_id) => {
+            // Validated when parsing the config.
+            TransportBuilder::new(CloudConnectionPool::new(cloud_id).unwrap())
+        }
+        ElasticLocation::Node(url) => {
+            TransportBuilder::new(SingleNodeConnectionPool::new(url.clone()))
+        }
+    };
+    if let Some((username, password)) = &config.credentials {
+        transport_builder =
+            transport_builder.auth(Credentials::Basic(username.clone(), password.clone()));
+    }
+    // The client does not expose a separate connect timeout, this one covers connecting too.
+    let es_transport = transport_builder.timeout(config.request_timeout).build().unwrap();
 
     Elasticsearch::new(es_transport)
 }
 
-/// Construct Elasticsearch client. Reads `GOOUT_ELASTIC_HOST`, `GOOUT_ELASTIC_PORT` env variables.
+/// Construct Elasticsearch client given by `config`.
 ///
 /// # Panics
 ///
-/// Panics if the env variables are not set.
 /// Panics if it is not possible to ping Elasticsearch server using given coordinates.
-pub(crate) async fn new() -> Elasticsearch {
-    let elasticsearch = new_pingless();
+pub(crate) async fn new(config: &ElasticConfig) -> Elasticsearch {
+    let elasticsearch = new_pingless(config);
 
     let es_result = elasticsearch.ping().send().await;
     let es_resp = es_result.map_err(|e| format!("Cannot ping Elasticsearch: {}.", e)).unwrap();
@@ -0,0 +1,19 @@
+//! IP geo-location database in the MaxMind DB format, e.g. GeoLite2 City.
+
+use log::info;
+use maxminddb::Reader;
+
+/// IP geo-location database loaded into memory.
+pub(crate) type GeoIpDatabase = Reader<Vec<u8>>;
+
+/// Load IP geo-location database from file at `path`.
+///
+/// # Panics
+///
+/// Panics if the database cannot be loaded.
+pub(crate) fn load(path: &str) -> GeoIpDatabase {
+    let database = Reader::open_readfile(path)
+        .unwrap_or_else(|e| panic!("Cannot load IP geo-location database {}: {}", path, e));
+    info!("Loaded IP geo-location database {}: {}.", path, database.metadata.database_type);
+    database
+}